@@ -1,6 +1,7 @@
 import fs from "fs"
 import path from "path"
 import { fileURLToPath } from 'url'
+import { fetchFfmpeg } from "./fetch_ffmpeg.js"
 
 const __filename = fileURLToPath(import.meta.url);
 const __dirname = path.dirname(__filename);
@@ -12,4 +13,6 @@ const [curr, inBundle] = Object.entries(json.bundle.resources).find(e => e[0].in
 if (curr) {
     json.bundle.resources[curr] = "./obs.dll"
     fs.writeFileSync(tauriConf, JSON.stringify(json, null, 2))
-}
\ No newline at end of file
+}
+
+await fetchFfmpeg()
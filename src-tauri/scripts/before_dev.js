@@ -1,6 +1,7 @@
 import fs from "fs"
 import path from "path"
 import { fileURLToPath } from 'url'
+import { fetchFfmpeg } from "./fetch_ffmpeg.js"
 
 const __filename = fileURLToPath(import.meta.url);
 const __dirname = path.dirname(__filename);
@@ -22,4 +23,6 @@ if (!fs.existsSync(targetFile)) {
         fs.mkdirSync(targetDir, { recursive: true })
 
     fs.copyFileSync(sourceFile, targetFile)
-}
\ No newline at end of file
+}
+
+await fetchFfmpeg()
@@ -0,0 +1,104 @@
+import crypto from "crypto"
+import fs from "fs"
+import os from "os"
+import path from "path"
+import { execSync } from "child_process"
+import { fileURLToPath } from 'url'
+
+const __filename = fileURLToPath(import.meta.url);
+const __dirname = path.dirname(__filename);
+const binariesDir = path.resolve(__dirname, "../binaries")
+
+// Bundled as sidecars through `bundle.externalBin`, Tauri expects them suffixed with the target triple
+const TOOLS = ["ffmpeg", "ffprobe"]
+// Pinned release, the archive ends up in the installer so it's checked against ffmpeg.sha256
+const WINDOWS_BUILD = "https://github.com/GyanD/codexffmpeg/releases/download/7.1/ffmpeg-7.1-essentials_build.zip"
+const CHECKSUMS = path.resolve(__dirname, "ffmpeg.sha256")
+
+/** Expected SHA-256 of a file name, from `ffmpeg.sha256` in `sha256sum` format */
+function expectedChecksum(name) {
+    const lines = fs.readFileSync(CHECKSUMS, "utf-8").split(/\r?\n/)
+    for (const line of lines) {
+        const [hash, file] = line.trim().split(/\s+\*?/)
+        if (!line.startsWith("#") && file === name)
+            return hash.toLowerCase()
+    }
+
+    return null
+}
+
+function verifyChecksum(file, name) {
+    const actual = crypto.createHash("sha256").update(fs.readFileSync(file)).digest("hex")
+    const expected = expectedChecksum(name)
+    if (!expected)
+        throw new Error(`No checksum for ${name} in ${CHECKSUMS}, the download hashed to ${actual}. Compare it with the published checksum and add it.`)
+    if (actual !== expected)
+        throw new Error(`Checksum of ${name} doesn't match: expected ${expected}, got ${actual}`)
+}
+
+function findFile(dir, name) {
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+        const full = path.join(dir, entry.name)
+        if (entry.isDirectory()) {
+            const found = findFile(full, name)
+            if (found)
+                return found
+        } else if (entry.name === name) {
+            return full
+        }
+    }
+
+    return null
+}
+
+async function downloadWindowsBuild(targets) {
+    const tmp = fs.mkdtempSync(path.join(os.tmpdir(), "clipture-ffmpeg-"))
+    const archive = path.join(tmp, "ffmpeg.zip")
+
+    console.log(`Downloading ffmpeg from ${WINDOWS_BUILD}`)
+    const res = await fetch(WINDOWS_BUILD)
+    if (!res.ok)
+        throw new Error(`Downloading ffmpeg failed with ${res.status}`)
+
+    fs.writeFileSync(archive, Buffer.from(await res.arrayBuffer()))
+    verifyChecksum(archive, path.basename(new URL(WINDOWS_BUILD).pathname))
+
+    // tar ships with Windows 10 and later and can extract zip files
+    execSync(`tar -xf "${archive}" -C "${tmp}"`)
+
+    for (const [tool, target] of targets) {
+        const binary = findFile(tmp, `${tool}.exe`)
+        if (!binary)
+            throw new Error(`${tool}.exe not found in the ffmpeg archive`)
+
+        fs.copyFileSync(binary, target)
+    }
+
+    fs.rmSync(tmp, { recursive: true, force: true })
+}
+
+// Distro binaries are linked dynamically, so they only run on the machine they came from.
+// Good enough for development, Linux bundles aren't supported.
+function copySystemBinaries(targets) {
+    console.warn("Using the system ffmpeg, the sidecars won't work on other machines")
+    for (const [tool, target] of targets) {
+        const binary = execSync(`which ${tool}`).toString().trim()
+        fs.copyFileSync(binary, target)
+        fs.chmodSync(target, 0o755)
+    }
+}
+
+export async function fetchFfmpeg() {
+    const triple = execSync("rustc -vV").toString().match(/host: (\S+)/)[1]
+    const windows = triple.includes("windows")
+    const targets = TOOLS.map(tool => [tool, path.join(binariesDir, `${tool}-${triple}${windows ? ".exe" : ""}`)])
+
+    if (targets.every(([, target]) => fs.existsSync(target)))
+        return
+
+    fs.mkdirSync(binariesDir, { recursive: true })
+    if (windows)
+        await downloadWindowsBuild(targets)
+    else
+        copySystemBinaries(targets)
+}
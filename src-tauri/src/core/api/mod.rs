@@ -0,0 +1,225 @@
+use std::{collections::HashMap, fmt};
+
+use lazy_static::lazy_static;
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode};
+use tauri::Emitter;
+
+use crate::{
+    core::auth::AUTH_MANAGER,
+    utils::consts::{clipture_to_url, APP_HANDLE},
+};
+
+/// Sent to the frontend when the API rejected the stored session
+pub const AUTH_EXPIRED_EVENT: &str = "auth_expired";
+
+lazy_static! {
+    static ref HTTP_CLIENT: Client = Client::builder()
+        .user_agent(format!(
+            "Clipture/{} ({})",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS
+        ))
+        .build()
+        .expect("Building HTTP client");
+}
+
+/// Shared HTTP client with the Clipture user agent, for requests outside of the Clipture API
+pub fn http_client() -> Client {
+    HTTP_CLIENT.clone()
+}
+
+/// Returned when the API answered with 401, the session has been marked expired at that point
+#[derive(Debug)]
+pub struct AuthExpired;
+
+impl fmt::Display for AuthExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session expired, please sign in again")
+    }
+}
+
+impl std::error::Error for AuthExpired {}
+
+fn cookie_header(cookies: &HashMap<String, String>) -> String {
+    let mut pairs = cookies
+        .iter()
+        .map(|(key, cookie)| format!("{}={}", key, cookie))
+        .collect::<Vec<_>>();
+
+    // HashMap order is random, keep the header stable
+    pairs.sort();
+    pairs.join("; ")
+}
+
+/// Client for the Clipture API that sends the session cookies of the signed in user
+#[derive(Clone)]
+pub struct ApiClient {
+    http: Client,
+    base_url: String,
+    cookies: Option<String>,
+}
+
+impl ApiClient {
+    pub fn with(base_url: &str, cookies: Option<&HashMap<String, String>>) -> Self {
+        Self {
+            http: http_client(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cookies: cookies.map(cookie_header),
+        }
+    }
+
+    /// Client for the configured API, with the cookies of the signed in user if there is one
+    pub async fn new() -> Self {
+        let cookies = match AUTH_MANAGER.read().await.as_ref() {
+            Some(auth) => auth.get_cookies().await,
+            None => None,
+        };
+
+        Self::with(&clipture_to_url(""), cookies.as_ref())
+    }
+
+    /// Like `new`, but fails if no one is signed in
+    pub async fn authenticated() -> anyhow::Result<Self> {
+        let client = Self::new().await;
+        if client.cookies.is_none() {
+            anyhow::bail!("Not signed in");
+        }
+
+        Ok(client)
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Request to an absolute url, with the session cookies attached
+    pub fn request_url(&self, method: Method, url: &str) -> RequestBuilder {
+        let req = self.http.request(method, url);
+        match &self.cookies {
+            Some(cookies) => req.header(header::COOKIE, cookies),
+            None => req,
+        }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.request_url(method, &self.url(path))
+    }
+
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.request(Method::POST, path)
+    }
+
+    pub fn put(&self, path: &str) -> RequestBuilder {
+        self.request(Method::PUT, path)
+    }
+
+    pub fn delete(&self, path: &str) -> RequestBuilder {
+        self.request(Method::DELETE, path)
+    }
+
+    /// Sends the request. A 401 to an authenticated request signs the user out and
+    /// fails with `AuthExpired`, other statuses are left to the caller.
+    pub async fn send(&self, req: RequestBuilder) -> anyhow::Result<Response> {
+        let res = req.send().await?;
+        if res.status() == StatusCode::UNAUTHORIZED && self.cookies.is_some() {
+            expire_session().await;
+            return Err(AuthExpired.into());
+        }
+
+        Ok(res)
+    }
+}
+
+/// Marks the active session as expired and tells the frontend to ask the user to sign in again
+pub(crate) async fn expire_session() {
+    log::warn!("API rejected the session, signing out");
+
+    if let Some(auth) = AUTH_MANAGER.read().await.as_ref() {
+        if let Err(e) = auth.expire_active().await {
+            log::error!("Couldn't clear expired session: {:?}", e);
+        }
+    }
+
+    if let Some(app) = APP_HANDLE.read().await.as_ref() {
+        if let Err(e) = app.emit(AUTH_EXPIRED_EVENT, ()) {
+            log::error!("Couldn't notify frontend about expired session: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers every request with `status` and returns the url and the received requests
+    async fn server(
+        status: &'static str,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or_default();
+                let _ = tx.send(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn cookies() -> HashMap<String, String> {
+        HashMap::from([
+            ("session".to_string(), "abc".to_string()),
+            ("csrf".to_string(), "xyz".to_string()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn sends_cookies_and_user_agent() -> anyhow::Result<()> {
+        let (url, mut requests) = server("200 OK").await;
+        let client = ApiClient::with(&url, Some(&cookies()));
+
+        let res = client.send(client.get("/api/test")).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("get /api/test "));
+        assert!(request.contains("cookie: csrf=xyz; session=abc"));
+        assert!(request.contains("user-agent: clipture/"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unauthorized_expires_the_session() -> anyhow::Result<()> {
+        let (url, _requests) = server("401 Unauthorized").await;
+
+        let client = ApiClient::with(&url, Some(&cookies()));
+        let err = client.send(client.get("/api/test")).await.unwrap_err();
+        assert!(err.downcast_ref::<AuthExpired>().is_some());
+
+        // Without a session there is nothing to expire, the caller handles the status
+        let anonymous = ApiClient::with(&url, None);
+        let res = anonymous.send(anonymous.get("/api/test")).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
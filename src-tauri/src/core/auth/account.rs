@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::json_typings::clipture_api::validation;
+
+/// A session is treated as expired this long before its cookies actually expire, so
+/// requests don't fail halfway through
+pub const EXPIRY_MARGIN_SECS: i64 = 5 * 60;
+/// Id of the account migrated from the flat cookie map, it's unknown whose cookies those are
+const LEGACY_ACCOUNT_ID: &str = "legacy";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredCookie {
+    pub value: String,
+    /// Unix timestamp, `None` for session cookies
+    pub expires_at: Option<i64>,
+}
+
+impl StoredCookie {
+    /// Parses a cookie as sent by `/api/validation/report`, either just the value or a
+    /// `Set-Cookie` style string with `Expires` / `Max-Age` attributes
+    pub fn parse(raw: &str, now: i64) -> Self {
+        let mut parts = raw.split(';');
+        let value = parts.next().unwrap_or_default().trim().to_string();
+
+        let mut expires = None;
+        let mut max_age = None;
+        for attr in parts {
+            let Some((name, attr_value)) = attr.split_once('=') else {
+                continue;
+            };
+
+            match name.trim().to_lowercase().as_str() {
+                "max-age" => max_age = attr_value.trim().parse::<i64>().ok(),
+                "expires" => {
+                    expires = DateTime::parse_from_rfc2822(attr_value.trim())
+                        .ok()
+                        .map(|d| d.timestamp())
+                }
+                _ => {}
+            }
+        }
+
+        // Max-Age wins over Expires, like in browsers
+        Self {
+            value,
+            expires_at: max_age.map(|age| now + age).or(expires),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub id: String,
+    pub name: Option<String>,
+    pub cookies: HashMap<String, StoredCookie>,
+    /// Unix timestamp of the last successful validation against the API
+    pub validated_at: Option<i64>,
+}
+
+impl Account {
+    pub fn from_report(id: String, report: &validation::report::Root, now: i64) -> Self {
+        let cookies = report
+            .entry
+            .iter()
+            .map(|e| (e.key.clone(), StoredCookie::parse(&e.cookie, now)))
+            .collect();
+
+        Self {
+            id,
+            name: None,
+            cookies,
+            validated_at: Some(now),
+        }
+    }
+
+    /// The earliest expiry of the cookies, if any of them expires
+    pub fn expires_at(&self) -> Option<i64> {
+        self.cookies.values().filter_map(|c| c.expires_at).min()
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at()
+            .is_some_and(|at| at - EXPIRY_MARGIN_SECS <= now)
+    }
+
+    pub fn cookie_values(&self) -> HashMap<String, String> {
+        self.cookies
+            .iter()
+            .map(|(k, c)| (k.clone(), c.value.clone()))
+            .collect()
+    }
+}
+
+/// What the frontend gets to see of a saved account
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub active: bool,
+    pub expired: bool,
+    /// RFC 3339 timestamp
+    pub expires_at: Option<String>,
+}
+
+/// All saved accounts, persisted as a whole in the credential store
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccountStore {
+    pub active: Option<String>,
+    pub accounts: Vec<Account>,
+}
+
+impl AccountStore {
+    /// Reads the store, accepting the flat cookie map older versions saved
+    pub fn from_json(raw: &str, now: i64) -> anyhow::Result<Self> {
+        if let Ok(store) = serde_json::from_str::<Self>(raw) {
+            return Ok(store);
+        }
+
+        let legacy: HashMap<String, String> = serde_json::from_str(raw)?;
+        let account = Account {
+            id: LEGACY_ACCOUNT_ID.to_string(),
+            name: None,
+            cookies: legacy
+                .into_iter()
+                .map(|(k, v)| (k, StoredCookie::parse(&v, now)))
+                .collect(),
+            validated_at: None,
+        };
+
+        Ok(Self {
+            active: Some(account.id.clone()),
+            accounts: vec![account],
+        })
+    }
+
+    pub fn active(&self) -> Option<&Account> {
+        let id = self.active.as_ref()?;
+        self.accounts.iter().find(|a| &a.id == id)
+    }
+
+    pub fn active_mut(&mut self) -> Option<&mut Account> {
+        let id = self.active.clone()?;
+        self.accounts.iter_mut().find(|a| a.id == id)
+    }
+
+    /// Adds the account or replaces the saved one with the same id, and makes it active.
+    /// The migrated legacy account is replaced by the first real one.
+    pub fn upsert(&mut self, account: Account) {
+        if account.id != LEGACY_ACCOUNT_ID {
+            self.accounts.retain(|a| a.id != LEGACY_ACCOUNT_ID);
+        }
+
+        self.active = Some(account.id.clone());
+        match self.accounts.iter_mut().find(|a| a.id == account.id) {
+            Some(existing) => {
+                let name = existing.name.take();
+                *existing = account;
+                existing.name = existing.name.take().or(name);
+            }
+            None => self.accounts.push(account),
+        }
+    }
+
+    /// Returns false if there is no account with that id
+    pub fn switch(&mut self, id: &str) -> bool {
+        if !self.accounts.iter().any(|a| a.id == id) {
+            return false;
+        }
+
+        self.active = Some(id.to_string());
+        true
+    }
+
+    /// Removes the account, switching to another saved one if it was active
+    pub fn remove(&mut self, id: &str) -> bool {
+        let len = self.accounts.len();
+        self.accounts.retain(|a| a.id != id);
+        if self.active.as_deref() == Some(id) {
+            self.active = self.accounts.first().map(|a| a.id.clone());
+        }
+
+        self.accounts.len() != len
+    }
+
+    pub fn infos(&self, now: i64) -> Vec<AccountInfo> {
+        self.accounts
+            .iter()
+            .map(|a| AccountInfo {
+                id: a.id.clone(),
+                name: a.name.clone(),
+                active: self.active.as_ref() == Some(&a.id),
+                expired: a.is_expired(now),
+                expires_at: a
+                    .expires_at()
+                    .and_then(|at| DateTime::<Utc>::from_timestamp(at, 0))
+                    .map(|at| at.to_rfc3339()),
+            })
+            .collect()
+    }
+}
+
+pub fn unix_now() -> i64 {
+    Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_727_740_800; // 2024-10-01T00:00:00Z
+
+    fn account(id: &str, expires_at: Option<i64>) -> Account {
+        Account {
+            id: id.to_string(),
+            name: None,
+            cookies: HashMap::from([(
+                "session".to_string(),
+                StoredCookie {
+                    value: id.to_string(),
+                    expires_at,
+                },
+            )]),
+            validated_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_cookie_expiry() {
+        assert_eq!(
+            StoredCookie::parse("abc", NOW),
+            StoredCookie {
+                value: "abc".to_string(),
+                expires_at: None
+            }
+        );
+        assert_eq!(
+            StoredCookie::parse("abc; Path=/; Expires=Wed, 02 Oct 2024 00:00:00 GMT", NOW)
+                .expires_at,
+            Some(NOW + 24 * 60 * 60)
+        );
+        assert_eq!(
+            StoredCookie::parse(
+                "abc; Expires=Wed, 02 Oct 2024 00:00:00 GMT; Max-Age=60",
+                NOW
+            )
+            .expires_at,
+            Some(NOW + 60)
+        );
+        assert_eq!(
+            StoredCookie::parse("abc; Expires=soon", NOW).expires_at,
+            None
+        );
+    }
+
+    #[test]
+    fn expiry_has_a_margin() {
+        assert!(!account("a", None).is_expired(NOW));
+        assert!(!account("a", Some(NOW + 60 * 60)).is_expired(NOW));
+        assert!(account("a", Some(NOW + 60)).is_expired(NOW));
+        assert!(account("a", Some(NOW - 60)).is_expired(NOW));
+    }
+
+    #[test]
+    fn reads_legacy_cookie_maps() -> anyhow::Result<()> {
+        let store = AccountStore::from_json(r#"{"session":"abc"}"#, NOW)?;
+        let active = store.active().unwrap();
+        assert_eq!(active.id, "legacy");
+        assert_eq!(active.cookie_values()["session"], "abc");
+
+        let json = serde_json::to_string(&store)?;
+        assert_eq!(AccountStore::from_json(&json, NOW)?, store);
+
+        let mut store = store;
+        store.upsert(account("a", None));
+        assert_eq!(store.accounts.len(), 1);
+        assert_eq!(store.active().unwrap().id, "a");
+        Ok(())
+    }
+
+    #[test]
+    fn switches_and_removes_accounts() {
+        let mut store = AccountStore::default();
+        store.upsert(account("a", None));
+        store.upsert(account("b", Some(NOW)));
+        assert_eq!(store.active.as_deref(), Some("b"));
+
+        assert!(store.switch("a"));
+        assert!(!store.switch("missing"));
+        assert_eq!(store.active().unwrap().id, "a");
+
+        let infos = store.infos(NOW);
+        assert!(infos[0].active && !infos[0].expired);
+        assert!(!infos[1].active && infos[1].expired);
+
+        // Signing in again keeps the name we already know
+        store.active_mut().unwrap().name = Some("Alice".to_string());
+        store.upsert(account("a", None));
+        assert_eq!(store.active().unwrap().name.as_deref(), Some("Alice"));
+
+        assert!(store.remove("a"));
+        assert_eq!(store.active.as_deref(), Some("b"));
+        assert!(store.remove("b"));
+        assert_eq!(store.active, None);
+    }
+}
@@ -0,0 +1,110 @@
+use sha2::{Digest, Sha256};
+use tauri::Url;
+use uuid::Uuid;
+
+/// Secrets of a single sign in. The login page gets the `state` and the `challenge`,
+/// callbacks without the same `state` are ignored and `/api/validation/report` only hands
+/// out cookies if it also gets the `verifier` the challenge was derived from. That way
+/// nothing that injects a `clipture://` URL can sign the app in to another account.
+pub struct SignInAttempt {
+    pub state: String,
+    verifier: String,
+}
+
+impl SignInAttempt {
+    pub fn new() -> Self {
+        Self {
+            state: Uuid::new_v4().simple().to_string(),
+            verifier: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        }
+    }
+
+    pub fn verifier(&self) -> &str {
+        &self.verifier
+    }
+
+    /// Hex encoded SHA-256 of the verifier
+    pub fn challenge(&self) -> String {
+        hex::encode(Sha256::digest(self.verifier.as_bytes()))
+    }
+
+    pub fn login_path(&self, silent: bool) -> String {
+        let mut path = format!(
+            "/redirects/login?appLogin=true&state={}&challenge={}",
+            self.state,
+            self.challenge()
+        );
+        if silent {
+            path.push_str("&silent=true");
+        }
+
+        path
+    }
+
+    /// The secret of a deep link callback, `None` if the URL doesn't belong to this attempt
+    pub fn secret_from_deep_link(&self, url: &Url) -> Option<String> {
+        if url.scheme() != "clipture" {
+            return None;
+        }
+
+        if url.path().starts_with("/login") || url.path().starts_with("login") {
+            return None;
+        }
+
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.trim().to_string())
+        };
+
+        if param("state").as_deref() != Some(self.state.as_str()) {
+            log::warn!("Ignoring sign in callback with a wrong state");
+            return None;
+        }
+
+        param("secret").filter(|secret| !secret.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attempts_are_unique() {
+        let a = SignInAttempt::new();
+        let b = SignInAttempt::new();
+        assert_ne!(a.state, b.state);
+        assert_ne!(a.verifier(), b.verifier());
+        assert_eq!(a.challenge(), a.challenge());
+        assert_eq!(a.challenge().len(), 64);
+    }
+
+    #[test]
+    fn only_accepts_callbacks_with_the_state() -> anyhow::Result<()> {
+        let attempt = SignInAttempt::new();
+        let url = |query: &str| Url::parse(&format!("clipture://auth?{}", query));
+
+        assert_eq!(
+            attempt.secret_from_deep_link(&url(&format!("secret=abc&state={}", attempt.state))?),
+            Some("abc".to_string())
+        );
+        assert_eq!(attempt.secret_from_deep_link(&url("secret=abc")?), None);
+        assert_eq!(
+            attempt.secret_from_deep_link(&url("secret=abc&state=guess")?),
+            None
+        );
+        assert_eq!(
+            attempt.secret_from_deep_link(&url(&format!("state={}", attempt.state))?),
+            None
+        );
+        assert_eq!(
+            attempt.secret_from_deep_link(&Url::parse(&format!(
+                "https://example.com/?secret=abc&state={}",
+                attempt.state
+            ))?),
+            None
+        );
+        Ok(())
+    }
+}
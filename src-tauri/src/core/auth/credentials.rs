@@ -0,0 +1,359 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use keyring::Entry;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::utils::util::write_atomic;
+
+/// Fallback file for the credentials if the OS keyring can't be used, in the app data dir
+pub const CREDENTIALS_FILE: &str = "credentials.bin";
+
+const FILE_MAGIC: &[u8; 4] = b"CLPC";
+const FILE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Where the saved accounts are persisted
+#[async_trait]
+pub trait CredentialStore: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn load(&self) -> anyhow::Result<Option<String>>;
+    async fn save(&self, secret: &str) -> anyhow::Result<()>;
+    async fn clear(&self) -> anyhow::Result<()>;
+}
+
+/// The OS keyring, e.g. the Windows Credential Manager or the Secret Service on Linux
+pub struct KeyringStore {
+    entry: Entry,
+}
+
+impl KeyringStore {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            entry: Entry::new("clipture-rs", &whoami::username())?,
+        })
+    }
+
+    /// Whether the keyring can actually be reached, there is no keyring daemon on
+    /// many headless Linux systems
+    pub fn is_available(&self) -> bool {
+        match self.entry.get_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => true,
+            Err(e) => {
+                log::warn!("OS keyring is unavailable: {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialStore for KeyringStore {
+    fn name(&self) -> &'static str {
+        "keyring"
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<String>> {
+        match self.entry.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e).context("Getting password"),
+        }
+    }
+
+    async fn save(&self, secret: &str) -> anyhow::Result<()> {
+        self.entry.set_password(secret).context("Setting password")
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        match self.entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e).context("Deleting password"),
+        }
+    }
+}
+
+/// A file encrypted with ChaCha20-Poly1305. The key is derived from a secret bound to this
+/// machine and user, so a copied file is useless elsewhere. It doesn't protect against
+/// programs running as the same user, the OS keyring is preferred for that reason.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    machine_secret: String,
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let machine_id = machine_uid::get().map_err(|e| anyhow!("Getting machine id: {}", e))?;
+        let machine_secret = format!("{}:{}", machine_id, whoami::username());
+
+        Ok(Self::with_secret(path, machine_secret))
+    }
+
+    pub fn with_secret(path: PathBuf, machine_secret: String) -> Self {
+        Self {
+            path,
+            machine_secret,
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    fn cipher(&self, salt: &[u8]) -> ChaCha20Poly1305 {
+        let mut hasher = Sha256::new();
+        hasher.update(b"clipture-credentials");
+        hasher.update(salt);
+        hasher.update(self.machine_secret.as_bytes());
+        let key = hasher.finalize();
+
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+
+    fn encrypt(&self, secret: &str) -> anyhow::Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher(&salt)
+            .encrypt(Nonce::from_slice(&nonce), secret.as_bytes())
+            .map_err(|_| anyhow!("Encrypting credentials"))?;
+
+        let mut out = Vec::with_capacity(5 + SALT_LEN + NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(FILE_MAGIC);
+        out.push(FILE_VERSION);
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> anyhow::Result<String> {
+        let header = FILE_MAGIC.len() + 1;
+        if data.len() < header + SALT_LEN + NONCE_LEN || &data[..FILE_MAGIC.len()] != FILE_MAGIC {
+            bail!("Not a credentials file");
+        }
+        if data[FILE_MAGIC.len()] != FILE_VERSION {
+            bail!(
+                "Unsupported credentials file version {}",
+                data[FILE_MAGIC.len()]
+            );
+        }
+
+        let (salt, rest) = data[header..].split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let plain = self
+            .cipher(salt)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                anyhow!(
+                    "Decrypting credentials, the file belongs to another machine or is corrupted"
+                )
+            })?;
+
+        Ok(String::from_utf8(plain)?)
+    }
+}
+
+#[async_trait]
+impl CredentialStore for EncryptedFileStore {
+    fn name(&self) -> &'static str {
+        "encrypted file"
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<String>> {
+        let data = match fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Reading credentials file"),
+        };
+
+        self.decrypt(&data).map(Some)
+    }
+
+    async fn save(&self, secret: &str) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        write_atomic(&self.path, self.encrypt(secret)?).await?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).context("Deleting credentials file"),
+        }
+    }
+}
+
+/// Keeps the credentials only while the app runs, used in tests and as a last resort
+#[derive(Default)]
+pub struct MemoryStore {
+    secret: std::sync::Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl CredentialStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<String>> {
+        Ok(self.secret.lock().unwrap().clone())
+    }
+
+    async fn save(&self, secret: &str) -> anyhow::Result<()> {
+        *self.secret.lock().unwrap() = Some(secret.to_string());
+        Ok(())
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        *self.secret.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+/// Moves the credentials from `from` to `to` if `to` has none yet
+async fn migrate(from: &dyn CredentialStore, to: &dyn CredentialStore) -> anyhow::Result<()> {
+    if to.load().await?.is_some() {
+        return Ok(());
+    }
+
+    if let Some(secret) = from.load().await? {
+        to.save(&secret).await?;
+        from.clear().await?;
+        log::info!("Moved credentials from {} to {}", from.name(), to.name());
+    }
+
+    Ok(())
+}
+
+/// Picks the OS keyring if it works, otherwise the encrypted file in `data_dir`, and
+/// keeps the credentials in memory only if neither can be used
+pub async fn select_credential_store(data_dir: &Path) -> Box<dyn CredentialStore> {
+    let file = EncryptedFileStore::new(data_dir.join(CREDENTIALS_FILE));
+    if let Err(e) = &file {
+        log::warn!("Encrypted credential file is unavailable: {:?}", e);
+    }
+
+    match KeyringStore::new() {
+        Ok(keyring) if keyring.is_available() => {
+            // Credentials saved while the keyring was unavailable
+            if let Ok(file) = file.as_ref() {
+                if file.exists() {
+                    if let Err(e) = migrate(file, &keyring).await {
+                        log::warn!("Couldn't move credentials to the keyring: {:?}", e);
+                    }
+                }
+            }
+
+            return Box::new(keyring);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Couldn't open keyring entry: {:?}", e),
+    }
+
+    match file {
+        Ok(file) => {
+            log::info!("Storing credentials in {}", file.path.display());
+            Box::new(file)
+        }
+        Err(_) => {
+            log::warn!("No persistent credential storage, sign ins are lost on restart");
+            Box::new(MemoryStore::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("clipture-credentials-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn encrypted_file_round_trips() -> anyhow::Result<()> {
+        let dir = temp_dir();
+        let path = dir.join(CREDENTIALS_FILE);
+        let store = EncryptedFileStore::with_secret(path.clone(), "machine:user".to_string());
+
+        assert_eq!(store.load().await?, None);
+        store.save(r#"{"active":null,"accounts":[]}"#).await?;
+        assert_eq!(
+            store.load().await?.as_deref(),
+            Some(r#"{"active":null,"accounts":[]}"#)
+        );
+
+        // Nothing readable ends up on disk
+        let raw = std::fs::read(&path)?;
+        assert!(!String::from_utf8_lossy(&raw).contains("accounts"));
+
+        store.clear().await?;
+        assert!(!store.exists());
+        store.clear().await?;
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_file_is_bound_to_the_machine() -> anyhow::Result<()> {
+        let dir = temp_dir();
+        let path = dir.join(CREDENTIALS_FILE);
+        EncryptedFileStore::with_secret(path.clone(), "machine:user".to_string())
+            .save("secret")
+            .await?;
+
+        let other = EncryptedFileStore::with_secret(path.clone(), "other:user".to_string());
+        assert!(other.load().await.is_err());
+
+        let mut raw = std::fs::read(&path)?;
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        std::fs::write(&path, raw)?;
+        let tampered = EncryptedFileStore::with_secret(path, "machine:user".to_string());
+        assert!(tampered.load().await.is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrates_only_into_empty_stores() -> anyhow::Result<()> {
+        let from = MemoryStore::default();
+        let to = MemoryStore::default();
+
+        from.save("old").await?;
+        migrate(&from, &to).await?;
+        assert_eq!(to.load().await?.as_deref(), Some("old"));
+        assert_eq!(from.load().await?, None);
+
+        from.save("older").await?;
+        migrate(&from, &to).await?;
+        assert_eq!(to.load().await?.as_deref(), Some("old"));
+        assert_eq!(from.load().await?.as_deref(), Some("older"));
+        Ok(())
+    }
+}
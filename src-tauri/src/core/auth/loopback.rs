@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tauri::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+const CALLBACK_PATH: &str = "/callback";
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// Browsers open connections they don't use right away, those mustn't hold up the callback
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+
+const DONE_PAGE: &str = "<!DOCTYPE html><html><head><title>Clipture</title></head>\
+<body style=\"font-family: sans-serif; text-align: center; margin-top: 4em\">\
+<h2>Signed in to Clipture</h2><p>You can close this tab and return to the app.</p></body></html>";
+
+/// Receives the sign in secret through a redirect to a local port, for systems where the
+/// `clipture://` deep link doesn't reach the app.
+///
+/// The website is opened with `loopbackPort` next to the usual `state` and redirects the
+/// browser to `http://127.0.0.1:<port>/callback?secret=<secret>&state=<state>` once signed in.
+pub struct LoopbackRedirect {
+    listener: TcpListener,
+    state: String,
+}
+
+/// What a single request to the loopback server amounted to
+#[derive(Debug, PartialEq)]
+enum Callback {
+    Secret(String),
+    /// A callback that doesn't belong to this sign in, e.g. from another website
+    BadState,
+    NotFound,
+}
+
+impl LoopbackRedirect {
+    /// `state` is the nonce of the sign in attempt the callback has to carry
+    pub async fn bind(state: String) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("Binding loopback port")?;
+
+        Ok(Self { listener, state })
+    }
+
+    pub fn port(&self) -> anyhow::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Query parameter to append to the login redirect
+    pub fn login_query(&self) -> anyhow::Result<String> {
+        Ok(format!("&loopbackPort={}", self.port()?))
+    }
+
+    /// Serves requests until the callback with the matching state arrives
+    pub async fn wait_for_secret(&self) -> anyhow::Result<String> {
+        loop {
+            let (mut stream, _) = self.listener.accept().await?;
+            let read = time::timeout(READ_TIMEOUT, read_callback(&mut stream, &self.state));
+            let callback = match read.await.context("Reading request timed out") {
+                Ok(Ok(callback)) => callback,
+                Ok(Err(e)) | Err(e) => {
+                    log::debug!("Invalid loopback request: {:?}", e);
+                    continue;
+                }
+            };
+
+            match callback {
+                Callback::Secret(secret) => {
+                    respond(&mut stream, "200 OK", DONE_PAGE).await;
+                    return Ok(secret);
+                }
+                Callback::BadState => {
+                    log::warn!("Ignoring loopback callback with a wrong state");
+                    respond(&mut stream, "400 Bad Request", "Invalid sign in state").await;
+                }
+                Callback::NotFound => respond(&mut stream, "404 Not Found", "Not found").await,
+            }
+        }
+    }
+}
+
+async fn read_callback(stream: &mut TcpStream, state: &str) -> anyhow::Result<Callback> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_LEN {
+            bail!("Request too large");
+        }
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some("GET"), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(Callback::NotFound);
+    };
+
+    Ok(parse_callback(target, state))
+}
+
+fn parse_callback(target: &str, state: &str) -> Callback {
+    let Ok(url) = Url::parse(&format!("http://127.0.0.1{}", target)) else {
+        return Callback::NotFound;
+    };
+
+    if url.path() != CALLBACK_PATH {
+        return Callback::NotFound;
+    }
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim().to_string())
+    };
+
+    if param("state").as_deref() != Some(state) {
+        return Callback::BadState;
+    }
+
+    match param("secret") {
+        Some(secret) if !secret.is_empty() => Callback::Secret(secret),
+        _ => Callback::BadState,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        log::debug!("Couldn't answer loopback request: {:?}", e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::core::api::http_client;
+
+    #[test]
+    fn parses_callbacks() {
+        assert_eq!(
+            parse_callback("/callback?secret=abc&state=xyz", "xyz"),
+            Callback::Secret("abc".to_string())
+        );
+        assert_eq!(
+            parse_callback("/callback?secret=abc&state=other", "xyz"),
+            Callback::BadState
+        );
+        assert_eq!(
+            parse_callback("/callback?secret=abc", "xyz"),
+            Callback::BadState
+        );
+        assert_eq!(
+            parse_callback("/callback?state=xyz", "xyz"),
+            Callback::BadState
+        );
+        assert_eq!(parse_callback("/favicon.ico", "xyz"), Callback::NotFound);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_matching_state() -> anyhow::Result<()> {
+        let redirect = LoopbackRedirect::bind("xyz".to_string()).await?;
+        let base = format!("http://127.0.0.1:{}", redirect.port()?);
+        let state = redirect.state.clone();
+
+        let browser = tokio::spawn(async move {
+            let client = http_client();
+            let forged = client
+                .get(format!("{}/callback?secret=evil&state=guess", base))
+                .send()
+                .await?;
+            let favicon = client.get(format!("{}/favicon.ico", base)).send().await?;
+            let real = client
+                .get(format!("{}/callback?secret=abc&state={}", base, state))
+                .send()
+                .await?;
+
+            anyhow::Ok((forged.status(), favicon.status(), real.status()))
+        });
+
+        assert_eq!(redirect.wait_for_secret().await?, "abc");
+        let (forged, favicon, real) = browser.await??;
+        assert_eq!(forged, StatusCode::BAD_REQUEST);
+        assert_eq!(favicon, StatusCode::NOT_FOUND);
+        assert_eq!(real, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connections_dont_block_the_callback() -> anyhow::Result<()> {
+        let redirect = LoopbackRedirect::bind("xyz".to_string()).await?;
+        let addr = format!("127.0.0.1:{}", redirect.port()?);
+
+        // A preconnect that never sends anything, queued before the real callback
+        let _idle = TcpStream::connect(&addr).await?;
+        let mut real = TcpStream::connect(&addr).await?;
+        real.write_all(b"GET /callback?secret=abc&state=xyz HTTP/1.1\r\n\r\n")
+            .await?;
+
+        assert_eq!(redirect.wait_for_secret().await?, "abc");
+        Ok(())
+    }
+}
@@ -1,27 +1,83 @@
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, Context, Ok};
-use keyring::Entry;
 use lazy_static::lazy_static;
 use tauri::{App, Url};
 use tauri_plugin_deep_link::DeepLinkExt;
 use tokio::{
     sync::{
         mpsc::{self, UnboundedReceiver},
-        Mutex, RwLock,
+        watch, Mutex, RwLock,
     },
     time::{self, Instant},
 };
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    core::api::{expire_session, ApiClient, AuthExpired},
+    json_typings::clipture_api::{user, validation},
+    utils::consts::clipture_to_url,
+};
+
+mod account;
+mod attempt;
+mod credentials;
+mod loopback;
+mod profile;
+pub use account::AccountInfo;
+use account::{unix_now, Account, AccountStore, EXPIRY_MARGIN_SECS};
+use attempt::SignInAttempt;
+pub use credentials::select_credential_store;
+use credentials::CredentialStore;
+use loopback::LoopbackRedirect;
+pub use profile::{AuthStatus, Profile};
+
+/// A silent sign in needs no interaction, so it either works quickly or not at all
+const SILENT_SIGN_IN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the active session is checked against the API
+const VALIDATION_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// How long a fetched profile is served from the cache
+const PROFILE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How the browser hands the sign in secret back to the app
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectMode {
+    /// The website opens `clipture://`, which reaches the app through the deep link plugin
+    DeepLink,
+    /// The website redirects to a port on localhost, used if deep links aren't registered
+    Loopback,
+}
+
+/// The sign in that is currently waiting for the redirect
+struct PendingSignIn {
+    token: CancellationToken,
+    silent: bool,
+}
+
+struct CachedProfile {
+    account_id: String,
+    fetched_at: Instant,
+    profile: Profile,
+}
 
-use crate::{json_typings::clipture_api::validation, utils::consts::clipture_to_url};
 pub struct AuthManager {
-    entry: Entry,
-    cookie_map: Arc<RwLock<Option<HashMap<String, String>>>>,
+    credentials: Box<dyn CredentialStore>,
+    store: Arc<RwLock<AccountStore>>,
     rx: Mutex<UnboundedReceiver<Url>>,
+    redirect_mode: RedirectMode,
+    /// Login page of the sign in that is currently waiting for the redirect
+    sign_in_url: std::sync::Mutex<Option<String>>,
+    pending: std::sync::Mutex<Option<PendingSignIn>>,
+    profile: Mutex<Option<CachedProfile>>,
+    status: watch::Sender<AuthStatus>,
 }
 
 impl AuthManager {
-    pub fn new(app: &mut App) -> anyhow::Result<AuthManager> {
+    pub async fn new(
+        app: &mut App,
+        credentials: Box<dyn CredentialStore>,
+        redirect_mode: RedirectMode,
+    ) -> anyhow::Result<AuthManager> {
         let (tx, rx) = mpsc::unbounded_channel();
         app.deep_link().on_open_url(move |event| {
             for url in event.urls() {
@@ -29,128 +85,530 @@ impl AuthManager {
             }
         });
 
-        let entry = Entry::new("clipture-rs", &whoami::username())?;
-        let pass = entry.get_password();
+        let pass = credentials.load().await;
         if let Err(e) = &pass {
-            log::warn!("Failed to get password: {}", e);
+            log::warn!("Failed to get password from {}: {}", credentials.name(), e);
         }
 
-        let cookie_map = pass.ok().and_then(|password| {
-            let r = serde_json::from_str::<HashMap<String, String>>(&password);
-            if let Err(e) = r {
-                log::warn!("Failed to deserialize password: {}", e);
-                return None;
-            }
-
-            r.ok()
-        });
+        let store = pass
+            .ok()
+            .flatten()
+            .and_then(|password| {
+                AccountStore::from_json(&password, unix_now())
+                    .inspect_err(|e| log::warn!("Failed to deserialize password: {}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
 
-        log::debug!("Auth Manager initializing: {}", cookie_map.is_some());
+        log::debug!(
+            "Auth Manager initializing: {} accounts, active {:?}, stored in {}, {:?} redirect",
+            store.accounts.len(),
+            store.active,
+            credentials.name(),
+            redirect_mode
+        );
+        let (status, _) = watch::channel(status_of(&store, None));
         let a = AuthManager {
-            entry,
-            cookie_map: Arc::new(RwLock::new(cookie_map)),
+            credentials,
+            store: Arc::new(RwLock::new(store)),
             rx: Mutex::new(rx),
+            redirect_mode,
+            sign_in_url: std::sync::Mutex::new(None),
+            pending: std::sync::Mutex::new(None),
+            profile: Mutex::new(None),
+            status,
         };
 
         Ok(a)
     }
 
-    #[allow(dead_code)]
+    async fn persist(&self, store: &AccountStore) -> anyhow::Result<()> {
+        if store.accounts.is_empty() {
+            return self.credentials.clear().await;
+        }
+
+        let as_str = serde_json::to_string(store).context("Serializing JSON")?;
+        self.credentials.save(&as_str).await?;
+
+        Ok(())
+    }
+
+    /// Cookies of the active account, `None` if no one is signed in or the session expired
     pub async fn get_cookies(&self) -> Option<HashMap<String, String>> {
-        self.cookie_map.read().await.clone()
+        let store = self.store.read().await;
+        store
+            .active()
+            .filter(|a| !a.is_expired(unix_now()))
+            .map(|a| a.cookie_values())
     }
 
     pub async fn is_logged_in(&self) -> bool {
-        self.cookie_map.read().await.is_some()
+        self.get_cookies().await.is_some()
     }
 
+    pub async fn accounts(&self) -> Vec<AccountInfo> {
+        self.store.read().await.infos(unix_now())
+    }
+
+    pub async fn switch_account(&self, id: &str) -> anyhow::Result<()> {
+        {
+            let mut store = self.store.write().await;
+            if !store.switch(id) {
+                bail!("No saved account with id {}", id);
+            }
+
+            self.persist(&store).await?;
+        }
+
+        self.load_profile().await;
+        Ok(())
+    }
+
+    /// Returns false if there is no saved account with that id
+    pub async fn remove_account(&self, id: &str) -> anyhow::Result<bool> {
+        {
+            let mut store = self.store.write().await;
+            if !store.remove(id) {
+                return Ok(false);
+            }
+
+            self.persist(&store).await?;
+        }
+
+        self.refresh_status().await;
+        Ok(true)
+    }
+
+    /// Removes the active account, another saved account becomes active if there is one
     pub async fn sign_out(&self) -> anyhow::Result<()> {
-        *self.cookie_map.write().await = None;
-        self.entry
-            .delete_credential()
-            .context("Deleting password")?;
+        {
+            let mut store = self.store.write().await;
+            if let Some(id) = store.active.clone() {
+                store.remove(&id);
+            }
+
+            self.persist(&store).await?;
+        }
+
+        self.refresh_status().await;
         Ok(())
     }
 
+    /// Marks the session of the active account as expired but keeps the account, so the
+    /// user can sign in to it again
+    pub async fn expire_active(&self) -> anyhow::Result<()> {
+        {
+            let mut store = self.store.write().await;
+            let Some(account) = store.active_mut() else {
+                return Ok(());
+            };
+
+            let now = unix_now();
+            for cookie in account.cookies.values_mut() {
+                cookie.expires_at = Some(now);
+            }
+
+            self.persist(&store).await?;
+        }
+
+        self.refresh_status().await;
+        Ok(())
+    }
+
+    /// Profile of the active account, fetched again once the cached one is older than
+    /// `PROFILE_TTL`. `None` if no one is signed in.
+    pub async fn profile(&self) -> anyhow::Result<Option<Profile>> {
+        let account = {
+            let store = self.store.read().await;
+            match store.active().filter(|a| !a.is_expired(unix_now())) {
+                Some(account) => account.clone(),
+                None => return Ok(None),
+            }
+        };
+
+        let mut cache = self.profile.lock().await;
+        if let Some(cached) = cache.as_ref() {
+            if cached.account_id == account.id && cached.fetched_at.elapsed() < PROFILE_TTL {
+                return Ok(Some(cached.profile.clone()));
+            }
+        }
+
+        let Some(profile) = fetch_profile(&account).await? else {
+            drop(cache);
+            // Also tells the frontend, so it asks the user to sign in again
+            expire_session().await;
+            return Err(AuthExpired.into());
+        };
+
+        *cache = Some(CachedProfile {
+            account_id: account.id,
+            fetched_at: Instant::now(),
+            profile: profile.clone(),
+        });
+        Ok(Some(profile))
+    }
+
+    pub fn subscribe_status(&self) -> watch::Receiver<AuthStatus> {
+        self.status.subscribe()
+    }
+
+    /// Fetches the profile so the status can include it, falls back to what the saved
+    /// account knows if that fails
+    async fn load_profile(&self) {
+        if let Err(e) = self.profile().await {
+            log::warn!("Couldn't load profile: {:?}", e);
+        }
+
+        self.refresh_status().await;
+    }
+
+    async fn refresh_status(&self) {
+        let status = {
+            let store = self.store.read().await;
+            let cache = self.profile.lock().await;
+            status_of(&store, cache.as_ref())
+        };
+
+        self.status.send_replace(status);
+    }
+
+    /// Opens the login page of the pending sign in again, e.g. if the browser didn't open
     pub fn open_sign_in_window(&self) -> () {
-        let url: String = clipture_to_url("/redirects/login?appLogin=true");
+        let url = self
+            .sign_in_url
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| clipture_to_url("/redirects/login?appLogin=true"));
         open::that_in_background(url);
     }
 
+    /// Waits until the user signed in in the browser or `cancel_sign_in` is called
     pub async fn sign_in(&self) -> anyhow::Result<()> {
-        let mut rx = self
-            .rx
-            .try_lock()
-            .map_err(|_| anyhow!("Already logging in [ALREADY_LOG]"))?;
+        self.sign_in_with(false, None).await
+    }
+
+    /// Signs in again without asking the user, which works if the browser still has a
+    /// session with the website
+    pub async fn silent_sign_in(&self) -> anyhow::Result<()> {
+        self.sign_in_with(true, Some(SILENT_SIGN_IN_TIMEOUT)).await
+    }
+
+    /// Stops the pending sign in, returns false if there is none
+    pub fn cancel_sign_in(&self) -> bool {
+        self.cancel_pending(|_| true)
+    }
+
+    /// Cancels the pending sign in if `filter` matches it
+    fn cancel_pending(&self, filter: impl FnOnce(&PendingSignIn) -> bool) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.take_if(|p| filter(p)) {
+            Some(p) => {
+                p.token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
 
-        self.open_sign_in_window();
+    async fn sign_in_with(&self, silent: bool, timeout: Option<Duration>) -> anyhow::Result<()> {
+        let mut rx = match self.rx.try_lock() {
+            std::result::Result::Ok(rx) => rx,
+            // The user asked to sign in, so a renewal in the background has to make way
+            Err(_) if !silent && self.cancel_pending(|p| p.silent) => self.rx.lock().await,
+            Err(_) => bail!("Already logging in [ALREADY_LOG]"),
+        };
+
+        let token = CancellationToken::new();
+        *self.pending.lock().unwrap() = Some(PendingSignIn {
+            token: token.clone(),
+            silent,
+        });
 
         // Clear URL callback here
         while !rx.is_empty() {
             let _ = rx.recv().await;
         }
 
-        // Timeout at 10 minutes
-        let timeout_at = Duration::from_secs(60 * 10);
-        let timeout_at = Instant::now() + timeout_at;
+        if !silent {
+            self.status.send_replace(AuthStatus::LoggingIn);
+        }
+
+        let attempt = SignInAttempt::new();
+        let secret = tokio::select! {
+            r = self.wait_for_secret(&attempt, silent, &mut rx) => r,
+            _ = token.cancelled() => Err(anyhow!("Sign in cancelled [CANCELLED]")),
+            _ = sleep_or_pending(timeout) => Err(anyhow!("Waiting for sign in timed out")),
+        };
+        *self.pending.lock().unwrap() = None;
+        *self.sign_in_url.lock().unwrap() = None;
 
-        let secret = loop {
-            let url = time::timeout_at(timeout_at, rx.recv())
-                .await
-                .context("Waiting for URL callback timed out")?
-                .ok_or_else(|| anyhow!("URL callback channel closed"))?;
+        let res = match secret {
+            std::result::Result::Ok(secret) => self.complete_sign_in(secret, &attempt).await,
+            Err(e) => Err(e),
+        };
 
-            if url.scheme() != "clipture" {
-                log::debug!("Ignoring URL: {}", url);
-                continue;
+        match &res {
+            std::result::Result::Ok(()) => self.load_profile().await,
+            Err(_) => self.refresh_status().await,
+        }
+        res
+    }
+
+    async fn wait_for_secret(
+        &self,
+        attempt: &SignInAttempt,
+        silent: bool,
+        rx: &mut UnboundedReceiver<Url>,
+    ) -> anyhow::Result<String> {
+        let mut path = attempt.login_path(silent);
+        match self.redirect_mode {
+            RedirectMode::DeepLink => {
+                self.open_login(&path);
+                loop {
+                    let url = rx
+                        .recv()
+                        .await
+                        .ok_or_else(|| anyhow!("URL callback channel closed"))?;
+
+                    match attempt.secret_from_deep_link(&url) {
+                        Some(secret) => return Ok(secret),
+                        None => log::debug!("Ignoring URL: {}", url),
+                    }
+                }
             }
+            RedirectMode::Loopback => {
+                let redirect = LoopbackRedirect::bind(attempt.state.clone()).await?;
+                path.push_str(&redirect.login_query()?);
+                self.open_login(&path);
 
-            if url.path().starts_with("/login") || url.path().starts_with("login") {
-                log::debug!("Ignoring URL: {}", url);
-                continue;
+                redirect.wait_for_secret().await
             }
+        }
+    }
 
-            break url
-                .query_pairs()
-                .find(|entry| entry.0 == "secret")
-                .ok_or_else(|| anyhow!("No secret in URL"))?
-                .1
-                .trim()
-                .to_string();
-        };
+    fn open_login(&self, path: &str) {
+        let url = clipture_to_url(path);
+        *self.sign_in_url.lock().unwrap() = Some(url.clone());
+        open::that_in_background(url);
+    }
 
-        let client = reqwest::Client::new();
+    /// Exchanges the secret from the login redirect for session cookies. The verifier of
+    /// the attempt is sent along as `X-Code-Verifier`, so a secret alone is worthless.
+    async fn complete_sign_in(
+        &self,
+        secret: String,
+        attempt: &SignInAttempt,
+    ) -> anyhow::Result<()> {
+        // Not signed in yet, so there are no cookies to send
+        let client = ApiClient::with(&clipture_to_url(""), None);
         let res = client
-            .get(clipture_to_url("/api/validation/report"))
-            .header("Authorization", secret)
-            .send()
+            .send(
+                client
+                    .get("/api/validation/report")
+                    .header("Authorization", secret)
+                    .header("X-Code-Verifier", attempt.verifier()),
+            )
             .await?;
 
         let res = res.error_for_status()?;
         let res: validation::report::Root = res.json().await?;
 
-        let mut mapped = HashMap::<String, String>::new();
-        for entry in &res.entry {
-            mapped.insert(entry.key.clone(), entry.cookie.clone());
+        if res.entry.is_empty() {
+            log::warn!("{:?}", res);
+            bail!("No cookies received");
+        }
+
+        // Accounts are saved under the user id, without it signing in again would add
+        // the same user a second time
+        let mut account = Account::from_report(String::new(), &res, unix_now());
+        let user = fetch_user(&account)
+            .await
+            .context("Fetching user of new session")?
+            .ok_or_else(|| anyhow!("The new session was rejected"))?;
+        account.id = user.id;
+        account.name = user.name;
+
+        log::debug!("Saved {} total of cookies", account.cookies.len());
+        let mut store = self.store.write().await;
+        store.upsert(account);
+        self.persist(&store).await
+    }
+
+    /// Checks the active session with the API. Returns false if it expired or was rejected,
+    /// errors if the API couldn't be reached.
+    pub async fn validate(&self) -> anyhow::Result<bool> {
+        let account = self.store.read().await.active().cloned();
+        let Some(account) = account else {
+            return Ok(false);
+        };
+
+        if account.is_expired(unix_now()) {
+            return Ok(false);
         }
 
-        // Wait for URL callback here
-        let as_str = serde_json::to_string(&mapped).context("Serializing JSON")?;
-        self.entry
-            .set_password(&as_str)
-            .context("Setting password")?;
+        let Some(user) = fetch_user(&account).await? else {
+            return Ok(false);
+        };
 
-        if mapped.len() == 0 {
-            log::warn!("{:?}", res);
-            bail!("No cookies received");
+        {
+            let mut store = self.store.write().await;
+            if let Some(active) = store.active_mut().filter(|a| a.id == account.id) {
+                active.name = user.name.or(active.name.take());
+                active.validated_at = Some(unix_now());
+            }
+            self.persist(&store).await?;
         }
-        log::debug!("Saved {} total of cookies", mapped.len());
-        *self.cookie_map.write().await = Some(mapped);
 
-        Ok(())
+        self.load_profile().await;
+        Ok(true)
+    }
+}
+
+async fn sleep_or_pending(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => time::sleep(timeout).await,
+        None => std::future::pending().await,
     }
 }
 
+fn status_of(store: &AccountStore, cache: Option<&CachedProfile>) -> AuthStatus {
+    match store.active() {
+        None => AuthStatus::LoggedOut,
+        Some(account) if account.is_expired(unix_now()) => AuthStatus::Expired,
+        Some(account) => AuthStatus::LoggedIn(
+            cache
+                .filter(|c| c.account_id == account.id)
+                .map(|c| c.profile.clone())
+                .unwrap_or_else(|| Profile::from_account(account)),
+        ),
+    }
+}
+
+/// Profile of the account, `None` if the API rejected its cookies
+async fn fetch_profile(account: &Account) -> anyhow::Result<Option<Profile>> {
+    let client = ApiClient::with(&clipture_to_url(""), Some(&account.cookie_values()));
+
+    // Sent directly, the caller decides what a rejection means
+    let res = client.get("/api/user/profile").send().await?;
+    if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(None);
+    }
+
+    let profile: user::profile::Root = res.error_for_status()?.json().await?;
+    Ok(Some(profile.into()))
+}
+
+/// The user the account's cookies belong to, `None` if the API rejected them
+async fn fetch_user(account: &Account) -> anyhow::Result<Option<validation::session::User>> {
+    let client = ApiClient::with(&clipture_to_url(""), Some(&account.cookie_values()));
+
+    // Sent directly, a rejection here is no reason to sign out whoever is active
+    let res = client.get("/api/validation/session").send().await?;
+    if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(None);
+    }
+
+    let session: validation::session::Root = res.error_for_status()?.json().await?;
+    Ok(Some(session.user))
+}
+
+/// Validates the active session periodically. Sessions that run out soon or were rejected
+/// are renewed silently if possible, otherwise the frontend is told to ask the user to sign
+/// in again.
+pub fn spawn_session_validator() {
+    tauri::async_runtime::spawn(async move {
+        let mut state = ValidatorState::default();
+        loop {
+            if !renew_session(&mut state).await {
+                expire_session().await;
+            }
+
+            time::sleep(VALIDATION_INTERVAL).await;
+        }
+    });
+}
+
+/// What the validator already did about a session, so a silent sign in opens the browser
+/// at most once and the user is asked to sign in again only once
+#[derive(Default)]
+struct ValidatorState {
+    /// Account a silent renewal was tried for
+    renewed: Option<String>,
+    /// Account whose expiry was reported to the frontend
+    reported: Option<String>,
+}
+
+impl ValidatorState {
+    /// Whether the expiry of the account still has to be reported
+    fn report(&mut self, id: &str) -> bool {
+        if self.reported.as_deref() == Some(id) {
+            return false;
+        }
+
+        self.reported = Some(id.to_string());
+        true
+    }
+}
+
+/// Returns false if the active session expired and couldn't be renewed
+async fn renew_session(state: &mut ValidatorState) -> bool {
+    let auth = AUTH_MANAGER.read().await;
+    let Some(auth) = auth.as_ref() else {
+        return true;
+    };
+
+    let Some(account) = auth.store.read().await.active().cloned() else {
+        return true;
+    };
+
+    let now = unix_now();
+    if account.is_expired(now) {
+        // Too late to renew it, the user has to sign in again
+        return !state.report(&account.id);
+    }
+
+    // Renewed ahead of time, the next check could come too late
+    let next_check = now + VALIDATION_INTERVAL.as_secs() as i64;
+    let expiring = account
+        .expires_at()
+        .is_some_and(|at| at - EXPIRY_MARGIN_SECS <= next_check);
+
+    let valid = match auth.validate().await {
+        std::result::Result::Ok(valid) => valid,
+        Err(e) => {
+            // Offline or API down, that doesn't mean the session is invalid
+            log::warn!("Couldn't validate session: {:?}", e);
+            return true;
+        }
+    };
+
+    if valid && !expiring {
+        *state = ValidatorState::default();
+        return true;
+    }
+
+    if state.renewed.as_ref() != Some(&account.id) {
+        state.renewed = Some(account.id.clone());
+
+        log::info!(
+            "Session {}, trying to renew it",
+            if valid {
+                "runs out soon"
+            } else {
+                "was rejected"
+            }
+        );
+        match auth.silent_sign_in().await {
+            std::result::Result::Ok(()) => return true,
+            Err(e) => log::warn!("Couldn't renew session: {:?}", e),
+        }
+    }
+
+    // A session that still works is reported once it ran out
+    valid || !state.report(&account.id)
+}
+
 lazy_static! {
     pub static ref AUTH_MANAGER: Arc<RwLock<Option<AuthManager>>> = Arc::new(RwLock::new(None));
 }
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::account::Account;
+use crate::json_typings::clipture_api::user::profile;
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageQuota {
+    #[specta(type = f64)]
+    pub used_bytes: u64,
+    #[specta(type = f64)]
+    pub quota_bytes: u64,
+}
+
+/// The signed in user as returned by `/api/user/profile`
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub plan: Option<String>,
+    pub storage: Option<StorageQuota>,
+}
+
+impl Profile {
+    /// What is known about the account without asking the API, e.g. while offline
+    pub fn from_account(account: &Account) -> Self {
+        Self {
+            id: account.id.clone(),
+            display_name: account.name.clone(),
+            avatar_url: None,
+            plan: None,
+            storage: None,
+        }
+    }
+}
+
+impl From<profile::Root> for Profile {
+    fn from(root: profile::Root) -> Self {
+        Self {
+            id: root.id,
+            display_name: root.name,
+            avatar_url: root.image,
+            plan: root.plan,
+            storage: root.storage.map(|s| StorageQuota {
+                used_bytes: s.used,
+                quota_bytes: s.quota,
+            }),
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AuthStatus {
+    LoggedOut,
+    LoggingIn,
+    LoggedIn(Profile),
+    /// There is an account, but its session has to be renewed by signing in again
+    Expired,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_api_profiles() -> anyhow::Result<()> {
+        let root: profile::Root = serde_json::from_str(
+            r#"{"id":"u1","name":"Alice","image":"https://cdn/a.png","plan":"pro","storage":{"used":10,"quota":100}}"#,
+        )?;
+
+        let profile = Profile::from(root);
+        assert_eq!(profile.display_name.as_deref(), Some("Alice"));
+        assert_eq!(profile.avatar_url.as_deref(), Some("https://cdn/a.png"));
+        assert_eq!(
+            profile.storage,
+            Some(StorageQuota {
+                used_bytes: 10,
+                quota_bytes: 100
+            })
+        );
+
+        // Older API versions don't send the entitlements
+        let root: profile::Root = serde_json::from_str(r#"{"id":"u1","name":null}"#)?;
+        assert_eq!(Profile::from(root).plan, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{fs, sync::broadcast};
+use uuid::Uuid;
+
+use super::{
+    ffmpeg::{probe_streams, run_ffmpeg_with_progress, StreamParams},
+    queue_thumbnails, with_library, Clip, NewClip,
+};
+
+/// How many updates a progress subscriber can fall behind
+const EDIT_UPDATES_CAPACITY: usize = 64;
+
+lazy_static! {
+    static ref EDIT_UPDATES: broadcast::Sender<EditUpdate> =
+        broadcast::channel(EDIT_UPDATES_CAPACITY).0;
+}
+
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub enum EditStatus {
+    Progress(f32, String),
+    /// The new clip, already added to the library
+    Done(Clip),
+    Error(String),
+}
+
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub struct EditUpdate {
+    pub job_id: String,
+    pub status: EditStatus,
+}
+
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub struct TrimRequest {
+    pub id: u32,
+    pub start_ms: u32,
+    pub end_ms: u32,
+}
+
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub struct MergeRequest {
+    /// Clips in the order they should appear in the merged clip
+    pub ids: Vec<u32>,
+}
+
+pub fn subscribe_edits() -> broadcast::Receiver<EditUpdate> {
+    EDIT_UPDATES.subscribe()
+}
+
+fn send_update(job_id: &str, status: EditStatus) {
+    // No one listening is fine, the result ends up in the library anyway
+    let _ = EDIT_UPDATES.send(EditUpdate {
+        job_id: job_id.to_string(),
+        status,
+    });
+}
+
+/// `<stem> (<suffix>).<ext>` next to `source`, numbered if that file already exists
+fn output_path(source: &Path, suffix: &str) -> PathBuf {
+    let dir = source.parent().unwrap_or(Path::new("."));
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = source
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| "mp4".to_string());
+
+    (1..)
+        .map(|i| match i {
+            1 => format!("{} ({}).{}", stem, suffix, ext),
+            i => format!("{} ({} {}).{}", stem, suffix, i, ext),
+        })
+        .map(|name| dir.join(name))
+        .find(|p| !p.exists())
+        .expect("Ran out of file names")
+}
+
+/// Line of a concat demuxer list, single quotes have to be closed, escaped and reopened
+fn concat_entry(path: &Path) -> String {
+    format!("file '{}'\n", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+fn check_compatible(streams: &[Vec<StreamParams>]) -> anyhow::Result<()> {
+    let Some((first, rest)) = streams.split_first() else {
+        return Ok(());
+    };
+
+    for (i, other) in rest.iter().enumerate() {
+        if other != first {
+            bail!(
+                "Clip {} has different codec parameters than the first clip, it can't be merged without re-encoding",
+                i + 2
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_trim(req: &TrimRequest, duration_ms: u32) -> anyhow::Result<()> {
+    if req.start_ms >= req.end_ms {
+        bail!("Start of the trim has to be before its end");
+    }
+    if duration_ms > 0 && req.start_ms >= duration_ms {
+        bail!("Start of the trim is after the end of the clip");
+    }
+
+    Ok(())
+}
+
+async fn get_clips(ids: &[u32]) -> anyhow::Result<Vec<Clip>> {
+    let ids = ids.to_vec();
+    with_library(move |library| {
+        ids.iter()
+            .map(|id| {
+                library
+                    .get(*id)?
+                    .ok_or_else(|| anyhow!("No clip with id {}", id))
+            })
+            .collect()
+    })
+    .await
+}
+
+/// Adds the edited clip to the library, inheriting game and title from `source`
+async fn index_output(output: PathBuf, source: &Clip) -> anyhow::Result<Clip> {
+    let clip = NewClip {
+        path: output,
+        kind: source.kind,
+        game_id: source.game_id.clone(),
+        window_title: source.window_title.clone(),
+        created_at: None,
+    };
+    let clip = with_library(move |library| library.index(clip)).await?;
+
+    queue_thumbnails(clip.id).await;
+    Ok(clip)
+}
+
+/// Reports the outcome of a job and removes the partial output if it failed
+async fn finish(job_id: &str, output: &Path, result: anyhow::Result<Clip>) {
+    match result {
+        Ok(clip) => send_update(job_id, EditStatus::Done(clip)),
+        Err(e) => {
+            log::error!("Edit job {} failed: {:?}", job_id, e);
+            let _ = fs::remove_file(output).await;
+            send_update(job_id, EditStatus::Error(e.to_string()));
+        }
+    }
+}
+
+/// Cuts the clip without re-encoding. The cut starts at the keyframe before `start_ms`,
+/// so the result can be slightly longer than requested. Returns the id of the job.
+pub async fn start_trim(req: TrimRequest) -> anyhow::Result<String> {
+    let source = get_clips(&[req.id]).await?.remove(0);
+    validate_trim(&req, source.duration_ms)?;
+
+    let job_id = Uuid::new_v4().to_string();
+    let output = output_path(Path::new(&source.path), "trimmed");
+
+    let job = job_id.clone();
+    tokio::spawn(async move {
+        send_update(&job, EditStatus::Progress(0.0, "Trimming clip".to_string()));
+
+        let end_ms = match source.duration_ms {
+            0 => req.end_ms,
+            duration => req.end_ms.min(duration),
+        };
+        let length_ms = end_ms - req.start_ms;
+
+        let start = format!("{:.3}", req.start_ms as f64 / 1000.0);
+        let length = format!("{:.3}", length_ms as f64 / 1000.0);
+        let out = output.to_string_lossy().to_string();
+
+        let result = run_ffmpeg_with_progress(
+            &[
+                "-ss",
+                &start,
+                "-i",
+                &source.path,
+                "-t",
+                &length,
+                "-map",
+                "0",
+                "-c",
+                "copy",
+                "-avoid_negative_ts",
+                "make_zero",
+                &out,
+            ],
+            length_ms,
+            |p| send_update(&job, EditStatus::Progress(p, "Trimming clip".to_string())),
+        )
+        .await;
+
+        let result = match result {
+            Ok(()) => index_output(output.clone(), &source).await,
+            Err(e) => Err(e),
+        };
+
+        finish(&job, &output, result).await;
+    });
+
+    Ok(job_id)
+}
+
+/// Concatenates clips with identical codec parameters without re-encoding.
+/// Returns the id of the job.
+pub async fn start_merge(req: MergeRequest) -> anyhow::Result<String> {
+    if req.ids.len() < 2 {
+        bail!("At least two clips are needed to merge");
+    }
+
+    let clips = get_clips(&req.ids).await?;
+    let job_id = Uuid::new_v4().to_string();
+    let output = output_path(Path::new(&clips[0].path), "merged");
+
+    let job = job_id.clone();
+    tokio::spawn(async move {
+        send_update(
+            &job,
+            EditStatus::Progress(0.0, "Checking clips".to_string()),
+        );
+
+        let result = merge(&job, &clips, &output).await;
+        let result = match result {
+            Ok(()) => index_output(output.clone(), &clips[0]).await,
+            Err(e) => Err(e),
+        };
+
+        finish(&job, &output, result).await;
+    });
+
+    Ok(job_id)
+}
+
+async fn merge(job: &str, clips: &[Clip], output: &Path) -> anyhow::Result<()> {
+    let mut streams = vec![];
+    for clip in clips {
+        streams.push(probe_streams(Path::new(&clip.path)).await?);
+    }
+    check_compatible(&streams)?;
+
+    let list = output.with_extension("txt");
+    let entries: String = clips
+        .iter()
+        .map(|c| concat_entry(Path::new(&c.path)))
+        .collect();
+    fs::write(&list, entries)
+        .await
+        .context("Writing concat list")?;
+
+    let total_ms = clips.iter().map(|c| c.duration_ms).sum();
+    let result = run_ffmpeg_with_progress(
+        &[
+            "-f",
+            "concat",
+            "-safe",
+            "0",
+            "-i",
+            &list.to_string_lossy(),
+            "-map",
+            "0",
+            "-c",
+            "copy",
+            &output.to_string_lossy(),
+        ],
+        total_ms,
+        |p| send_update(job, EditStatus::Progress(p, "Merging clips".to_string())),
+    )
+    .await;
+
+    let _ = fs::remove_file(&list).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(codec: &str, width: u32) -> StreamParams {
+        StreamParams {
+            codec_type: "video".to_string(),
+            codec_name: codec.to_string(),
+            width: Some(width),
+            height: Some(1080),
+            pix_fmt: Some("yuv420p".to_string()),
+            sample_rate: None,
+            channels: None,
+        }
+    }
+
+    #[test]
+    fn output_paths_are_unique() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("clipture-edit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let source = dir.join("Replay 1.mkv");
+
+        let first = output_path(&source, "trimmed");
+        assert_eq!(first, dir.join("Replay 1 (trimmed).mkv"));
+
+        std::fs::write(&first, "")?;
+        let second = output_path(&source, "trimmed");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(second, dir.join("Replay 1 (trimmed 2).mkv"));
+        Ok(())
+    }
+
+    #[test]
+    fn concat_entries_are_escaped() {
+        assert_eq!(
+            concat_entry(Path::new("/clips/it's.mp4")),
+            "file '/clips/it'\\''s.mp4'\n"
+        );
+    }
+
+    #[test]
+    fn only_identical_codecs_merge() {
+        let h264 = vec![params("h264", 1920)];
+        assert!(check_compatible(&[h264.clone(), h264.clone()]).is_ok());
+        assert!(check_compatible(&[h264.clone(), vec![params("hevc", 1920)]]).is_err());
+        assert!(check_compatible(&[h264, vec![params("h264", 1280)]]).is_err());
+    }
+
+    #[test]
+    fn trim_ranges_are_validated() {
+        let trim = |start_ms, end_ms| TrimRequest {
+            id: 1,
+            start_ms,
+            end_ms,
+        };
+
+        assert!(validate_trim(&trim(1000, 5000), 10_000).is_ok());
+        assert!(validate_trim(&trim(5000, 5000), 10_000).is_err());
+        assert!(validate_trim(&trim(12_000, 15_000), 10_000).is_err());
+        // Unknown duration
+        assert!(validate_trim(&trim(12_000, 15_000), 0).is_ok());
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+use super::probe::VideoInfo;
+
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+/// Binary shipped next to Clipture as a sidecar, falling back to the one in PATH
+fn tool_path(tool: &str) -> PathBuf {
+    let name = if cfg!(windows) {
+        format!("{}.exe", tool)
+    } else {
+        tool.to_string()
+    };
+
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(&name)))
+        .filter(|p| p.exists())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+fn command(tool: &str) -> Command {
+    let mut cmd = Command::new(tool_path(tool));
+    cmd.args(["-hide_banner", "-loglevel", "error"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    #[cfg(windows)]
+    cmd.creation_flags(CREATE_NO_WINDOW);
+
+    cmd
+}
+
+fn spawn_error(tool: &str, e: std::io::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        return anyhow!("{} is missing, reinstall Clipture to restore it", tool);
+    }
+
+    anyhow::Error::from(e).context(format!("Running {}", tool))
+}
+
+/// Whether ffmpeg and ffprobe can be started. Thumbnails and editing don't work without
+/// them, so the frontend shows a hint instead.
+pub async fn ffmpeg_available() -> bool {
+    for tool in ["ffmpeg", "ffprobe"] {
+        let out = command(tool).arg("-version").output().await;
+        if !out.is_ok_and(|o| o.status.success()) {
+            log::warn!("{} can't be run from {}", tool, tool_path(tool).display());
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Runs ffmpeg with the given arguments, overwriting the output
+pub async fn run_ffmpeg(args: &[&str]) -> anyhow::Result<()> {
+    let out = command("ffmpeg")
+        .arg("-y")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| spawn_error("ffmpeg", e))?;
+
+    if !out.status.success() {
+        bail!(
+            "ffmpeg exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Reads the `out_time_ms` value of a `-progress` line, which despite its name is in microseconds
+fn parse_progress_line(line: &str) -> Option<u64> {
+    let (key, value) = line.split_once('=')?;
+    if key.trim() != "out_time_ms" && key.trim() != "out_time_us" {
+        return None;
+    }
+
+    value.trim().parse().ok()
+}
+
+/// Runs ffmpeg and calls `on_progress` with a value between 0 and 1, based on how much
+/// of `total_ms` has been written
+pub async fn run_ffmpeg_with_progress<F>(
+    args: &[&str],
+    total_ms: u32,
+    mut on_progress: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(f32),
+{
+    let mut child = command("ffmpeg")
+        .args(["-y", "-nostats", "-progress", "pipe:1"])
+        .args(args)
+        .spawn()
+        .map_err(|e| spawn_error("ffmpeg", e))?;
+
+    let stdout = child.stdout.take().context("No ffmpeg stdout")?;
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(us) = parse_progress_line(&line) {
+            if total_ms > 0 {
+                on_progress((us as f64 / 1000.0 / total_ms as f64).clamp(0.0, 1.0) as f32);
+            }
+        }
+    }
+
+    let out = child.wait_with_output().await?;
+    if !out.status.success() {
+        bail!(
+            "ffmpeg exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Codec parameters that have to match for streams to be concatenated without re-encoding
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StreamParams {
+    pub codec_type: String,
+    pub codec_name: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pix_fmt: Option<String>,
+    pub sample_rate: Option<String>,
+    pub channels: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    streams: Vec<StreamParams>,
+}
+
+pub async fn probe_streams(path: &Path) -> anyhow::Result<Vec<StreamParams>> {
+    let out = command("ffprobe")
+        .args([
+            "-show_entries",
+            "stream=codec_type,codec_name,width,height,pix_fmt,sample_rate,channels",
+            "-of",
+            "json",
+        ])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| spawn_error("ffprobe", e))?;
+
+    if !out.status.success() {
+        bail!(
+            "ffprobe exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    let probe: ProbeOutput =
+        serde_json::from_slice(&out.stdout).context("Parsing ffprobe output")?;
+    Ok(probe.streams)
+}
+
+#[derive(Deserialize)]
+struct VideoStream {
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct VideoFormat {
+    /// Seconds as a decimal string
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VideoProbeOutput {
+    streams: Vec<VideoStream>,
+    format: VideoFormat,
+}
+
+fn parse_video_info(json: &[u8]) -> anyhow::Result<VideoInfo> {
+    let probe: VideoProbeOutput = serde_json::from_slice(json).context("Parsing ffprobe output")?;
+    let seconds: f64 = probe
+        .format
+        .duration
+        .context("ffprobe reported no duration")?
+        .parse()
+        .context("Parsing duration")?;
+    let stream = probe.streams.first();
+
+    Ok(VideoInfo {
+        duration_ms: (seconds * 1000.0).round() as u32,
+        width: stream.and_then(|s| s.width).unwrap_or_default(),
+        height: stream.and_then(|s| s.height).unwrap_or_default(),
+    })
+}
+
+/// Reads the duration and resolution of containers `probe_mp4` doesn't understand.
+/// Blocks until ffprobe exits, so it can run on the library's blocking threads.
+pub fn probe_video_blocking(path: &Path) -> anyhow::Result<VideoInfo> {
+    let mut cmd = std::process::Command::new(tool_path("ffprobe"));
+    cmd.args([
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-select_streams",
+        "v:0",
+        "-show_entries",
+        "stream=width,height:format=duration",
+        "-of",
+        "json",
+    ])
+    .arg(path)
+    .stdin(Stdio::null());
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        cmd.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let out = cmd.output().map_err(|e| spawn_error("ffprobe", e))?;
+    if !out.status.success() {
+        bail!(
+            "ffprobe exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    parse_video_info(&out.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_progress() {
+        assert_eq!(parse_progress_line("out_time_ms=1500000"), Some(1_500_000));
+        assert_eq!(parse_progress_line("out_time_us=20"), Some(20));
+        assert_eq!(parse_progress_line("out_time_ms=N/A"), None);
+        assert_eq!(parse_progress_line("progress=continue"), None);
+    }
+
+    #[test]
+    fn parses_video_info() {
+        let json =
+            br#"{"streams":[{"width":1920,"height":1080}],"format":{"duration":"12.345678"}}"#;
+        assert_eq!(
+            parse_video_info(json).unwrap(),
+            VideoInfo {
+                duration_ms: 12_346,
+                width: 1920,
+                height: 1080,
+            }
+        );
+
+        let no_video = br#"{"streams":[],"format":{"duration":"3.0"}}"#;
+        assert_eq!(parse_video_info(no_video).unwrap().width, 0);
+        assert!(parse_video_info(br#"{"streams":[],"format":{}}"#).is_err());
+    }
+}
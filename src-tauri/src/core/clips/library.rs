@@ -0,0 +1,642 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+
+use super::{
+    probe::probe_video, Clip, ClipFilter, ClipKind, ClipMetadataUpdate, ClipPage, ClipSort, NewClip,
+};
+
+/// Bump this and add a statement to `MIGRATIONS` when the schema changes
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE clips (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        path TEXT NOT NULL UNIQUE,
+        kind TEXT NOT NULL,
+        game_id TEXT,
+        window_title TEXT,
+        created_at TEXT NOT NULL,
+        duration_ms INTEGER NOT NULL DEFAULT 0,
+        width INTEGER NOT NULL DEFAULT 0,
+        height INTEGER NOT NULL DEFAULT 0,
+        size_bytes INTEGER NOT NULL DEFAULT 0,
+        favorite INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX clips_created_at ON clips (created_at);
+    CREATE INDEX clips_game_id ON clips (game_id);
+    CREATE TABLE clip_tags (
+        clip_id INTEGER NOT NULL REFERENCES clips (id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (clip_id, tag)
+    );
+"#,
+    r#"
+    ALTER TABLE clips ADD COLUMN thumbnail_key TEXT;
+"#,
+];
+
+/// Containers the ffmpeg_muxer writes
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mkv"];
+
+const CLIP_COLUMNS: &str = "id, path, kind, game_id, window_title, created_at, duration_ms, width, height, size_bytes, favorite, thumbnail_key IS NOT NULL";
+
+fn kind_to_str(kind: ClipKind) -> &'static str {
+    match kind {
+        ClipKind::Replay => "replay",
+        ClipKind::Recording => "recording",
+    }
+}
+
+fn kind_from_str(kind: &str) -> ClipKind {
+    match kind {
+        "recording" => ClipKind::Recording,
+        _ => ClipKind::Replay,
+    }
+}
+
+/// Recordings are named "Recording <date>.mp4", everything else OBS writes is a replay
+fn guess_kind(path: &Path) -> ClipKind {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name.starts_with("Recording ") {
+        ClipKind::Recording
+    } else {
+        ClipKind::Replay
+    }
+}
+
+/// Index of the clips in the clips directory, stored in a SQLite database
+pub struct ClipLibrary {
+    conn: Mutex<Connection>,
+    clips_dir: PathBuf,
+}
+
+impl ClipLibrary {
+    pub fn open(db_file: &Path, clips_dir: PathBuf) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_file).context("Opening clip database")?;
+        Self::with_connection(conn, clips_dir)
+    }
+
+    fn with_connection(mut conn: Connection, clips_dir: PathBuf) -> anyhow::Result<Self> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Self::migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            clips_dir,
+        })
+    }
+
+    fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+        let version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "Clip database version {} is newer than supported version {}",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+
+            log::info!("Migrated clip database to version {}", i + 1);
+        }
+
+        Ok(())
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().expect("Clip database lock poisoned")
+    }
+
+    /// Adds the clip or updates it if the path is already indexed
+    pub fn index(&self, clip: NewClip) -> anyhow::Result<Clip> {
+        let meta = fs::metadata(&clip.path).context("Reading clip metadata")?;
+        let info = probe_video(&clip.path).unwrap_or_else(|e| {
+            log::warn!("Couldn't probe {}: {:?}", clip.path.display(), e);
+            Default::default()
+        });
+
+        let created_at = clip.created_at.unwrap_or_else(|| {
+            meta.modified()
+                .map(DateTime::<Utc>::from)
+                .unwrap_or_else(|_| Utc::now())
+                .to_rfc3339()
+        });
+
+        let conn = self.conn();
+        let id = conn.query_row(
+            "INSERT INTO clips (path, kind, game_id, window_title, created_at, duration_ms, width, height, size_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (path) DO UPDATE SET
+                duration_ms = excluded.duration_ms,
+                width = excluded.width,
+                height = excluded.height,
+                size_bytes = excluded.size_bytes,
+                game_id = COALESCE(excluded.game_id, clips.game_id),
+                window_title = COALESCE(excluded.window_title, clips.window_title),
+                thumbnail_key = CASE WHEN clips.size_bytes = excluded.size_bytes THEN clips.thumbnail_key END
+             RETURNING id",
+            params![
+                clip.path.to_string_lossy(),
+                kind_to_str(clip.kind),
+                clip.game_id,
+                clip.window_title,
+                created_at,
+                info.duration_ms,
+                info.width,
+                info.height,
+                meta.len() as i64,
+            ],
+            |r| r.get::<_, u32>(0),
+        )?;
+
+        Self::get_with(&conn, id)?.context("Clip disappeared after inserting")
+    }
+
+    /// Indexes videos in the clips directory that are missing and removes clips whose
+    /// file is gone. Clips that couldn't be probed are probed again, e.g. recordings that
+    /// were indexed before OBS finished writing them. Returns how many clips were added
+    /// and removed.
+    pub fn reconcile(&self) -> anyhow::Result<(usize, usize)> {
+        let on_disk = fs::read_dir(&self.clips_dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .filter(|p| {
+                p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| VIDEO_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+            })
+            .collect::<Vec<_>>();
+
+        let indexed = {
+            let conn = self.conn();
+            let mut stmt = conn.prepare("SELECT id, path FROM clips")?;
+            let rows = stmt
+                .query_map([], |r| Ok((r.get::<_, u32>(0)?, r.get::<_, String>(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok::<_, rusqlite::Error>(rows)
+        }?;
+
+        let mut removed = 0;
+        for (id, path) in indexed.iter() {
+            if !Path::new(path).exists() {
+                self.conn()
+                    .execute("DELETE FROM clips WHERE id = ?1", [id])?;
+                removed += 1;
+            }
+        }
+
+        let incomplete = {
+            let conn = self.conn();
+            let mut stmt = conn.prepare("SELECT path, kind FROM clips WHERE duration_ms = 0")?;
+            let rows = stmt
+                .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok::<_, rusqlite::Error>(rows)
+        }?;
+
+        for (path, kind) in incomplete {
+            let path = PathBuf::from(path);
+            if !path.exists() {
+                continue;
+            }
+
+            let r = self.index(NewClip {
+                path: path.clone(),
+                kind: kind_from_str(&kind),
+                game_id: None,
+                window_title: None,
+                created_at: None,
+            });
+            if let Err(e) = r {
+                log::warn!("Couldn't probe {} again: {:?}", path.display(), e);
+            }
+        }
+
+        let indexed = indexed
+            .into_iter()
+            .map(|(_, p)| PathBuf::from(p))
+            .collect::<HashSet<_>>();
+
+        let mut added = 0;
+        for path in on_disk.into_iter().filter(|p| !indexed.contains(p)) {
+            let kind = guess_kind(&path);
+            let r = self.index(NewClip {
+                path: path.clone(),
+                kind,
+                game_id: None,
+                window_title: None,
+                created_at: None,
+            });
+
+            match r {
+                Ok(_) => added += 1,
+                Err(e) => log::warn!("Couldn't index {}: {:?}", path.display(), e),
+            }
+        }
+
+        Ok((added, removed))
+    }
+
+    fn read_clip(conn: &Connection, row: &Row) -> rusqlite::Result<Clip> {
+        let id: u32 = row.get(0)?;
+        let mut stmt =
+            conn.prepare_cached("SELECT tag FROM clip_tags WHERE clip_id = ?1 ORDER BY tag")?;
+        let tags = stmt
+            .query_map([id], |r| r.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Clip {
+            id,
+            path: row.get(1)?,
+            kind: kind_from_str(&row.get::<_, String>(2)?),
+            game_id: row.get(3)?,
+            window_title: row.get(4)?,
+            created_at: row.get(5)?,
+            duration_ms: row.get(6)?,
+            width: row.get(7)?,
+            height: row.get(8)?,
+            size_bytes: row.get::<_, i64>(9)? as u64,
+            favorite: row.get(10)?,
+            has_thumbnails: row.get(11)?,
+            tags,
+        })
+    }
+
+    fn get_with(conn: &Connection, id: u32) -> anyhow::Result<Option<Clip>> {
+        let sql = format!("SELECT {} FROM clips WHERE id = ?1", CLIP_COLUMNS);
+        let clip = conn
+            .query_row(&sql, [id], |r| Self::read_clip(conn, r))
+            .optional()?;
+
+        Ok(clip)
+    }
+
+    pub fn get(&self, id: u32) -> anyhow::Result<Option<Clip>> {
+        Self::get_with(&self.conn(), id)
+    }
+
+    pub fn list(&self, filter: &ClipFilter) -> anyhow::Result<ClipPage> {
+        let mut conditions = vec![];
+        let mut args: Vec<Value> = vec![];
+
+        if let Some(game_id) = &filter.game_id {
+            conditions.push("game_id = ?");
+            args.push(Value::Text(game_id.clone()));
+        }
+        if let Some(kind) = filter.kind {
+            conditions.push("kind = ?");
+            args.push(Value::Text(kind_to_str(kind).to_string()));
+        }
+        if let Some(favorite) = filter.favorite {
+            conditions.push("favorite = ?");
+            args.push(Value::Integer(favorite as i64));
+        }
+        if let Some(tag) = &filter.tag {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM clip_tags t WHERE t.clip_id = clips.id AND t.tag = ?)",
+            );
+            args.push(Value::Text(tag.clone()));
+        }
+        if let Some(search) = filter.search.as_ref().filter(|s| !s.trim().is_empty()) {
+            conditions.push("(window_title LIKE ? ESCAPE '\\' OR path LIKE ? ESCAPE '\\')");
+            let escaped = search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            let pattern = format!("%{}%", escaped);
+            args.push(Value::Text(pattern.clone()));
+            args.push(Value::Text(pattern));
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let order = match filter.sort {
+            ClipSort::Newest => "created_at DESC",
+            ClipSort::Oldest => "created_at ASC",
+            ClipSort::Longest => "duration_ms DESC",
+            ClipSort::Largest => "size_bytes DESC",
+        };
+
+        let conn = self.conn();
+        let total: u32 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM clips {}", where_clause),
+            params_from_iter(args.iter()),
+            |r| r.get(0),
+        )?;
+
+        let sql = format!(
+            "SELECT {} FROM clips {} ORDER BY {}, id DESC LIMIT ? OFFSET ?",
+            CLIP_COLUMNS, where_clause, order
+        );
+        args.push(Value::Integer(filter.limit as i64));
+        args.push(Value::Integer(filter.offset as i64));
+
+        let mut stmt = conn.prepare(&sql)?;
+        let clips = stmt
+            .query_map(params_from_iter(args.iter()), |r| Self::read_clip(&conn, r))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ClipPage { clips, total })
+    }
+
+    pub fn update_metadata(
+        &self,
+        id: u32,
+        update: ClipMetadataUpdate,
+    ) -> anyhow::Result<Option<Clip>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let exists = tx
+            .query_row("SELECT 1 FROM clips WHERE id = ?1", [id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !exists {
+            return Ok(None);
+        }
+
+        if let Some(favorite) = update.favorite {
+            tx.execute(
+                "UPDATE clips SET favorite = ?1 WHERE id = ?2",
+                params![favorite, id],
+            )?;
+        }
+        if let Some(title) = update.window_title {
+            tx.execute(
+                "UPDATE clips SET window_title = ?1 WHERE id = ?2",
+                params![title, id],
+            )?;
+        }
+        if let Some(tags) = update.tags {
+            tx.execute("DELETE FROM clip_tags WHERE clip_id = ?1", [id])?;
+            for tag in tags.iter().map(|t| t.trim()).filter(|t| !t.is_empty()) {
+                tx.execute(
+                    "INSERT OR IGNORE INTO clip_tags (clip_id, tag) VALUES (?1, ?2)",
+                    params![id, tag],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        Self::get_with(&conn, id)
+    }
+
+    pub fn thumbnail_key(&self, id: u32) -> anyhow::Result<Option<String>> {
+        let key = self
+            .conn()
+            .query_row("SELECT thumbnail_key FROM clips WHERE id = ?1", [id], |r| {
+                r.get(0)
+            })
+            .optional()?;
+
+        Ok(key.flatten())
+    }
+
+    pub fn set_thumbnail_key(&self, id: u32, key: &str) -> anyhow::Result<()> {
+        self.conn().execute(
+            "UPDATE clips SET thumbnail_key = ?1 WHERE id = ?2",
+            params![key, id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Whether any clip uses the thumbnails with the given key, clips with the same
+    /// content share them
+    pub fn thumbnail_key_used(&self, key: &str) -> anyhow::Result<bool> {
+        let used = self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM clips WHERE thumbnail_key = ?1 LIMIT 1",
+                [key],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+
+        Ok(used)
+    }
+
+    /// Ids of the clips that have no thumbnails yet
+    pub fn missing_thumbnails(&self) -> anyhow::Result<Vec<u32>> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT id FROM clips WHERE thumbnail_key IS NULL ORDER BY created_at DESC")?;
+        let ids = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ids)
+    }
+
+    /// Removes the clip from the library and deletes its file
+    pub fn delete(&self, id: u32) -> anyhow::Result<bool> {
+        let Some(clip) = self.get(id)? else {
+            return Ok(false);
+        };
+
+        match fs::remove_file(&clip.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Deleting clip file"),
+        }
+
+        self.conn()
+            .execute("DELETE FROM clips WHERE id = ?1", [id])?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(name: &str) -> ClipLibrary {
+        let dir =
+            std::env::temp_dir().join(format!("clipture-clips-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        ClipLibrary::with_connection(Connection::open_in_memory().unwrap(), dir).unwrap()
+    }
+
+    fn add(lib: &ClipLibrary, name: &str, size: usize, game_id: Option<&str>) -> Clip {
+        let path = lib.clips_dir.join(name);
+        fs::write(&path, vec![0u8; size]).unwrap();
+
+        lib.index(NewClip {
+            path,
+            kind: guess_kind(Path::new(name)),
+            game_id: game_id.map(|g| g.to_string()),
+            window_title: Some(format!("{} title", name)),
+            created_at: Some(format!("2024-10-0{}T00:00:00+00:00", size)),
+        })
+        .unwrap()
+    }
+
+    fn ids(page: &ClipPage) -> Vec<u32> {
+        page.clips.iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn list_filters_sorts_and_pages() -> anyhow::Result<()> {
+        let lib = library("list");
+        let a = add(&lib, "Replay a.mp4", 1, Some("cs2"));
+        let b = add(&lib, "Recording b.mp4", 2, Some("cs2"));
+        let c = add(&lib, "Replay c.mp4", 3, Some("dota2"));
+
+        assert_eq!(b.kind, ClipKind::Recording);
+        assert_eq!(c.size_bytes, 3);
+
+        let page = lib.list(&ClipFilter::default())?;
+        assert_eq!(page.total, 3);
+        assert_eq!(ids(&page), vec![c.id, b.id, a.id]);
+
+        let page = lib.list(&ClipFilter {
+            game_id: Some("cs2".to_string()),
+            sort: ClipSort::Oldest,
+            ..Default::default()
+        })?;
+        assert_eq!(ids(&page), vec![a.id, b.id]);
+
+        let page = lib.list(&ClipFilter {
+            kind: Some(ClipKind::Replay),
+            limit: 1,
+            offset: 1,
+            ..Default::default()
+        })?;
+        assert_eq!(page.total, 2);
+        assert_eq!(ids(&page), vec![a.id]);
+
+        let page = lib.list(&ClipFilter {
+            search: Some("c.mp4".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(ids(&page), vec![c.id]);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_updates() -> anyhow::Result<()> {
+        let lib = library("metadata");
+        let a = add(&lib, "Replay a.mp4", 1, None);
+        add(&lib, "Replay b.mp4", 2, None);
+
+        let updated = lib
+            .update_metadata(
+                a.id,
+                ClipMetadataUpdate {
+                    tags: Some(vec![
+                        "clutch".to_string(),
+                        " ".to_string(),
+                        "ace".to_string(),
+                    ]),
+                    favorite: Some(true),
+                    window_title: None,
+                },
+            )?
+            .unwrap();
+        assert_eq!(updated.tags, vec!["ace", "clutch"]);
+        assert!(updated.favorite);
+        assert_eq!(updated.window_title.as_deref(), Some("Replay a.mp4 title"));
+
+        let page = lib.list(&ClipFilter {
+            tag: Some("ace".to_string()),
+            favorite: Some(true),
+            ..Default::default()
+        })?;
+        assert_eq!(ids(&page), vec![a.id]);
+
+        assert!(lib
+            .update_metadata(999, ClipMetadataUpdate::default())?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn thumbnail_keys_reset_when_file_changes() -> anyhow::Result<()> {
+        let lib = library("thumbnails");
+        let a = add(&lib, "Replay a.mp4", 1, None);
+        let b = add(&lib, "Replay b.mp4", 2, None);
+        assert!(!a.has_thumbnails);
+
+        lib.set_thumbnail_key(a.id, "key-a")?;
+        lib.set_thumbnail_key(b.id, "key-b")?;
+        assert!(lib.get(a.id)?.unwrap().has_thumbnails);
+        assert_eq!(lib.thumbnail_key(a.id)?.as_deref(), Some("key-a"));
+        assert!(lib.missing_thumbnails()?.is_empty());
+
+        // Indexing the same file again keeps the thumbnails, a changed file doesn't
+        add(&lib, "Replay a.mp4", 1, None);
+        add(&lib, "Replay b.mp4", 3, None);
+        assert_eq!(lib.missing_thumbnails()?, vec![b.id]);
+        assert_eq!(lib.thumbnail_key(999)?, None);
+
+        // A copy of a shares its thumbnails, they stay until the last clip is gone
+        let copy = add(&lib, "Replay copy.mp4", 1, None);
+        lib.set_thumbnail_key(copy.id, "key-a")?;
+        lib.delete(a.id)?;
+        assert!(lib.thumbnail_key_used("key-a")?);
+        lib.delete(copy.id)?;
+        assert!(!lib.thumbnail_key_used("key-a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_and_delete() -> anyhow::Result<()> {
+        let lib = library("reconcile");
+        let a = add(&lib, "Replay a.mp4", 1, None);
+        let b = add(&lib, "Replay b.mp4", 2, None);
+
+        fs::remove_file(&b.path)?;
+        fs::write(lib.clips_dir.join("Recording new.mp4"), [0u8; 4])?;
+        fs::write(lib.clips_dir.join("notes.txt"), "not a video")?;
+
+        assert_eq!(lib.reconcile()?, (1, 1));
+        let page = lib.list(&ClipFilter::default())?;
+        assert_eq!(page.total, 2);
+        assert!(lib.get(b.id)?.is_none());
+
+        assert!(lib.delete(a.id)?);
+        assert!(!Path::new(&a.path).exists());
+        assert!(!lib.delete(a.id)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_probes_unfinished_clips_again() -> anyhow::Result<()> {
+        let lib = library("unfinished");
+        // Nothing that can be probed yet, like a recording OBS is still writing
+        let a = add(&lib, "Recording a.mp4", 1, Some("cs2"));
+        assert_eq!(a.duration_ms, 0);
+        lib.set_thumbnail_key(a.id, "partial")?;
+
+        fs::write(&a.path, [0u8; 8])?;
+        assert_eq!(lib.reconcile()?, (0, 0));
+
+        let a = lib.get(a.id)?.unwrap();
+        assert_eq!(a.size_bytes, 8);
+        assert_eq!(a.game_id.as_deref(), Some("cs2"));
+        assert!(!a.has_thumbnails);
+        Ok(())
+    }
+}
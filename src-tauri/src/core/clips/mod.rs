@@ -0,0 +1,203 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::sync::RwLock;
+
+mod edit;
+mod ffmpeg;
+mod library;
+mod probe;
+mod thumbnail;
+pub use edit::{start_merge, start_trim, subscribe_edits, MergeRequest, TrimRequest};
+pub use ffmpeg::ffmpeg_available;
+pub use library::ClipLibrary;
+pub use thumbnail::{
+    handle_thumbnail_request, queue_thumbnails, SpriteLayout, ThumbnailCache, ThumbnailWorker,
+    SPRITE_LAYOUT, THUMBNAILS_DIR, THUMBNAIL_PROTOCOL, THUMBNAIL_WORKER,
+};
+
+pub const CLIPS_DB_FILE: &str = "clips.db";
+/// Page size used when the frontend doesn't specify a limit
+const DEFAULT_PAGE_SIZE: u32 = 50;
+
+lazy_static! {
+    pub static ref CLIP_LIBRARY: Arc<RwLock<Option<ClipLibrary>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClipKind {
+    Replay,
+    Recording,
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Clip {
+    pub id: u32,
+    pub path: String,
+    pub kind: ClipKind,
+    pub game_id: Option<String>,
+    pub window_title: Option<String>,
+    /// RFC 3339 timestamp
+    pub created_at: String,
+    pub duration_ms: u32,
+    pub width: u32,
+    pub height: u32,
+    #[specta(type = f64)]
+    pub size_bytes: u64,
+    pub tags: Vec<String>,
+    pub favorite: bool,
+    /// Whether the poster and sprite sheet have been generated
+    pub has_thumbnails: bool,
+}
+
+/// A file that should be added to the library
+#[derive(Debug, Clone)]
+pub struct NewClip {
+    pub path: PathBuf,
+    pub kind: ClipKind,
+    pub game_id: Option<String>,
+    pub window_title: Option<String>,
+    /// Defaults to the modification time of the file
+    pub created_at: Option<String>,
+}
+
+#[derive(Type, Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ClipSort {
+    #[default]
+    Newest,
+    Oldest,
+    Longest,
+    Largest,
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClipFilter {
+    pub game_id: Option<String>,
+    pub kind: Option<ClipKind>,
+    pub favorite: Option<bool>,
+    pub tag: Option<String>,
+    /// Matched against the window title and the file path
+    pub search: Option<String>,
+    pub sort: ClipSort,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+impl Default for ClipFilter {
+    fn default() -> Self {
+        Self {
+            game_id: None,
+            kind: None,
+            favorite: None,
+            tag: None,
+            search: None,
+            sort: ClipSort::default(),
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClipPage {
+    pub clips: Vec<Clip>,
+    /// Number of clips matching the filter, ignoring offset and limit
+    pub total: u32,
+}
+
+/// Fields that are `None` are left unchanged
+#[derive(Type, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClipMetadataUpdate {
+    pub tags: Option<Vec<String>>,
+    pub favorite: Option<bool>,
+    pub window_title: Option<String>,
+}
+
+/// Runs `f` with the global library on the blocking thread pool, SQLite queries and
+/// probing files would otherwise stall the async runtime
+pub async fn with_library<T, F>(f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&ClipLibrary) -> anyhow::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let library = CLIP_LIBRARY.blocking_read();
+        let library = library.as_ref().context("Clip library not initialized")?;
+
+        f(library)
+    })
+    .await?
+}
+
+/// Adds a saved replay or recording to the global library, errors are only logged
+/// because the clip itself was saved fine
+pub async fn index_clip(clip: NewClip) {
+    let path = clip.path.clone();
+    match with_library(move |library| library.index(clip)).await {
+        Ok(clip) => queue_thumbnails(clip.id).await,
+        Err(e) => log::error!("Couldn't index clip {}: {:?}", path.display(), e),
+    }
+}
+
+/// Urls of the thumbnails of a clip for the webview
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClipThumbnails {
+    pub poster_url: String,
+    pub sprite_url: String,
+    pub sprite: SpriteLayout,
+}
+
+impl ClipThumbnails {
+    pub fn new(id: u32) -> Self {
+        // Windows serves custom protocols over http
+        let base = if cfg!(windows) {
+            format!("http://{}.localhost/", THUMBNAIL_PROTOCOL)
+        } else {
+            format!("{}://", THUMBNAIL_PROTOCOL)
+        };
+
+        Self {
+            poster_url: format!("{}{}/poster", base, id),
+            sprite_url: format!("{}{}/sprite", base, id),
+            sprite: SPRITE_LAYOUT,
+        }
+    }
+}
+
+/// Syncs the global library with the clips directory without blocking the runtime
+pub fn spawn_reconcile() {
+    tokio::task::spawn_blocking(|| {
+        let library = CLIP_LIBRARY.blocking_read();
+        let Some(library) = library.as_ref() else {
+            return;
+        };
+
+        match library.reconcile() {
+            Ok((added, removed)) => {
+                log::info!(
+                    "Reconciled clip library, {} added, {} removed",
+                    added,
+                    removed
+                )
+            }
+            Err(e) => log::error!("Couldn't reconcile clip library: {:?}", e),
+        }
+
+        let missing = match library.missing_thumbnails() {
+            Ok(missing) => missing,
+            Err(e) => {
+                log::error!("Couldn't list clips without thumbnails: {:?}", e);
+                return;
+            }
+        };
+
+        if let Some(worker) = THUMBNAIL_WORKER.blocking_read().as_ref() {
+            missing.into_iter().for_each(|id| worker.queue(id));
+        }
+    });
+}
@@ -0,0 +1,230 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use super::ffmpeg::probe_video_blocking;
+
+/// What we can tell about a video without decoding it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct VideoInfo {
+    pub duration_ms: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+struct BoxHeader {
+    kind: [u8; 4],
+    /// Size of the box content without the header
+    size: u64,
+}
+
+fn read_header<R: Read>(r: &mut R, remaining: u64) -> anyhow::Result<Option<BoxHeader>> {
+    if remaining < 8 {
+        return Ok(None);
+    }
+
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    let size = u32::from_be_bytes(buf[..4].try_into()?) as u64;
+    let kind = buf[4..].try_into()?;
+
+    let (size, header_len) = match size {
+        // The box extends to the end of the file
+        0 => (remaining, 8),
+        1 => {
+            let mut large = [0u8; 8];
+            r.read_exact(&mut large)?;
+            (u64::from_be_bytes(large), 16)
+        }
+        size => (size, 8),
+    };
+
+    if size < header_len || size > remaining {
+        bail!("Invalid box size {}", size);
+    }
+
+    Ok(Some(BoxHeader {
+        kind,
+        size: size - header_len,
+    }))
+}
+
+/// Returns the content of the first child box of the given kind
+fn find_box(data: &[u8], kind: &[u8; 4]) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(children(data)?
+        .into_iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, d)| d))
+}
+
+fn children(data: &[u8]) -> anyhow::Result<Vec<([u8; 4], Vec<u8>)>> {
+    let mut cursor = std::io::Cursor::new(data);
+    let mut boxes = vec![];
+
+    loop {
+        let remaining = data.len() as u64 - cursor.position();
+        let Some(header) = read_header(&mut cursor, remaining)? else {
+            break;
+        };
+
+        let start = cursor.position() as usize;
+        let end = start + header.size as usize;
+        boxes.push((header.kind, data[start..end].to_vec()));
+        cursor.set_position(end as u64);
+    }
+
+    Ok(boxes)
+}
+
+fn be_u32(data: &[u8], at: usize) -> anyhow::Result<u32> {
+    let bytes = data.get(at..at + 4).context("Box too short")?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> anyhow::Result<u64> {
+    let bytes = data.get(at..at + 8).context("Box too short")?;
+    Ok(u64::from_be_bytes(bytes.try_into()?))
+}
+
+fn parse_mvhd(mvhd: &[u8]) -> anyhow::Result<u32> {
+    let version = *mvhd.first().context("Empty mvhd box")?;
+    let (timescale, duration) = if version == 1 {
+        (be_u32(mvhd, 20)?, be_u64(mvhd, 24)?)
+    } else {
+        (be_u32(mvhd, 12)?, be_u32(mvhd, 16)? as u64)
+    };
+
+    if timescale == 0 {
+        bail!("Invalid timescale");
+    }
+
+    Ok((duration * 1000 / timescale as u64).min(u32::MAX as u64) as u32)
+}
+
+/// Width and height are the last two 16.16 fixed point values of the box
+fn parse_tkhd(tkhd: &[u8]) -> anyhow::Result<(u32, u32)> {
+    if tkhd.len() < 8 {
+        bail!("tkhd box too short");
+    }
+
+    let at = tkhd.len() - 8;
+    Ok((be_u32(tkhd, at)? >> 16, be_u32(tkhd, at + 4)? >> 16))
+}
+
+/// Reads the duration and the resolution of the largest track from an mp4 file
+pub fn probe_mp4(path: &Path) -> anyhow::Result<VideoInfo> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    // OBS writes the moov box at the end, so skip over everything else
+    let moov = loop {
+        let pos = file.stream_position()?;
+        let header = read_header(&mut file, len - pos)?.context("No moov box found")?;
+        if &header.kind == b"moov" {
+            let mut moov = vec![0; header.size as usize];
+            file.read_exact(&mut moov)?;
+            break moov;
+        }
+
+        file.seek(SeekFrom::Current(header.size as i64))?;
+    };
+
+    let mvhd = find_box(&moov, b"mvhd")?.context("No mvhd box found")?;
+    let duration_ms = parse_mvhd(&mvhd)?;
+
+    let (width, height) = children(&moov)?
+        .into_iter()
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| find_box(&trak, b"tkhd").ok().flatten())
+        .filter_map(|tkhd| parse_tkhd(&tkhd).ok())
+        .max_by_key(|(w, h)| w * h)
+        .unwrap_or_default();
+
+    Ok(VideoInfo {
+        duration_ms,
+        width,
+        height,
+    })
+}
+
+/// Parses mp4 files directly and leaves other containers, like mkv, to ffprobe
+pub fn probe_video(path: &Path) -> anyhow::Result<VideoInfo> {
+    let is_mp4 = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("mp4") || e.eq_ignore_ascii_case("mov"));
+
+    if is_mp4 {
+        probe_mp4(path)
+    } else {
+        probe_video_blocking(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut b = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(content);
+        b
+    }
+
+    fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut content = vec![0u8; 12];
+        content.extend_from_slice(&timescale.to_be_bytes());
+        content.extend_from_slice(&duration.to_be_bytes());
+        content.extend_from_slice(&[0u8; 80]);
+        mp4_box(b"mvhd", &content)
+    }
+
+    fn trak(width: u32, height: u32) -> Vec<u8> {
+        let mut content = vec![0u8; 76];
+        content.extend_from_slice(&(width << 16).to_be_bytes());
+        content.extend_from_slice(&(height << 16).to_be_bytes());
+        mp4_box(b"trak", &mp4_box(b"tkhd", &content))
+    }
+
+    #[test]
+    fn reads_duration_and_resolution() -> anyhow::Result<()> {
+        let mut moov = mvhd(1000, 30_500);
+        moov.extend(trak(0, 0));
+        moov.extend(trak(1920, 1080));
+
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend(mp4_box(b"mdat", &[0u8; 64]));
+        file.extend(mp4_box(b"moov", &moov));
+
+        let path = std::env::temp_dir().join(format!("clipture-probe-{}.mp4", std::process::id()));
+        std::fs::write(&path, file)?;
+        let info = probe_mp4(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            info?,
+            VideoInfo {
+                duration_ms: 30_500,
+                width: 1920,
+                height: 1080
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_files_fail() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("clipture-probe-bad-{}.mp4", std::process::id()));
+        std::fs::write(&path, mp4_box(b"ftyp", b"isom"))?;
+        let info = probe_mp4(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(info.is_err());
+        Ok(())
+    }
+}
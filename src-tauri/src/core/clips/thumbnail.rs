@@ -0,0 +1,373 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use specta::Type;
+use tauri::http::{header, Response, StatusCode, Uri};
+use tokio::{
+    fs,
+    sync::{mpsc, RwLock},
+};
+
+use super::{ffmpeg::run_ffmpeg, with_library};
+
+pub const THUMBNAILS_DIR: &str = "thumbnails";
+pub const THUMBNAIL_PROTOCOL: &str = "clipture-thumb";
+
+const POSTER_FILE: &str = "poster.jpg";
+const SPRITE_FILE: &str = "sprite.jpg";
+const POSTER_WIDTH: u32 = 480;
+
+/// Only the start and the end of a clip are hashed, hashing gigabytes of video for every
+/// clip would take far too long. Together with the size this is unique enough.
+const HASH_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Layout of the storyboard sprite sheet, frames are spread evenly over the clip
+#[derive(Type, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpriteLayout {
+    pub columns: u32,
+    pub rows: u32,
+    pub frame_width: u32,
+}
+
+pub const SPRITE_LAYOUT: SpriteLayout = SpriteLayout {
+    columns: 5,
+    rows: 4,
+    frame_width: 160,
+};
+
+lazy_static! {
+    pub static ref THUMBNAIL_WORKER: Arc<RwLock<Option<ThumbnailWorker>>> =
+        Arc::new(RwLock::new(None));
+}
+
+/// Cache key of a clip, derived from its size and the hash of its first and last megabyte
+pub fn content_key(path: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut hasher = Sha256::new();
+    hasher.update(len.to_le_bytes());
+
+    let mut buf = vec![];
+    (&mut file).take(HASH_CHUNK_SIZE).read_to_end(&mut buf)?;
+    hasher.update(&buf);
+
+    if len > HASH_CHUNK_SIZE * 2 {
+        buf.clear();
+        file.seek(SeekFrom::End(-(HASH_CHUNK_SIZE as i64)))?;
+        file.read_to_end(&mut buf)?;
+        hasher.update(&buf);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Frame rate that spreads the sprite frames evenly over the clip
+fn sprite_filter(duration_ms: u32) -> String {
+    let SpriteLayout {
+        columns,
+        rows,
+        frame_width,
+    } = SPRITE_LAYOUT;
+
+    let frames = columns * rows;
+    let fps = if duration_ms == 0 {
+        1.0
+    } else {
+        frames as f64 * 1000.0 / duration_ms as f64
+    };
+
+    format!(
+        "fps={:.6},scale={}:-2,tile={}x{}",
+        fps, frame_width, columns, rows
+    )
+}
+
+/// Poster frames and sprite sheets in the app data dir, one directory per content key
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    fn is_complete(&self, key: &str) -> bool {
+        let entry = self.entry(key);
+        entry.join(POSTER_FILE).exists() && entry.join(SPRITE_FILE).exists()
+    }
+
+    /// Extracts the poster and the sprite sheet unless a clip with the same content
+    /// already has them
+    pub async fn generate(&self, video: &Path, duration_ms: u32, key: &str) -> anyhow::Result<()> {
+        if self.is_complete(key) {
+            return Ok(());
+        }
+
+        let entry = self.entry(key);
+        fs::create_dir_all(&entry).await?;
+
+        let video = video.to_string_lossy();
+        // Skip the first moments, they are usually a loading screen or black
+        let poster_at = format!("{:.3}", duration_ms as f64 / 1000.0 * 0.1);
+        let poster = entry.join(POSTER_FILE);
+        let poster_scale = format!("scale={}:-2", POSTER_WIDTH);
+        run_ffmpeg(&[
+            "-ss",
+            &poster_at,
+            "-i",
+            &video,
+            "-frames:v",
+            "1",
+            "-vf",
+            &poster_scale,
+            &poster.to_string_lossy(),
+        ])
+        .await
+        .context("Extracting poster frame")?;
+
+        let sprite = entry.join(SPRITE_FILE);
+        run_ffmpeg(&[
+            "-i",
+            &video,
+            "-an",
+            "-frames:v",
+            "1",
+            "-vf",
+            &sprite_filter(duration_ms),
+            &sprite.to_string_lossy(),
+        ])
+        .await
+        .context("Extracting sprite sheet")?;
+
+        Ok(())
+    }
+
+    pub async fn read(&self, key: &str, file: ThumbnailFile) -> anyhow::Result<Vec<u8>> {
+        let name = match file {
+            ThumbnailFile::Poster => POSTER_FILE,
+            ThumbnailFile::Sprite => SPRITE_FILE,
+        };
+
+        Ok(fs::read(self.entry(key).join(name)).await?)
+    }
+
+    pub async fn remove(&self, key: &str) -> anyhow::Result<()> {
+        match fs::remove_dir_all(self.entry(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbnailFile {
+    Poster,
+    Sprite,
+}
+
+/// Generates thumbnails for queued clips one at a time in the background
+pub struct ThumbnailWorker {
+    cache: Arc<ThumbnailCache>,
+    tx: mpsc::UnboundedSender<u32>,
+}
+
+impl ThumbnailWorker {
+    pub fn spawn(cache: ThumbnailCache) -> Self {
+        let cache = Arc::new(cache);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let worker_cache = cache.clone();
+        tokio::spawn(async move {
+            while let Some(id) = rx.recv().await {
+                if let Err(e) = Self::process(&worker_cache, id).await {
+                    log::warn!("Couldn't generate thumbnails for clip {}: {:?}", id, e);
+                }
+            }
+        });
+
+        Self { cache, tx }
+    }
+
+    pub fn cache(&self) -> &ThumbnailCache {
+        &self.cache
+    }
+
+    pub fn queue(&self, id: u32) {
+        // The worker only stops when this sender is dropped
+        let _ = self.tx.send(id);
+    }
+
+    async fn process(cache: &ThumbnailCache, id: u32) -> anyhow::Result<()> {
+        let clip = with_library(move |library| {
+            if library.thumbnail_key(id)?.is_some() {
+                return Ok(None);
+            }
+
+            library.get(id)
+        })
+        .await?;
+
+        let Some(clip) = clip else {
+            return Ok(());
+        };
+
+        let path = PathBuf::from(&clip.path);
+        let key_path = path.clone();
+        let key = tokio::task::spawn_blocking(move || content_key(&key_path)).await??;
+        cache.generate(&path, clip.duration_ms, &key).await?;
+
+        with_library(move |library| library.set_thumbnail_key(id, &key)).await?;
+
+        log::debug!("Generated thumbnails for clip {}", id);
+        Ok(())
+    }
+}
+
+/// Queues thumbnail generation for the clip if the worker is running
+pub async fn queue_thumbnails(id: u32) {
+    if let Some(worker) = THUMBNAIL_WORKER.read().await.as_ref() {
+        worker.queue(id);
+    }
+}
+
+/// Parses `<clip id>[/poster|/sprite]` from a protocol url. Windows serves custom
+/// protocols as `http://<protocol>.localhost/...`, so the id is either the host or
+/// the first path segment.
+fn parse_thumbnail_uri(uri: &Uri) -> Option<(u32, ThumbnailFile)> {
+    let host = uri
+        .host()
+        .filter(|h| !h.ends_with(".localhost") && *h != THUMBNAIL_PROTOCOL);
+
+    let mut segments = host
+        .into_iter()
+        .chain(uri.path().split('/'))
+        .filter(|s| !s.is_empty());
+
+    let id = segments.next()?.parse().ok()?;
+    let file = match segments.next() {
+        None | Some("poster") => ThumbnailFile::Poster,
+        Some("sprite") => ThumbnailFile::Sprite,
+        Some(_) => return None,
+    };
+
+    if segments.next().is_some() {
+        return None;
+    }
+
+    Some((id, file))
+}
+
+fn status_response(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .body(vec![])
+        .expect("Building empty response")
+}
+
+/// Serves `clipture-thumb://<clip id>/poster` and `clipture-thumb://<clip id>/sprite`
+pub async fn handle_thumbnail_request(uri: &Uri) -> Response<Vec<u8>> {
+    let Some((id, file)) = parse_thumbnail_uri(uri) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+
+    let key = match with_library(move |library| library.thumbnail_key(id)).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return status_response(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::error!("Couldn't look up thumbnails of clip {}: {:?}", id, e);
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let data = match THUMBNAIL_WORKER.read().await.as_ref() {
+        Some(worker) => worker.cache().read(&key, file).await,
+        None => return status_response(StatusCode::SERVICE_UNAVAILABLE),
+    };
+
+    match data {
+        Ok(data) => Response::builder()
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            // Keys change with the content, so the images never go stale
+            .header(header::CACHE_CONTROL, "max-age=31536000, immutable")
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .body(data)
+            .expect("Building thumbnail response"),
+        Err(_) => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(uri: &str) -> Option<(u32, ThumbnailFile)> {
+        parse_thumbnail_uri(&uri.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_protocol_urls() {
+        assert_eq!(
+            parse("clipture-thumb://12"),
+            Some((12, ThumbnailFile::Poster))
+        );
+        assert_eq!(
+            parse("clipture-thumb://12/sprite"),
+            Some((12, ThumbnailFile::Sprite))
+        );
+        assert_eq!(
+            parse("http://clipture-thumb.localhost/12/poster"),
+            Some((12, ThumbnailFile::Poster))
+        );
+        assert_eq!(parse("clipture-thumb://12/other"), None);
+        assert_eq!(parse("clipture-thumb://abc"), None);
+        assert_eq!(parse("clipture-thumb://12/sprite/1"), None);
+    }
+
+    #[test]
+    fn sprite_frames_cover_the_clip() {
+        // 20 frames over 40 seconds
+        assert_eq!(sprite_filter(40_000), "fps=0.500000,scale=160:-2,tile=5x4");
+        assert_eq!(sprite_filter(0), "fps=1.000000,scale=160:-2,tile=5x4");
+    }
+
+    #[test]
+    fn content_keys() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("clipture-thumb-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let big = vec![7u8; (HASH_CHUNK_SIZE * 3) as usize];
+        let mut changed_end = big.clone();
+        *changed_end.last_mut().unwrap() = 8;
+
+        let write = |name: &str, data: &[u8]| -> anyhow::Result<String> {
+            let path = dir.join(name);
+            std::fs::write(&path, data)?;
+            content_key(&path)
+        };
+
+        let a = write("a.mp4", &big)?;
+        let copy = write("copy.mp4", &big)?;
+        let end = write("end.mp4", &changed_end)?;
+        let small = write("small.mp4", b"small")?;
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(a, copy);
+        assert_ne!(a, end);
+        assert_ne!(a, small);
+        Ok(())
+    }
+}
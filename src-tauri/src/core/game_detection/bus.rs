@@ -0,0 +1,216 @@
+use std::{collections::VecDeque, future::Future, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use uuid::Uuid;
+
+use super::GameEvent;
+
+/// How many events a subscriber can fall behind before it starts missing events
+pub const BUS_CAPACITY: usize = 256;
+/// How long a listener may take to handle a single event before it is cancelled
+pub const LISTENER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub enum GameEventMessage {
+    Event(GameEvent),
+    /// The subscriber was too slow and missed this many events
+    Lagged(u32),
+}
+
+/// Delivers game events to every subscriber, each with its own bounded queue
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<GameEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+
+    pub fn publish(&self, event: GameEvent) {
+        // No one listening is fine
+        let _ = self.tx.send(event);
+    }
+
+    /// `replay` is handed out before any published event, it should describe the current
+    /// state so late subscribers don't have to wait for the next change
+    pub fn subscribe(&self, replay: Vec<GameEvent>) -> EventSubscription {
+        EventSubscription {
+            replay: replay.into(),
+            rx: self.tx.subscribe(),
+        }
+    }
+}
+
+pub struct EventSubscription {
+    replay: VecDeque<GameEvent>,
+    rx: broadcast::Receiver<GameEvent>,
+}
+
+impl EventSubscription {
+    /// Returns `None` once the bus has been dropped
+    pub async fn recv(&mut self) -> Option<GameEventMessage> {
+        if let Some(event) = self.replay.pop_front() {
+            return Some(GameEventMessage::Event(event));
+        }
+
+        match self.rx.recv().await {
+            Ok(event) => Some(GameEventMessage::Event(event)),
+            Err(RecvError::Lagged(n)) => Some(GameEventMessage::Lagged(
+                u32::try_from(n).unwrap_or(u32::MAX),
+            )),
+            Err(RecvError::Closed) => None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Option<GameEventMessage> {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        if let Some(event) = self.replay.pop_front() {
+            return Some(GameEventMessage::Event(event));
+        }
+
+        match self.rx.try_recv() {
+            Ok(event) => Some(GameEventMessage::Event(event)),
+            Err(TryRecvError::Lagged(n)) => Some(GameEventMessage::Lagged(n as u32)),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Runs the listener for every event of the subscription on its own task, so a slow
+/// listener only delays itself. The listener stops when the guard is dropped.
+pub(super) fn spawn_listener<F, T>(
+    key: Uuid,
+    mut subscription: EventSubscription,
+    listener: T,
+    timeout: Duration,
+) -> DropGuard
+where
+    F: Future<Output = ()> + Send + 'static,
+    T: (Fn(GameEvent) -> F) + Send + Sync + 'static,
+{
+    let token = CancellationToken::new();
+    let cancelled = token.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let msg = select! {
+                _ = cancelled.cancelled() => break,
+                msg = subscription.recv() => msg,
+            };
+
+            match msg {
+                Some(GameEventMessage::Event(event)) => {
+                    if time::timeout(timeout, listener(event)).await.is_err() {
+                        log::warn!("Listener {} timed out handling an event", key);
+                    }
+                }
+                Some(GameEventMessage::Lagged(n)) => {
+                    log::warn!("Listener {} lagged behind and missed {} events", key, n)
+                }
+                None => break,
+            }
+        }
+    });
+
+    token.drop_guard()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn closed(pid: u32) -> GameEvent {
+        GameEvent::Closed(
+            serde_json::from_value(json!({
+                "full_exe": "C:/game.exe",
+                "obs_id": "Title:Class:game.exe",
+                "pid": pid,
+                "is_game": false,
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn pid(msg: Option<GameEventMessage>) -> Option<u32> {
+        match msg {
+            Some(GameEventMessage::Event(GameEvent::Closed(w))) => Some(w.pid),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replays_before_live_events() {
+        let bus = EventBus::new(4);
+        let mut sub = bus.subscribe(vec![closed(1)]);
+        bus.publish(closed(2));
+
+        assert_eq!(pid(sub.try_recv()), Some(1));
+        assert_eq!(pid(sub.try_recv()), Some(2));
+        assert!(sub.try_recv().is_none());
+    }
+
+    #[test]
+    fn reports_lag() {
+        let bus = EventBus::new(2);
+        let mut sub = bus.subscribe(vec![]);
+        for i in 0..5 {
+            bus.publish(closed(i));
+        }
+
+        assert!(matches!(sub.try_recv(), Some(GameEventMessage::Lagged(3))));
+        assert_eq!(pid(sub.try_recv()), Some(3));
+        assert_eq!(pid(sub.try_recv()), Some(4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_listeners_time_out() {
+        let bus = EventBus::new(4);
+        let handled = Arc::new(Mutex::new(vec![]));
+
+        let h = handled.clone();
+        let _guard = spawn_listener(
+            Uuid::new_v4(),
+            bus.subscribe(vec![]),
+            move |event| {
+                let h = h.clone();
+                async move {
+                    let GameEvent::Closed(w) = event else { return };
+                    if w.pid == 1 {
+                        // Never finishes in time
+                        time::sleep(Duration::from_secs(60)).await;
+                    }
+                    h.lock().unwrap().push(w.pid);
+                }
+            },
+            Duration::from_millis(50),
+        );
+
+        bus.publish(closed(1));
+        bus.publish(closed(2));
+
+        // The clock is paused, sleeping only returns once the listener is waiting on a timer
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(handled.lock().unwrap().is_empty());
+
+        time::advance(Duration::from_millis(100)).await;
+        time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(*handled.lock().unwrap(), vec![2]);
+    }
+}
@@ -1,17 +1,21 @@
-use std::future::Future;
+use std::{future::Future, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use libobs_window_helper::{get_all_windows, WindowInfo, WindowSearchMode};
-use tokio::{pin, select, task::JoinHandle};
+use libobs_window_helper::WindowInfo;
+use tokio::{select, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::{
-    core::game_detection::{GameEvent, WindowType},
-    json_typings::clipture_api::game::detection,
+use crate::core::game_detection::{GameEvent, WindowType};
+
+use super::{
+    bus::{self, EventBus},
+    matcher::GameMatcher,
+    GameDetection, GameDetectionTypeRw, ListenerRef, WindowSource, WindowTrackerRw,
 };
 
-use super::{GameDetection, GameDetectionTypeRw, ListenerRef, ListenersTypeRw};
+/// How often the windows are enumerated
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[async_trait]
 pub trait GameEventNotifier {
@@ -22,11 +26,21 @@ pub trait GameEventNotifier {
         &self,
         listener: T,
     ) -> ListenerRef;
-    fn get_window_type(info: &WindowInfo, data: &detection::Root) -> WindowType;
+    fn get_window_type(info: &WindowInfo, matcher: &GameMatcher) -> WindowType;
+
+    /// Enumerates the windows once and publishes every change on the bus
+    async fn poll_windows(
+        source: &dyn WindowSource,
+        tracker: &WindowTrackerRw,
+        bus: &EventBus,
+        detection: &GameDetectionTypeRw,
+    ) -> anyhow::Result<()>;
 
     async fn spawn_event_thread(
         token: CancellationToken,
-        listeners: ListenersTypeRw,
+        source: Arc<dyn WindowSource>,
+        tracker: WindowTrackerRw,
+        bus: EventBus,
         detection: GameDetectionTypeRw,
     ) -> JoinHandle<()>;
 }
@@ -40,88 +54,305 @@ impl GameEventNotifier for GameDetection {
         &self,
         listener: T,
     ) -> ListenerRef {
-        let token = Uuid::new_v4();
-        self.listeners.write().await.insert(token.clone(), Box::new(move |e| Box::new(Box::pin(listener(e)))));
+        let key = Uuid::new_v4();
+        let subscription = self.subscribe().await;
+        let guard = bus::spawn_listener(key, subscription, listener, bus::LISTENER_TIMEOUT);
+        self.listeners.write().await.insert(key, guard);
 
         ListenerRef {
-            key: token.clone(),
+            key,
             map: self.listeners.clone(),
         }
     }
 
+    async fn poll_windows(
+        source: &dyn WindowSource,
+        tracker: &WindowTrackerRw,
+        bus: &EventBus,
+        detection: &GameDetectionTypeRw,
+    ) -> anyhow::Result<()> {
+        let windows = source.windows()?;
+        let focused = source.focused();
+
+        let detection = detection.read().await;
+        let mut tracker = tracker.write().await;
+        let events = tracker.update(windows, focused, |w| {
+            Self::get_window_type(w, detection.matcher())
+        });
+        drop(detection);
+
+        // Publishing never waits on subscribers. The tracker stays locked so subscribing
+        // can't happen between the update and publishing its events.
+        for event in events {
+            bus.publish(event);
+        }
+
+        Ok(())
+    }
+
     async fn spawn_event_thread(
         token: CancellationToken,
-        listeners: ListenersTypeRw,
+        source: Arc<dyn WindowSource>,
+        tracker: WindowTrackerRw,
+        bus: EventBus,
         detection: GameDetectionTypeRw,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let mut prev = vec![];
             loop {
-                let windows = match get_all_windows(WindowSearchMode::IncludeMinimized) {
-                    Ok(games) => games,
-                    Err(e) => {
-                        eprintln!("Error getting windows: {:?}", e);
-                        continue;
-                    }
-                };
-
-                let detection = detection.read().await;
-                let listeners = listeners.read().await;
-
-                for window in windows.iter() {
-                    if !prev.iter().any(|x: &WindowInfo| x.pid == window.pid) {
-                        let window_type = Self::get_window_type(&window, &detection);
-
-                        for listener in listeners.values() {
-                            let r =
-                                listener(GameEvent::Opened(window_type.clone(), window.clone()));
-                            pin!(r);
-
-                            r.await
-                        }
-                    }
-
-                    if !windows.iter().any(|x: &WindowInfo| x.pid == window.pid) {
-                        for listener in listeners.values() {
-                            let r = listener(GameEvent::Closed(window.clone()));
-                            pin!(r);
-
-                            r.await
-                        }
-                    }
+                let r = Self::poll_windows(source.as_ref(), &tracker, &bus, &detection).await;
+                if let Err(e) = r {
+                    log::error!("Error getting windows: {:?}", e);
                 }
 
-                prev = windows;
                 select! {
                     _ = token.cancelled() => break,
-                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
                 }
             }
         })
     }
 
-    fn get_window_type(window: &WindowInfo, data: &detection::Root) -> WindowType {
-        for game in data.iter() {
-            for exe in game.executables.iter() {
-                let full_exe = window.full_exe.to_lowercase().replace("\\", "/");
-                if !full_exe.ends_with(&exe.name.to_lowercase()) {
-                    continue;
-                }
-                if let Some(args) = exe.arguments.as_ref() {
-                    // Maybe if cmdline is not present, we should not check for it?
-                    if !window
-                        .cmd_line
-                        .as_ref()
-                        .is_some_and(|e| e.to_lowercase().contains(&args.to_lowercase()))
-                    {
-                        continue;
-                    }
-                }
+    fn get_window_type(window: &WindowInfo, matcher: &GameMatcher) -> WindowType {
+        matcher.find(window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::{
+        core::game_detection::{
+            matcher::CURRENT_OS, source::ScriptedWindowSource, DetectionData, EventSubscription,
+            GameEventMessage,
+        },
+        json_typings::clipture_api::game::detection,
+    };
+
+    fn window(pid: u32, exe: &str, title: &str) -> WindowInfo {
+        serde_json::from_value(json!({
+            "full_exe": format!("C:\\Games\\{}", exe),
+            "obs_id": format!("{}:Main:{}", title, exe),
+            "pid": pid,
+            "title": title,
+            "class": "Main",
+            "is_game": false,
+        }))
+        .unwrap()
+    }
+
+    fn game(id: &str, exe: &str, hook: bool) -> detection::Root2 {
+        detection::Root2 {
+            id: id.to_string(),
+            name: id.to_string(),
+            hook,
+            executables: vec![detection::Executable {
+                name: exe.to_string(),
+                os: CURRENT_OS.to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    struct Harness {
+        source: ScriptedWindowSource,
+        tracker: WindowTrackerRw,
+        bus: EventBus,
+        detection: GameDetectionTypeRw,
+        subscription: EventSubscription,
+    }
+
+    impl Harness {
+        async fn new(detection: detection::Root) -> Self {
+            let bus = EventBus::new(bus::BUS_CAPACITY);
+
+            Self {
+                source: ScriptedWindowSource::new(),
+                tracker: Default::default(),
+                subscription: bus.subscribe(vec![]),
+                bus,
+                detection: Arc::new(RwLock::new(DetectionData::new(detection, vec![]))),
+            }
+        }
+
+        /// Runs one poll and returns the events it produced
+        async fn poll(&mut self) -> anyhow::Result<Vec<String>> {
+            GameDetection::poll_windows(&self.source, &self.tracker, &self.bus, &self.detection)
+                .await?;
+
+            Ok(drain(&mut self.subscription))
+        }
+
+        /// Subscribes like `GameDetection::subscribe` does
+        async fn late_subscriber(&self) -> EventSubscription {
+            let tracker = self.tracker.read().await;
+            self.bus.subscribe(tracker.replay_events())
+        }
+    }
 
-                return WindowType::Game;
+    fn drain(subscription: &mut EventSubscription) -> Vec<String> {
+        let mut events = vec![];
+        while let Some(msg) = subscription.try_recv() {
+            match msg {
+                GameEventMessage::Event(e) => events.push(describe(&e)),
+                GameEventMessage::Lagged(n) => events.push(format!("lagged {}", n)),
             }
         }
 
-        return WindowType::Window;
+        events
+    }
+
+    fn describe(event: &GameEvent) -> String {
+        let title = |w: &WindowInfo| w.title.clone().unwrap_or_default();
+        match event {
+            GameEvent::Opened(WindowType::Game(game), w) => {
+                format!("game {} {} hook={}", game.id, title(w), game.hook)
+            }
+            GameEvent::Opened(WindowType::Launcher(game), w) => {
+                format!("launcher {} {}", game.id, title(w))
+            }
+            GameEvent::Opened(WindowType::Window, w) => format!("window {}", title(w)),
+            GameEvent::Closed(w) => format!("closed {}", title(w)),
+            GameEvent::Focused(w) => format!("focused {}", title(w)),
+            GameEvent::Unfocused(w) => format!("unfocused {}", title(w)),
+            GameEvent::TitleChanged(w) => format!("title {}", title(w)),
+        }
+    }
+
+    #[tokio::test]
+    async fn open_and_close() -> anyhow::Result<()> {
+        let mut h = Harness::new(vec![game("cs2", "cs2.exe", true)]).await;
+        let cs = window(1, "cs2.exe", "Counter-Strike 2");
+        let editor = window(2, "notepad.exe", "Notes");
+
+        h.source
+            .push(vec![editor.clone()], Some(&editor))
+            .push(vec![editor.clone(), cs.clone()], Some(&cs))
+            .push(vec![editor.clone()], Some(&editor));
+
+        assert_eq!(h.poll().await?, vec!["window Notes", "focused Notes"]);
+        assert_eq!(
+            h.poll().await?,
+            vec![
+                "unfocused Notes",
+                "game cs2 Counter-Strike 2 hook=true",
+                "focused Counter-Strike 2"
+            ]
+        );
+
+        let running = h.tracker.read().await.running_games();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].game.id, "cs2");
+        assert!(running[0].focused);
+
+        assert_eq!(
+            h.poll().await?,
+            vec![
+                "unfocused Counter-Strike 2",
+                "closed Counter-Strike 2",
+                "focused Notes"
+            ]
+        );
+
+        assert!(h.tracker.read().await.running_games().is_empty());
+
+        // Nothing changes once the script has run out
+        assert!(h.poll().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopened_game_is_matched_again() -> anyhow::Result<()> {
+        let mut h = Harness::new(vec![game("cs2", "cs2.exe", false)]).await;
+        let first = window(1, "cs2.exe", "Counter-Strike 2");
+        let second = window(7, "cs2.exe", "Counter-Strike 2");
+
+        h.source
+            .push(vec![first], None)
+            .push(vec![], None)
+            .push(vec![second], None);
+
+        assert_eq!(
+            h.poll().await?,
+            vec!["game cs2 Counter-Strike 2 hook=false"]
+        );
+        assert_eq!(h.poll().await?, vec!["closed Counter-Strike 2"]);
+        assert_eq!(
+            h.poll().await?,
+            vec!["game cs2 Counter-Strike 2 hook=false"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_keep_tracked_windows() -> anyhow::Result<()> {
+        let mut h = Harness::new(vec![]).await;
+        let editor = window(2, "notepad.exe", "Notes");
+
+        h.source
+            .push(vec![editor.clone()], None)
+            .push_error("enumeration failed")
+            .push(vec![editor], None);
+
+        assert_eq!(h.poll().await?, vec!["window Notes"]);
+        assert!(h.poll().await.is_err());
+        // A failed poll must not be treated as every window closing
+        assert!(h.poll().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detection_refresh_mid_run() -> anyhow::Result<()> {
+        let mut h = Harness::new(vec![]).await;
+        let first = window(1, "valorant.exe", "VALORANT");
+        let second = window(2, "valorant.exe", "VALORANT");
+
+        h.source
+            .push(vec![first.clone()], None)
+            .push(vec![first.clone()], None)
+            .push(vec![], None)
+            .push(vec![second], None);
+
+        assert_eq!(h.poll().await?, vec!["window VALORANT"]);
+
+        h.detection
+            .write()
+            .await
+            .set_remote(vec![game("valorant", "valorant.exe", true)]);
+
+        // Windows that are already open keep their type
+        assert!(h.poll().await?.is_empty());
+        assert_eq!(h.poll().await?, vec!["closed VALORANT"]);
+        // New windows are matched against the refreshed data
+        assert_eq!(h.poll().await?, vec!["game valorant VALORANT hook=true"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn late_subscribers_get_current_state() -> anyhow::Result<()> {
+        let mut h = Harness::new(vec![game("cs2", "cs2.exe", true)]).await;
+        let cs = window(1, "cs2.exe", "Counter-Strike 2");
+        let editor = window(2, "notepad.exe", "Notes");
+
+        h.source
+            .push(vec![cs.clone()], Some(&cs))
+            .push(vec![cs.clone(), editor.clone()], Some(&cs));
+
+        h.poll().await?;
+        let mut late = h.late_subscriber().await;
+        h.poll().await?;
+
+        assert_eq!(
+            drain(&mut late),
+            vec![
+                "game cs2 Counter-Strike 2 hook=true",
+                "focused Counter-Strike 2",
+                "window Notes"
+            ]
+        );
+        Ok(())
     }
 }
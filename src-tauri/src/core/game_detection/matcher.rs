@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use libobs_window_helper::WindowInfo;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::json_typings::clipture_api::game::detection;
+
+use super::WindowType;
+
+/// The value of `Executable.os` for the platform we are running on
+#[cfg(windows)]
+pub const CURRENT_OS: &str = "win32";
+#[cfg(target_os = "macos")]
+pub const CURRENT_OS: &str = "darwin";
+#[cfg(all(not(windows), not(target_os = "macos")))]
+pub const CURRENT_OS: &str = "linux";
+
+/// The game a window was matched to
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetectedGame {
+    pub id: String,
+    pub name: String,
+    /// Other names the game is known by
+    pub aliases: Vec<String>,
+    /// True if the game supports game capture
+    pub hook: bool,
+}
+
+impl DetectedGame {
+    fn new(game: &detection::Root2) -> Self {
+        Self {
+            id: game.id.clone(),
+            name: game.name.clone(),
+            aliases: game.aliases.clone(),
+            hook: game.hook,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Candidate {
+    game: usize,
+    /// Lowercase path segments of the executable, the file name is the last one
+    segments: Vec<String>,
+    arguments: Option<String>,
+    is_launcher: bool,
+}
+
+impl Candidate {
+    fn matches(&self, segments: &[String], cmd_line: Option<&str>) -> bool {
+        if !segments.ends_with(&self.segments) {
+            return false;
+        }
+
+        match &self.arguments {
+            // Maybe if cmdline is not present, we should not check for it?
+            Some(args) => cmd_line.is_some_and(|c| c.contains(args.as_str())),
+            None => true,
+        }
+    }
+
+    /// Candidates with more path segments or arguments are more specific and win
+    fn specificity(&self) -> (usize, bool) {
+        (self.segments.len(), self.arguments.is_some())
+    }
+}
+
+/// Splits a path into lowercase segments, accepting both separators
+fn segments(path: &str) -> Vec<String> {
+    path.to_lowercase()
+        .split(['/', '\\'])
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Index of the detection data keyed by executable file name. Built once whenever the
+/// detection data changes so matching a window doesn't have to go through every game.
+#[derive(Debug, Default)]
+pub struct GameMatcher {
+    games: Vec<DetectedGame>,
+    by_file_name: HashMap<String, Vec<Candidate>>,
+}
+
+impl GameMatcher {
+    pub fn new(data: &detection::Root) -> Self {
+        Self::for_os(data, CURRENT_OS)
+    }
+
+    /// Only executables for the given os are indexed, executables without an os match any
+    pub fn for_os(data: &detection::Root, os: &str) -> Self {
+        let mut games = Vec::with_capacity(data.len());
+        let mut by_file_name: HashMap<String, Vec<Candidate>> = HashMap::new();
+
+        for game in data.iter() {
+            let index = games.len();
+            games.push(DetectedGame::new(game));
+
+            for exe in game.executables.iter() {
+                if !exe.os.is_empty() && !exe.os.eq_ignore_ascii_case(os) {
+                    continue;
+                }
+
+                let segments = segments(&exe.name);
+                let Some(file_name) = segments.last().cloned() else {
+                    continue;
+                };
+
+                by_file_name.entry(file_name).or_default().push(Candidate {
+                    game: index,
+                    segments,
+                    arguments: exe.arguments.as_ref().map(|a| a.to_lowercase()),
+                    is_launcher: exe.is_launcher,
+                });
+            }
+        }
+
+        Self {
+            games,
+            by_file_name,
+        }
+    }
+
+    pub fn find(&self, window: &WindowInfo) -> WindowType {
+        let segments = segments(&window.full_exe);
+        let Some(candidates) = segments.last().and_then(|f| self.by_file_name.get(f)) else {
+            return WindowType::Window;
+        };
+
+        let cmd_line = window.cmd_line.as_ref().map(|c| c.to_lowercase());
+
+        // On ties the game that comes first in the detection data wins
+        let best = candidates
+            .iter()
+            .filter(|c| c.matches(&segments, cmd_line.as_deref()))
+            .rev()
+            .max_by_key(|c| c.specificity());
+
+        match best {
+            Some(c) if c.is_launcher => WindowType::Launcher(self.games[c.game].clone()),
+            Some(c) => WindowType::Game(self.games[c.game].clone()),
+            None => WindowType::Window,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn window(full_exe: &str, cmd_line: Option<&str>) -> WindowInfo {
+        serde_json::from_value(json!({
+            "full_exe": full_exe,
+            "obs_id": "Title:Class:exe",
+            "pid": 1,
+            "cmd_line": cmd_line,
+            "is_game": false,
+        }))
+        .unwrap()
+    }
+
+    fn exe(
+        name: &str,
+        os: &str,
+        is_launcher: bool,
+        arguments: Option<&str>,
+    ) -> detection::Executable {
+        detection::Executable {
+            name: name.to_string(),
+            os: os.to_string(),
+            is_launcher,
+            arguments: arguments.map(|a| a.to_string()),
+        }
+    }
+
+    fn game(id: &str, executables: Vec<detection::Executable>) -> detection::Root2 {
+        detection::Root2 {
+            id: id.to_string(),
+            name: id.to_string(),
+            executables,
+            ..Default::default()
+        }
+    }
+
+    fn matched_id(result: WindowType) -> Option<String> {
+        match result {
+            WindowType::Game(g) => Some(g.id),
+            WindowType::Launcher(g) => Some(format!("launcher {}", g.id)),
+            WindowType::Window => None,
+        }
+    }
+
+    #[test]
+    fn matches_whole_path_segments() {
+        let data = vec![game(
+            "cs2",
+            vec![exe("win64/cs2.exe", "win32", false, None)],
+        )];
+        let matcher = GameMatcher::for_os(&data, "win32");
+
+        let found = matcher.find(&window("C:\\Steam\\game\\bin\\WIN64\\cs2.exe", None));
+        assert_eq!(matched_id(found), Some("cs2".to_string()));
+
+        // A raw suffix match would have accepted these
+        assert_eq!(
+            matched_id(matcher.find(&window("C:/bin/win64/notcs2.exe", None))),
+            None
+        );
+        assert_eq!(
+            matched_id(matcher.find(&window("C:/bin/xwin64/cs2.exe", None))),
+            None
+        );
+    }
+
+    #[test]
+    fn filters_by_os() {
+        let data = vec![
+            game("linux_only", vec![exe("game", "linux", false, None)]),
+            game("any_os", vec![exe("other.exe", "", false, None)]),
+        ];
+        let matcher = GameMatcher::for_os(&data, "win32");
+
+        assert_eq!(matched_id(matcher.find(&window("C:/game", None))), None);
+        assert_eq!(
+            matched_id(matcher.find(&window("C:/other.exe", None))),
+            Some("any_os".to_string())
+        );
+    }
+
+    #[test]
+    fn launchers_and_specificity() {
+        let data = vec![
+            game(
+                "riot",
+                vec![exe("RiotClientServices.exe", "win32", true, None)],
+            ),
+            game(
+                "valorant",
+                vec![exe(
+                    "RiotClientServices.exe",
+                    "win32",
+                    false,
+                    Some("--launch-product=valorant"),
+                )],
+            ),
+        ];
+        let matcher = GameMatcher::for_os(&data, "win32");
+
+        let launcher = window("C:/Riot/RiotClientServices.exe", None);
+        assert_eq!(
+            matched_id(matcher.find(&launcher)),
+            Some("launcher riot".to_string())
+        );
+
+        let game = window(
+            "C:/Riot/RiotClientServices.exe",
+            Some("RiotClientServices.exe --launch-product=VALORANT"),
+        );
+        assert_eq!(
+            matched_id(matcher.find(&game)),
+            Some("valorant".to_string())
+        );
+    }
+}
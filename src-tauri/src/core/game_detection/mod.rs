@@ -1,13 +1,10 @@
 use std::{
     collections::HashMap,
     fmt::{self, Debug, Formatter},
-    fs,
-    future::Future,
     sync::Arc,
     time::Duration,
 };
 
-use lazy_static::lazy_static;
 use libobs_window_helper::WindowInfo;
 use serde::{Deserialize, Serialize};
 use specta::Type;
@@ -15,24 +12,42 @@ use tokio::sync::RwLock;
 use tokio_util::sync::{CancellationToken, DropGuard};
 use uuid::Uuid;
 
-use crate::json_typings::clipture_api::game::detection;
+use crate::core::settings::get_settings;
 use refresh::RefreshGameDetection;
 
+mod bus;
+pub use bus::{EventBus, EventSubscription, GameEventMessage};
+
 mod event;
 pub use event::GameEventNotifier;
 
+mod matcher;
+pub use matcher::{DetectedGame, GameMatcher};
+
+mod overrides;
+pub use overrides::{DetectionData, DetectionOverride, OverrideRule};
+
 mod refresh;
+pub use refresh::RefreshStatus;
+mod source;
+pub use source::{SystemWindowSource, WindowSource};
+
+mod tracker;
+pub use tracker::RunningGame;
+use tracker::WindowTracker;
 
 pub const GAME_DETECTION_FILE: &str = "game_detection.json";
-lazy_static! {
-    pub static ref REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24); // Refresh every 24 hours
+
+/// How often the detection data is refreshed, configured in the settings
+pub async fn refresh_interval() -> Duration {
+    let secs = get_settings().await.game_detection.refresh_interval_secs;
+    Duration::from_secs(secs as u64)
 }
 
-pub type ListenerPtr<T> =
-    dyn Fn(T) -> Box<dyn Future<Output = ()> + Unpin + Send> + Send + Sync + 'static;
-pub type ListenerPtrBoxed<T> = Box<ListenerPtr<T>>;
-pub type ListenersTypeRw = Arc<RwLock<HashMap<Uuid, ListenerPtrBoxed<GameEvent>>>>;
-pub type GameDetectionTypeRw = Arc<RwLock<detection::Root>>;
+/// Each listener runs on its own task which is stopped when its guard is removed
+pub type ListenersTypeRw = Arc<RwLock<HashMap<Uuid, DropGuard>>>;
+pub type GameDetectionTypeRw = Arc<RwLock<DetectionData>>;
+pub type WindowTrackerRw = Arc<RwLock<WindowTracker>>;
 
 #[derive(Clone)]
 pub struct ListenerRef {
@@ -73,7 +88,9 @@ impl Drop for ListenerDropGuard {
 
 #[derive(Type, Debug, Clone, Serialize, Deserialize)]
 pub enum WindowType {
-    Game,
+    Game(DetectedGame),
+    /// The launcher of a game, it is not captured
+    Launcher(DetectedGame),
     Window,
 }
 
@@ -81,38 +98,75 @@ pub enum WindowType {
 pub enum GameEvent {
     Closed(WindowInfo),
     Opened(WindowType, WindowInfo),
+    Focused(WindowInfo),
+    Unfocused(WindowInfo),
+    /// Contains the window with its new title
+    TitleChanged(WindowInfo),
 }
 
 pub struct GameDetection {
-    //TODO Maybe use later? Used in other threads for sure
-    #[allow(dead_code)]
     game_detection: GameDetectionTypeRw,
+    tracker: WindowTrackerRw,
+    bus: EventBus,
     listeners: ListenersTypeRw,
     _token: DropGuard,
 }
 
 impl GameDetection {
-    pub async fn initialize() -> anyhow::Result<Self> {
-        let detection_file = Self::get_detection_file().await?;
-        let detection_str = fs::read_to_string(detection_file).ok();
-        let detection = detection_str
-            .map(|s| serde_json::from_str::<detection::Root>(&s))
-            .transpose()?
-            .unwrap_or_default();
-
-        let game_detection = Arc::new(RwLock::new(detection));
+    /// Loads the cached detection data and starts watching the windows of the given source
+    pub async fn initialize<S: WindowSource>(source: S) -> anyhow::Result<Self> {
+        let cache = Self::get_detection_cache().await?;
+        let detection = cache.load().await.unwrap_or_else(|| {
+            log::info!("No cached game detection data, using the bundled list");
+            refresh::bundled_detection()
+        });
+
+        let overrides = match overrides::load_overrides(&Self::get_overrides_file().await?).await {
+            Ok(overrides) => overrides,
+            Err(e) => {
+                log::error!("Couldn't load detection overrides, ignoring them: {:?}", e);
+                vec![]
+            }
+        };
+
+        let game_detection = Arc::new(RwLock::new(DetectionData::new(detection, overrides)));
+        let tracker = Arc::new(RwLock::new(WindowTracker::new()));
+        let bus = EventBus::new(bus::BUS_CAPACITY);
         let listeners = Arc::new(RwLock::new(HashMap::new()));
 
         let token = CancellationToken::new();
         Self::spawn_refresh_file_thread(token.clone(), game_detection.clone()).await;
-        Self::spawn_event_thread(token.clone(), listeners.clone(), game_detection.clone()).await;
+        Self::spawn_event_thread(
+            token.clone(),
+            Arc::new(source),
+            tracker.clone(),
+            bus.clone(),
+            game_detection.clone(),
+        )
+        .await;
 
         let s = Self {
             game_detection,
+            tracker,
+            bus,
             listeners,
             _token: token.drop_guard(),
         };
 
         Ok(s)
     }
+
+    /// The detected games that are running right now
+    pub async fn current(&self) -> Vec<RunningGame> {
+        self.tracker.read().await.running_games()
+    }
+
+    /// Subscribes to the game events. The subscription starts with the windows that are
+    /// open right now, as if they had just been opened.
+    pub async fn subscribe(&self) -> EventSubscription {
+        // The event thread publishes while holding the tracker lock, so no event can be
+        // missed or seen twice between the snapshot and subscribing
+        let tracker = self.tracker.read().await;
+        self.bus.subscribe(tracker.replay_events())
+    }
 }
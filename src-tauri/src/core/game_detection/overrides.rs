@@ -0,0 +1,409 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::Manager;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    json_typings::clipture_api::game::detection,
+    utils::{consts::app_handle, util::write_atomic},
+};
+
+use super::{
+    matcher::{GameMatcher, CURRENT_OS},
+    refresh::unix_now,
+    GameDetection,
+};
+
+pub const OVERRIDES_FILE: &str = "game_detection_overrides.json";
+
+/// Prefix of the game ids created by `OverrideRule::Add`
+const LOCAL_GAME_PREFIX: &str = "local:";
+
+/// A rule the user created to fix the detection data from the API
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OverrideRule {
+    /// Treats windows of the executable as a game
+    Add {
+        name: String,
+        /// File name of the executable, e.g. `game.exe`
+        exe: String,
+        hook: bool,
+    },
+    /// Removes the game with the given id from the detection data
+    Ignore { game_id: String },
+    /// Treats windows of the executable as a normal window even if a game matches it
+    ForceWindow { exe: String },
+}
+
+impl OverrideRule {
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            OverrideRule::Add { name, exe, .. } => {
+                if name.trim().is_empty() {
+                    bail!("Game name must not be empty");
+                }
+                if exe.trim().is_empty() {
+                    bail!("Executable must not be empty");
+                }
+            }
+            OverrideRule::Ignore { game_id } => {
+                if game_id.trim().is_empty() {
+                    bail!("Game id must not be empty");
+                }
+            }
+            OverrideRule::ForceWindow { exe } => {
+                if exe.trim().is_empty() {
+                    bail!("Executable must not be empty");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetectionOverride {
+    pub id: String,
+    pub rule: OverrideRule,
+}
+
+/// The detection data from the API together with the local overrides
+#[derive(Debug, Default)]
+pub struct DetectionData {
+    remote: detection::Root,
+    overrides: Vec<DetectionOverride>,
+    matcher: GameMatcher,
+}
+
+impl DetectionData {
+    pub fn new(remote: detection::Root, overrides: Vec<DetectionOverride>) -> Self {
+        let mut data = Self {
+            remote,
+            overrides,
+            matcher: GameMatcher::default(),
+        };
+
+        data.merge();
+        data
+    }
+
+    /// Matches windows against the remote data with the overrides applied
+    pub fn matcher(&self) -> &GameMatcher {
+        &self.matcher
+    }
+
+    pub fn remote(&self) -> &detection::Root {
+        &self.remote
+    }
+
+    pub fn set_remote(&mut self, remote: detection::Root) {
+        self.remote = remote;
+        self.merge();
+    }
+
+    pub fn overrides(&self) -> &[DetectionOverride] {
+        &self.overrides
+    }
+
+    pub fn set_overrides(&mut self, overrides: Vec<DetectionOverride>) {
+        self.overrides = overrides;
+        self.merge();
+    }
+
+    fn merge(&mut self) {
+        let mut merged = self.remote.clone();
+
+        for o in self.overrides.iter() {
+            match &o.rule {
+                OverrideRule::Ignore { game_id } => merged.retain(|g| &g.id != game_id),
+                OverrideRule::ForceWindow { exe } => {
+                    let exe = exe.to_lowercase();
+                    for game in merged.iter_mut() {
+                        game.executables.retain(|e| file_name(&e.name) != exe);
+                    }
+                }
+                OverrideRule::Add { .. } => {}
+            }
+        }
+
+        // Added games go first so they win over the API data
+        let added = self.overrides.iter().filter_map(|o| match &o.rule {
+            OverrideRule::Add { name, exe, hook } => Some(detection::Root2 {
+                id: format!("{}{}", LOCAL_GAME_PREFIX, o.id),
+                name: name.clone(),
+                hook: *hook,
+                executables: vec![detection::Executable {
+                    name: exe.clone(),
+                    os: CURRENT_OS.to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            _ => None,
+        });
+
+        let merged = added.chain(merged).collect();
+        self.matcher = GameMatcher::new(&merged);
+    }
+}
+
+/// Lowercase file name of an executable path
+fn file_name(path: &str) -> String {
+    path.to_lowercase()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub(super) async fn load_overrides(path: &Path) -> anyhow::Result<Vec<DetectionOverride>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let raw = fs::read_to_string(path)
+        .await
+        .context("Reading detection overrides")?;
+
+    match serde_json::from_str(&raw) {
+        Ok(overrides) => Ok(overrides),
+        Err(e) => {
+            // The next saved rule would replace the user's rules otherwise
+            let target = path.with_file_name(format!(
+                "game_detection_overrides.corrupt-{}.json",
+                unix_now()
+            ));
+            fs::rename(path, &target)
+                .await
+                .context("Moving corrupt detection overrides aside")?;
+
+            Err(anyhow::Error::from(e).context(format!(
+                "Parsing detection overrides, moved them to {}",
+                target.display()
+            )))
+        }
+    }
+}
+
+async fn save_overrides(path: &Path, overrides: &[DetectionOverride]) -> anyhow::Result<()> {
+    write_atomic(path, serde_json::to_string_pretty(overrides)?)
+        .await
+        .context("Writing detection overrides")?;
+
+    Ok(())
+}
+
+impl GameDetection {
+    pub(super) async fn get_overrides_file() -> anyhow::Result<PathBuf> {
+        let app = app_handle().await;
+        let data = app.path().app_data_dir()?;
+
+        Ok(data.join(OVERRIDES_FILE))
+    }
+
+    pub async fn overrides(&self) -> Vec<DetectionOverride> {
+        self.game_detection.read().await.overrides().to_vec()
+    }
+
+    pub async fn add_override(&self, rule: OverrideRule) -> anyhow::Result<DetectionOverride> {
+        rule.validate()?;
+
+        let created = DetectionOverride {
+            id: Uuid::new_v4().to_string(),
+            rule,
+        };
+
+        let mut data = self.game_detection.write().await;
+        let mut overrides = data.overrides().to_vec();
+        overrides.push(created.clone());
+
+        save_overrides(&Self::get_overrides_file().await?, &overrides).await?;
+        data.set_overrides(overrides);
+
+        Ok(created)
+    }
+
+    /// Returns false if there is no override with the given id
+    pub async fn remove_override(&self, id: &str) -> anyhow::Result<bool> {
+        let mut data = self.game_detection.write().await;
+        let mut overrides = data.overrides().to_vec();
+
+        let len = overrides.len();
+        overrides.retain(|o| o.id != id);
+        if overrides.len() == len {
+            return Ok(false);
+        }
+
+        save_overrides(&Self::get_overrides_file().await?, &overrides).await?;
+        data.set_overrides(overrides);
+
+        Ok(true)
+    }
+
+    /// Adds the window that was focused last before Clipture as a game. Only applies to
+    /// windows opened afterwards, so the game has to be restarted to be detected.
+    pub async fn mark_foreground_as_game(&self, hook: bool) -> anyhow::Result<DetectionOverride> {
+        let window = self
+            .tracker
+            .read()
+            .await
+            .last_external_focused()
+            .cloned()
+            .ok_or_else(|| anyhow!("No game window was focused yet, focus the game first"))?;
+
+        let exe = file_name(&window.full_exe);
+
+        let name = window
+            .product_name
+            .clone()
+            .filter(|n| !n.trim().is_empty())
+            .or_else(|| window.title.clone())
+            .unwrap_or_else(|| exe.clone());
+
+        self.add_override(OverrideRule::Add { name, exe, hook })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libobs_window_helper::WindowInfo;
+    use serde_json::json;
+
+    use super::*;
+    use crate::core::game_detection::WindowType;
+
+    fn window(full_exe: &str) -> WindowInfo {
+        serde_json::from_value(json!({
+            "full_exe": full_exe,
+            "obs_id": "Title:Class:exe",
+            "pid": 1,
+            "is_game": false,
+        }))
+        .unwrap()
+    }
+
+    fn game(id: &str, exes: &[&str]) -> detection::Root2 {
+        detection::Root2 {
+            id: id.to_string(),
+            name: id.to_string(),
+            executables: exes
+                .iter()
+                .map(|e| detection::Executable {
+                    name: e.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn rule(id: &str, rule: OverrideRule) -> DetectionOverride {
+        DetectionOverride {
+            id: id.to_string(),
+            rule,
+        }
+    }
+
+    #[test]
+    fn overrides_are_merged_over_remote() {
+        let remote = vec![
+            game("cs2", &["cs2.exe"]),
+            game("riot", &["RiotClientServices.exe", "valorant.exe"]),
+        ];
+
+        let mut data = DetectionData::new(
+            remote,
+            vec![
+                rule(
+                    "1",
+                    OverrideRule::Ignore {
+                        game_id: "cs2".to_string(),
+                    },
+                ),
+                rule(
+                    "2",
+                    OverrideRule::ForceWindow {
+                        exe: "riotclientservices.exe".to_string(),
+                    },
+                ),
+                rule(
+                    "3",
+                    OverrideRule::Add {
+                        name: "My Game".to_string(),
+                        exe: "mygame.exe".to_string(),
+                        hook: true,
+                    },
+                ),
+            ],
+        );
+
+        let find = |data: &DetectionData, exe: &str| match data.matcher().find(&window(exe)) {
+            WindowType::Game(g) => Some(g.id),
+            _ => None,
+        };
+
+        assert_eq!(find(&data, "C:/Games/cs2.exe"), None);
+        assert_eq!(find(&data, "C:/Riot/RiotClientServices.exe"), None);
+        assert_eq!(
+            find(&data, "C:/Riot/valorant.exe"),
+            Some("riot".to_string())
+        );
+        assert_eq!(
+            find(&data, "C:/Games/MyGame.exe"),
+            Some("local:3".to_string())
+        );
+
+        // Overrides survive a refresh of the remote data
+        data.set_remote(vec![
+            game("cs2", &["cs2.exe"]),
+            game("dota2", &["dota2.exe"]),
+        ]);
+        assert_eq!(find(&data, "C:/Games/cs2.exe"), None);
+        assert_eq!(find(&data, "C:/Games/dota2.exe"), Some("dota2".to_string()));
+        assert_eq!(
+            find(&data, "C:/Games/MyGame.exe"),
+            Some("local:3".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_rules_are_rejected() {
+        assert!(OverrideRule::ForceWindow {
+            exe: " ".to_string()
+        }
+        .validate()
+        .is_err());
+        assert!(OverrideRule::Ignore {
+            game_id: "cs2".to_string()
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn corrupt_overrides_are_moved_aside() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("clipture-overrides-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(OVERRIDES_FILE);
+        std::fs::write(&path, "[{\"id\": ")?;
+
+        assert!(load_overrides(&path).await.is_err());
+        let moved = std::fs::read_dir(&dir)?.filter_map(|e| e.ok()).any(|e| {
+            e.file_name()
+                .to_string_lossy()
+                .starts_with("game_detection_overrides.corrupt-")
+        });
+        let exists = path.exists();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(moved);
+        assert!(!exists);
+        Ok(())
+    }
+}
@@ -1,50 +1,278 @@
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
+    core::api::ApiClient,
     json_typings::clipture_api::game::detection,
-    utils::consts::{app_handle, clipture_to_url},
+    utils::{
+        consts::{app_handle, clipture_to_url},
+        util::write_atomic,
+    },
 };
+use anyhow::{bail, Context};
 use async_trait::async_trait;
+use rand::Rng;
+use reqwest::{
+    header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use specta::Type;
 use tauri::Manager;
-use tokio::{fs, select, sync::RwLock, task::JoinHandle, time::Instant};
+use tokio::{fs, select, task::JoinHandle, time::Instant};
 use tokio_util::sync::CancellationToken;
 
-use super::{GameDetection, GAME_DETECTION_FILE, REFRESH_INTERVAL};
+use super::{refresh_interval, GameDetection, GameDetectionTypeRw, GAME_DETECTION_FILE};
+
+/// Stored next to the detection file to make conditional requests
+pub const GAME_DETECTION_META_FILE: &str = "game_detection.meta.json";
+
+/// Used until the detection data has been fetched for the first time
+const BUNDLED_DETECTION: &str = include_str!("../../../resources/game_detection.json");
+
+pub(super) fn bundled_detection() -> detection::Root {
+    serde_json::from_str(BUNDLED_DETECTION).expect("Bundled detection data should be valid")
+}
+
+/// Rejects detection data that can't be right, e.g. from a truncated or tampered file.
+/// Single games that can never match a window are dropped instead, so one bad entry
+/// doesn't keep the whole list from updating.
+pub(super) fn validate_detection(data: detection::Root) -> anyhow::Result<detection::Root> {
+    let mut ids = HashSet::with_capacity(data.len());
+    for game in data.iter() {
+        if game.id.trim().is_empty() {
+            bail!("Game {:?} has no id", game.name);
+        }
+        if !ids.insert(game.id.as_str()) {
+            bail!("Duplicate game id {}", game.id);
+        }
+    }
+
+    let valid = data
+        .into_iter()
+        .filter_map(|mut game| {
+            let len = game.executables.len();
+            game.executables.retain(|e| !e.name.trim().is_empty());
+            if game.executables.len() != len {
+                log::warn!(
+                    "Game {} has executables without a name, ignoring them",
+                    game.id
+                );
+            }
+
+            if game.executables.is_empty() {
+                log::warn!("Game {} has no executables, ignoring it", game.id);
+                return None;
+            }
+
+            Some(game)
+        })
+        .collect();
+
+    Ok(valid)
+}
+
+/// Outcome of a manual refresh
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub enum RefreshStatus {
+    Updated { games: u32 },
+    NotModified,
+    Failed(String),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct CacheMeta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Unix timestamp in seconds of the last successful request
+    pub checked_at: u64,
+}
+
+pub(super) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Where the detection data and its meta data are cached
+pub(super) struct DetectionCache {
+    pub file: PathBuf,
+    pub meta_file: PathBuf,
+}
+
+impl DetectionCache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            file: dir.join(GAME_DETECTION_FILE),
+            meta_file: dir.join(GAME_DETECTION_META_FILE),
+        }
+    }
+
+    /// Missing or invalid meta data just means the next request is unconditional
+    pub async fn read_meta(&self) -> CacheMeta {
+        if !self.file.exists() {
+            return CacheMeta::default();
+        }
+
+        match fs::read_to_string(&self.meta_file).await {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_default(),
+            Err(_) => CacheMeta::default(),
+        }
+    }
+
+    pub async fn write_meta(&self, meta: &CacheMeta) -> anyhow::Result<()> {
+        write_atomic(&self.meta_file, serde_json::to_string(meta)?)
+            .await
+            .context("Writing detection meta data")
+    }
+
+    pub async fn write(&self, data: &detection::Root, meta: &CacheMeta) -> anyhow::Result<()> {
+        write_atomic(&self.file, serde_json::to_string(data)?)
+            .await
+            .context("Writing detection data")?;
+
+        self.write_meta(meta).await
+    }
+
+    /// Reads the cached data. A file that can't be parsed or is invalid is moved out of
+    /// the way, so the next refresh fetches the data again.
+    pub async fn load(&self) -> Option<detection::Root> {
+        let raw = fs::read_to_string(&self.file).await.ok()?;
+        let parsed = serde_json::from_str::<detection::Root>(&raw)
+            .map_err(anyhow::Error::from)
+            .and_then(validate_detection);
+
+        match parsed {
+            Ok(data) => Some(data),
+            Err(e) => {
+                log::warn!("Cached game detection data is corrupt: {:?}", e);
+                if let Err(e) = self.quarantine().await {
+                    log::error!("Couldn't quarantine game detection data: {:?}", e);
+                }
+
+                None
+            }
+        }
+    }
+
+    async fn quarantine(&self) -> anyhow::Result<PathBuf> {
+        let target = self
+            .file
+            .with_file_name(format!("game_detection.corrupt-{}.json", unix_now()));
+
+        fs::rename(&self.file, &target).await?;
+        // The validators belong to the corrupt file
+        let _ = fs::remove_file(&self.meta_file).await;
+
+        log::info!("Moved corrupt game detection data to {}", target.display());
+        Ok(target)
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum FetchResult {
+    Updated(detection::Root, CacheMeta),
+    NotModified,
+}
+
+/// Exponential backoff with jitter for failed refreshes
+pub(super) struct Backoff {
+    attempt: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            attempt: 0,
+            base,
+            max,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// `jitter` between 0 and 1 picks a delay between half and the full backoff
+    fn delay_with(&self, jitter: f64) -> Duration {
+        let exp = self.base.saturating_mul(2u32.saturating_pow(self.attempt));
+        let capped = exp.min(self.max);
+
+        capped / 2 + capped.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay_with(rand::thread_rng().gen());
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay
+    }
+}
 
 #[async_trait]
 pub(super) trait RefreshGameDetection {
-    async fn fetch_game_detection() -> anyhow::Result<detection::Root>;
+    /// Sends a conditional request if we know the ETag or Last-Modified of the cached data
+    async fn fetch_game_detection(url: &str, meta: &CacheMeta) -> anyhow::Result<FetchResult>;
     /// Returns as an option the detection data and when the data should be refreshed again
-    async fn refresh(force: bool) -> anyhow::Result<(Option<detection::Root>, Instant)>;
+    async fn refresh(
+        cache: &DetectionCache,
+        url: &str,
+        interval: Duration,
+        force: bool,
+    ) -> anyhow::Result<(Option<detection::Root>, Instant)>;
 
     async fn spawn_refresh_file_thread(
         token: CancellationToken,
-        lock: Arc<RwLock<detection::Root>>,
+        lock: GameDetectionTypeRw,
     ) -> JoinHandle<()>;
 
-    async fn get_detection_file() -> anyhow::Result<PathBuf>;
+    async fn get_detection_cache() -> anyhow::Result<DetectionCache>;
 }
 
 #[async_trait]
 impl RefreshGameDetection for GameDetection {
     async fn spawn_refresh_file_thread(
         token: CancellationToken,
-        lock: Arc<RwLock<detection::Root>>,
+        lock: GameDetectionTypeRw,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
+            let mut backoff = Backoff::new(Duration::from_secs(30), Duration::from_secs(60 * 60));
             loop {
-                let data_len = lock.read().await.len();
-                let r = Self::refresh(data_len == 0).await;
-                if let Err(e) = r {
-                    log::error!("Error refreshing game detection: {:?}", e);
-                    tokio::time::sleep(Duration::from_secs(60)).await;
-                    continue;
-                }
+                let r = async {
+                    let cache = Self::get_detection_cache().await?;
+                    let url = clipture_to_url("/api/game/detection");
+                    let force = !cache.file.exists();
 
-                let (game_detection, refresh_time) = r.unwrap();
-                if let Some(game_detection) = game_detection {
-                    *lock.write().await = game_detection;
+                    Self::refresh(&cache, &url, refresh_interval().await, force).await
                 }
+                .await;
+
+                let refresh_time = match r {
+                    Ok((game_detection, refresh_time)) => {
+                        backoff.reset();
+                        if let Some(game_detection) = game_detection {
+                            lock.write().await.set_remote(game_detection);
+                        }
+
+                        refresh_time
+                    }
+                    Err(e) => {
+                        let delay = backoff.next_delay();
+                        log::error!(
+                            "Error refreshing game detection, retrying in {:?}: {:?}",
+                            delay,
+                            e
+                        );
+
+                        Instant::now() + delay
+                    }
+                };
 
                 select! {
                     _ = tokio::time::sleep_until(refresh_time) => {}
@@ -54,43 +282,364 @@ impl RefreshGameDetection for GameDetection {
         })
     }
 
-    async fn fetch_game_detection() -> anyhow::Result<detection::Root> {
+    async fn fetch_game_detection(url: &str, meta: &CacheMeta) -> anyhow::Result<FetchResult> {
         log::debug!("Fetching game detection data");
 
-        let detection_data = reqwest::get(clipture_to_url("/api/game/detection")).await?;
-        let detection_data = detection_data.error_for_status()?;
-        let detection_data: detection::Root = detection_data.json().await?;
+        let api = ApiClient::new().await;
+        let mut req = api.request_url(Method::GET, url);
+        if let Some(etag) = &meta.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let res = api.send(req).await?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(FetchResult::NotModified);
+        }
 
-        Ok(detection_data)
+        let res = res.error_for_status()?;
+        let header = |name: HeaderName| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+
+        let meta = CacheMeta {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            checked_at: unix_now(),
+        };
+
+        let detection_data: detection::Root = res.json().await?;
+        Ok(FetchResult::Updated(detection_data, meta))
     }
 
-    async fn get_detection_file() -> anyhow::Result<PathBuf> {
+    async fn get_detection_cache() -> anyhow::Result<DetectionCache> {
         let app = app_handle().await;
         let data = app.path().app_data_dir()?;
-        let detection_file = data.join(GAME_DETECTION_FILE);
 
-        Ok(detection_file)
+        Ok(DetectionCache::new(&data))
     }
 
-    async fn refresh(force: bool) -> anyhow::Result<(Option<detection::Root>, Instant)> {
-        // Either file can be missing or it's time to refresh
+    async fn refresh(
+        cache: &DetectionCache,
+        url: &str,
+        interval: Duration,
+        force: bool,
+    ) -> anyhow::Result<(Option<detection::Root>, Instant)> {
+        let mut meta = cache.read_meta().await;
+
+        // If force, we ignore the last check and just overwrite the file
+        let since_check = Duration::from_secs(unix_now().saturating_sub(meta.checked_at));
+        if since_check <= interval && !force {
+            return Ok((None, Instant::now() + (interval - since_check)));
+        }
+
+        let refresh_time = Instant::now() + interval;
+        match Self::fetch_game_detection(url, &meta).await? {
+            FetchResult::NotModified => {
+                log::debug!("Game detection data not modified");
+                meta.checked_at = unix_now();
+                cache.write_meta(&meta).await?;
 
-        let detection_file = Self::get_detection_file().await?;
-        let meta = fs::metadata(&detection_file).await;
-        if let Ok(meta) = meta {
-            let last_modified = meta.modified()?;
-            let duration = last_modified.elapsed()?;
+                Ok((None, refresh_time))
+            }
+            FetchResult::Updated(game_detection, meta) => {
+                let game_detection =
+                    validate_detection(game_detection).context("Invalid game detection data")?;
+                cache.write(&game_detection, &meta).await?;
 
-            // If force, we ignore the duration and just overwrite the file
-            if duration <= *REFRESH_INTERVAL && !force {
-                return Ok((None, Instant::now() + (*REFRESH_INTERVAL - duration)));
+                Ok((Some(game_detection), refresh_time))
             }
         }
+    }
+}
+
+impl GameDetection {
+    /// Fetches the detection data right away, unless the server says it hasn't changed
+    pub async fn refresh_now(&self) -> RefreshStatus {
+        let r = async {
+            let cache = Self::get_detection_cache().await?;
+            let url = clipture_to_url("/api/game/detection");
+
+            Self::refresh(&cache, &url, refresh_interval().await, true).await
+        }
+        .await;
+
+        match r {
+            Ok((Some(game_detection), _)) => {
+                let games = game_detection.len() as u32;
+                self.game_detection.write().await.set_remote(game_detection);
+
+                RefreshStatus::Updated { games }
+            }
+            Ok((None, _)) => RefreshStatus::NotModified,
+            Err(e) => {
+                log::error!("Error refreshing game detection: {:?}", e);
+                RefreshStatus::Failed(e.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    /// Answers every request with the same canned response and records the request heads
+    struct MockServer {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+        hits: Arc<AtomicUsize>,
+    }
+
+    impl MockServer {
+        async fn start(status: &str, headers: &[(&str, &str)], body: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!(
+                "http://{}/api/game/detection",
+                listener.local_addr().unwrap()
+            );
+
+            let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+            for (name, value) in headers {
+                response.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            response.push_str("Connection: close\r\n\r\n");
+            response.push_str(body);
+
+            let requests = Arc::new(Mutex::new(vec![]));
+            let hits = Arc::new(AtomicUsize::new(0));
+
+            let (r, h) = (requests.clone(), hits.clone());
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let mut buf = vec![0; 4096];
+                    let n = stream.read(&mut buf).await.unwrap_or_default();
+                    r.lock()
+                        .unwrap()
+                        .push(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+                    h.fetch_add(1, Ordering::SeqCst);
+
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+
+            Self {
+                url,
+                requests,
+                hits,
+            }
+        }
+
+        fn last_request(&self) -> String {
+            self.requests
+                .lock()
+                .unwrap()
+                .last()
+                .cloned()
+                .unwrap_or_default()
+        }
+    }
+
+    fn temp_cache(name: &str) -> DetectionCache {
+        let dir = std::env::temp_dir().join(format!(
+            "clipture-detection-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        DetectionCache::new(&dir)
+    }
+
+    const BODY: &str = r#"[{"id":"cs2","name":"Counter-Strike 2","hook":true,"executables":[{"is_launcher":false,"name":"cs2.exe","os":"win32"}]}]"#;
+
+    #[tokio::test]
+    async fn ok_response_is_cached_with_validators() -> anyhow::Result<()> {
+        let server = MockServer::start("200 OK", &[("ETag", "\"v1\"")], BODY).await;
+        let cache = temp_cache("ok");
+
+        let (data, _) = GameDetection::refresh(&cache, &server.url, DAY, true).await?;
+        assert_eq!(data.unwrap()[0].id, "cs2");
+        assert!(cache.file.exists());
+        assert_eq!(cache.read_meta().await.etag.as_deref(), Some("\"v1\""));
+        // Nothing cached yet, so the first request is unconditional
+        assert!(!server.last_request().contains("if-none-match"));
+
+        // Fresh data isn't requested again
+        let (data, _) = GameDetection::refresh(&cache, &server.url, DAY, false).await?;
+        assert!(data.is_none());
+        assert_eq!(server.hits.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn not_modified_keeps_cache() -> anyhow::Result<()> {
+        let server = MockServer::start("304 Not Modified", &[], "").await;
+        let cache = temp_cache("not-modified");
+        fs::write(&cache.file, BODY).await?;
+        cache
+            .write_meta(&CacheMeta {
+                etag: Some("\"v1\"".to_string()),
+                last_modified: Some("Tue, 01 Oct 2024 00:00:00 GMT".to_string()),
+                checked_at: 0,
+            })
+            .await?;
+
+        let (data, _) = GameDetection::refresh(&cache, &server.url, DAY, false).await?;
+        assert!(data.is_none());
+
+        let request = server.last_request();
+        assert!(request.contains("if-none-match: \"v1\""));
+        assert!(request.contains("if-modified-since: tue, 01 oct 2024 00:00:00 gmt"));
+
+        let meta = cache.read_meta().await;
+        assert_eq!(meta.etag.as_deref(), Some("\"v1\""));
+        assert!(meta.checked_at > 0);
+        assert_eq!(fs::read_to_string(&cache.file).await?, BODY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_error_keeps_cache() -> anyhow::Result<()> {
+        let server = MockServer::start("500 Internal Server Error", &[], "oops").await;
+        let cache = temp_cache("server-error");
+        fs::write(&cache.file, BODY).await?;
+
+        assert!(GameDetection::refresh(&cache, &server.url, DAY, true)
+            .await
+            .is_err());
+        assert_eq!(fs::read_to_string(&cache.file).await?, BODY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn offline_fails_without_touching_cache() -> anyhow::Result<()> {
+        // Bind and drop a listener to get a port nothing listens on
+        let addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let cache = temp_cache("offline");
+
+        let url = format!("http://{}/api/game/detection", addr);
+        assert!(GameDetection::refresh(&cache, &url, DAY, true)
+            .await
+            .is_err());
+        assert!(!cache.file.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let mut backoff = Backoff::new(Duration::from_secs(30), Duration::from_secs(300));
+        assert_eq!(backoff.delay_with(0.0), Duration::from_secs(15));
+        assert_eq!(backoff.delay_with(1.0), Duration::from_secs(30));
+
+        for _ in 0..3 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.delay_with(1.0), Duration::from_secs(240));
+
+        backoff.next_delay();
+        assert_eq!(backoff.delay_with(1.0), Duration::from_secs(300));
+        assert_eq!(backoff.delay_with(0.0), Duration::from_secs(150));
+
+        backoff.reset();
+        assert_eq!(backoff.delay_with(1.0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn bundled_detection_is_valid() {
+        let bundled = bundled_detection();
+        assert!(!bundled.is_empty());
+        let len = bundled.len();
+        assert_eq!(validate_detection(bundled).unwrap().len(), len);
+    }
+
+    #[tokio::test]
+    async fn invalid_response_is_not_cached() -> anyhow::Result<()> {
+        let body = r#"[{"id":"cs2","name":"A","hook":true,"executables":[{"is_launcher":false,"name":"a.exe","os":"win32"}]},{"id":"cs2","name":"B","hook":true,"executables":[{"is_launcher":false,"name":"b.exe","os":"win32"}]}]"#;
+        let server = MockServer::start("200 OK", &[], body).await;
+        let cache = temp_cache("invalid");
+
+        assert!(GameDetection::refresh(&cache, &server.url, DAY, true)
+            .await
+            .is_err());
+        assert!(!cache.file.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_cache_is_quarantined() -> anyhow::Result<()> {
+        let cache = temp_cache("corrupt");
+        fs::write(&cache.file, &BODY[..BODY.len() / 2]).await?;
+        cache.write_meta(&CacheMeta::default()).await?;
+
+        assert!(cache.load().await.is_none());
+        assert!(!cache.file.exists());
+        assert!(!cache.meta_file.exists());
+
+        let dir = cache.file.parent().unwrap();
+        let quarantined = std::fs::read_dir(dir)?.filter_map(|e| e.ok()).any(|e| {
+            e.file_name()
+                .to_string_lossy()
+                .starts_with("game_detection.corrupt-")
+        });
+        assert!(quarantined);
+
+        // A valid file loads and leaves no temporary file behind
+        cache
+            .write(&bundled_detection(), &CacheMeta::default())
+            .await?;
+        assert!(cache.load().await.is_some());
+        assert!(!dir.join("game_detection.json.tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn validation_rules() {
+        let game = |id: &str, exe: &str| detection::Root2 {
+            id: id.to_string(),
+            executables: vec![detection::Executable {
+                name: exe.to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(validate_detection(vec![game("a", "a.exe"), game("b", "b.exe")]).is_ok());
+        assert!(validate_detection(vec![game("a", "a.exe"), game("a", "b.exe")]).is_err());
+        assert!(validate_detection(vec![game("", "a.exe")]).is_err());
 
-        let game_detection = Self::fetch_game_detection().await?;
-        fs::write(&detection_file, serde_json::to_string(&game_detection)?).await?;
+        // Games that can't match anything are dropped, the rest is kept
+        let mut no_exes = game("c", "c.exe");
+        no_exes.executables.clear();
+        let mut unnamed = game("d", "d.exe");
+        unnamed.executables.push(detection::Executable {
+            name: " ".to_string(),
+            ..Default::default()
+        });
 
-        let refresh_time = Instant::now() + *REFRESH_INTERVAL;
-        return Ok((Some(game_detection), refresh_time));
+        let valid =
+            validate_detection(vec![game("a", "a.exe"), game("b", " "), no_exes, unnamed]).unwrap();
+        let ids = valid.iter().map(|g| g.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["a", "d"]);
+        assert_eq!(valid[1].executables.len(), 1);
     }
 }
@@ -0,0 +1,106 @@
+use libobs_window_helper::{get_all_windows, WindowInfo, WindowSearchMode};
+
+use super::tracker::{foreground_window, with_handles, WindowKey};
+
+/// Where the game detection gets the open windows from
+pub trait WindowSource: Send + Sync + 'static {
+    fn windows(&self) -> anyhow::Result<Vec<(WindowKey, WindowInfo)>>;
+    /// The window that currently has focus, called right after `windows`
+    fn focused(&self) -> Option<WindowKey>;
+}
+
+/// Enumerates the windows of the system
+pub struct SystemWindowSource;
+
+impl WindowSource for SystemWindowSource {
+    fn windows(&self) -> anyhow::Result<Vec<(WindowKey, WindowInfo)>> {
+        let windows = get_all_windows(WindowSearchMode::IncludeMinimized)?;
+        Ok(with_handles(windows))
+    }
+
+    fn focused(&self) -> Option<WindowKey> {
+        foreground_window()
+    }
+}
+
+#[cfg(test)]
+pub use scripted::ScriptedWindowSource;
+
+#[cfg(test)]
+mod scripted {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Snapshot {
+        windows: Vec<WindowInfo>,
+        focused: Option<WindowKey>,
+    }
+
+    #[derive(Default)]
+    struct Script {
+        steps: VecDeque<Result<Snapshot, String>>,
+        current: Snapshot,
+    }
+
+    /// Returns a scripted sequence of window lists, one step per call to `windows`.
+    /// The last step is repeated once the script runs out.
+    #[derive(Clone, Default)]
+    pub struct ScriptedWindowSource {
+        script: Arc<Mutex<Script>>,
+    }
+
+    impl ScriptedWindowSource {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn push(&self, windows: Vec<WindowInfo>, focused: Option<&WindowInfo>) -> &Self {
+            let snapshot = Snapshot {
+                windows,
+                focused: focused.map(WindowKey::for_window),
+            };
+
+            self.script.lock().unwrap().steps.push_back(Ok(snapshot));
+            self
+        }
+
+        /// The next call to `windows` fails with the given message
+        pub fn push_error(&self, msg: &str) -> &Self {
+            self.script
+                .lock()
+                .unwrap()
+                .steps
+                .push_back(Err(msg.to_string()));
+            self
+        }
+    }
+
+    impl WindowSource for ScriptedWindowSource {
+        fn windows(&self) -> anyhow::Result<Vec<(WindowKey, WindowInfo)>> {
+            let mut script = self.script.lock().unwrap();
+            match script.steps.pop_front() {
+                Some(Ok(snapshot)) => script.current = snapshot,
+                Some(Err(e)) => return Err(anyhow!(e)),
+                None => {}
+            }
+
+            Ok(script
+                .current
+                .windows
+                .iter()
+                .map(|w| (WindowKey::for_window(w), w.clone()))
+                .collect())
+        }
+
+        fn focused(&self) -> Option<WindowKey> {
+            self.script.lock().unwrap().current.focused
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use libobs_window_helper::WindowInfo;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::{DetectedGame, GameEvent, WindowType};
+
+/// Identifies a window across refreshes by its native handle, which stays the same while
+/// the window is open. The pid guards against a handle reused by another process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WindowKey {
+    pub pid: u32,
+    pub hwnd: isize,
+}
+
+impl WindowKey {
+    pub fn new(pid: u32, hwnd: isize) -> Self {
+        Self { pid, hwnd }
+    }
+
+    /// Stands in for the native handle in tests, one window per class of a process
+    #[cfg(test)]
+    pub fn for_window(window: &WindowInfo) -> Self {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        window.class.hash(&mut hasher);
+        Self::new(window.pid, hasher.finish() as isize)
+    }
+}
+
+/// A detected game that is currently running
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub struct RunningGame {
+    pub game: DetectedGame,
+    pub window: WindowInfo,
+    pub focused: bool,
+}
+
+/// Remembers the windows of the last refresh and turns changes into events
+pub struct WindowTracker {
+    /// Windows are classified once when they open
+    windows: HashMap<WindowKey, (WindowInfo, WindowType)>,
+    focused: Option<WindowKey>,
+    /// The last focused window of another process. Clipture itself has focus whenever
+    /// the user interacts with it, e.g. to mark the game they were just playing.
+    last_external: Option<WindowInfo>,
+    own_pid: u32,
+}
+
+impl Default for WindowTracker {
+    fn default() -> Self {
+        Self::with_own_pid(std::process::id())
+    }
+}
+
+impl WindowTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `own_pid` is the process whose windows don't count as `last_external_focused`
+    pub fn with_own_pid(own_pid: u32) -> Self {
+        Self {
+            windows: HashMap::new(),
+            focused: None,
+            last_external: None,
+            own_pid,
+        }
+    }
+
+    /// Diffs the given windows against the previous ones. Events are ordered as
+    /// unfocused, closed, opened, title changed and focused.
+    pub fn update<F: Fn(&WindowInfo) -> WindowType>(
+        &mut self,
+        windows: Vec<(WindowKey, WindowInfo)>,
+        focused: Option<WindowKey>,
+        classify: F,
+    ) -> Vec<GameEvent> {
+        let current = windows.into_iter().collect::<HashMap<_, _>>();
+
+        // Only windows we know about can be focused
+        let focused = focused.filter(|k| current.contains_key(k));
+
+        let mut events = vec![];
+        if self.focused != focused {
+            if let Some((prev, _)) = self.focused.as_ref().and_then(|k| self.windows.get(k)) {
+                events.push(GameEvent::Unfocused(prev.clone()));
+            }
+        }
+
+        for (key, (window, _)) in self.windows.iter() {
+            if !current.contains_key(key) {
+                events.push(GameEvent::Closed(window.clone()));
+            }
+        }
+
+        let mut windows = HashMap::with_capacity(current.len());
+        for (key, window) in current.iter() {
+            let window_type = match self.windows.remove(key) {
+                None => {
+                    let window_type = classify(window);
+                    events.push(GameEvent::Opened(window_type.clone(), window.clone()));
+                    window_type
+                }
+                Some((prev, window_type)) => {
+                    if prev.title != window.title {
+                        events.push(GameEvent::TitleChanged(window.clone()));
+                    }
+                    window_type
+                }
+            };
+
+            windows.insert(*key, (window.clone(), window_type));
+        }
+
+        if self.focused != focused {
+            if let Some(window) = focused.as_ref().and_then(|k| current.get(k)) {
+                events.push(GameEvent::Focused(window.clone()));
+                if window.pid != self.own_pid {
+                    self.last_external = Some(window.clone());
+                }
+            }
+        }
+
+        self.windows = windows;
+        self.focused = focused;
+
+        events
+    }
+
+    /// Events that describe the current state to someone who hasn't seen any events yet
+    pub fn replay_events(&self) -> Vec<GameEvent> {
+        let mut events = self
+            .windows
+            .values()
+            .map(|(window, window_type)| GameEvent::Opened(window_type.clone(), window.clone()))
+            .collect::<Vec<_>>();
+
+        if let Some((window, _)) = self.focused.as_ref().and_then(|k| self.windows.get(k)) {
+            events.push(GameEvent::Focused(window.clone()));
+        }
+
+        events
+    }
+
+    /// The window that had focus before Clipture, as it was when it got focused
+    pub fn last_external_focused(&self) -> Option<&WindowInfo> {
+        self.last_external.as_ref()
+    }
+
+    pub fn running_games(&self) -> Vec<RunningGame> {
+        self.windows
+            .iter()
+            .filter_map(|(key, (window, window_type))| match window_type {
+                WindowType::Game(game) => Some(RunningGame {
+                    game: game.clone(),
+                    window: window.clone(),
+                    focused: self.focused.as_ref() == Some(key),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(windows)]
+mod native {
+    use windows::{
+        core::BOOL,
+        Win32::{
+            Foundation::{HWND, LPARAM},
+            UI::WindowsAndMessaging::{
+                EnumWindows, GetClassNameW, GetForegroundWindow, GetWindowTextW,
+                GetWindowThreadProcessId,
+            },
+        },
+    };
+
+    use super::*;
+
+    pub fn key(hwnd: HWND) -> WindowKey {
+        let mut pid = 0;
+        unsafe { GetWindowThreadProcessId(hwnd, Some(&mut pid)) };
+
+        WindowKey::new(pid, hwnd.0 as isize)
+    }
+
+    fn class(hwnd: HWND) -> Option<String> {
+        let mut buf = [0u16; 256];
+        let len = unsafe { GetClassNameW(hwnd, &mut buf) };
+        (len > 0).then(|| String::from_utf16_lossy(&buf[..len as usize]))
+    }
+
+    fn title(hwnd: HWND) -> Option<String> {
+        let mut buf = [0u16; 512];
+        let len = unsafe { GetWindowTextW(hwnd, &mut buf) };
+        (len > 0).then(|| String::from_utf16_lossy(&buf[..len as usize]))
+    }
+
+    pub fn foreground() -> Option<HWND> {
+        let hwnd = unsafe { GetForegroundWindow() };
+        (!hwnd.0.is_null()).then_some(hwnd)
+    }
+
+    unsafe extern "system" fn collect(hwnd: HWND, param: LPARAM) -> BOOL {
+        let handles = &mut *(param.0 as *mut Vec<HWND>);
+        handles.push(hwnd);
+        true.into()
+    }
+
+    /// All top level windows in the order `EnumWindows` returns them
+    pub fn top_level() -> Vec<(WindowKey, Option<String>, Option<String>)> {
+        let mut handles: Vec<HWND> = vec![];
+        unsafe {
+            if let Err(e) = EnumWindows(Some(collect), LPARAM(&mut handles as *mut _ as isize)) {
+                log::warn!("Couldn't enumerate windows: {:?}", e);
+            }
+        }
+
+        handles
+            .into_iter()
+            .map(|hwnd| (key(hwnd), class(hwnd), title(hwnd)))
+            .collect()
+    }
+}
+
+/// Pairs the windows with their native handles. Windows with the same pid, class and title
+/// are matched in enumeration order, windows that closed in the meantime are dropped.
+#[cfg(windows)]
+pub fn with_handles(windows: Vec<WindowInfo>) -> Vec<(WindowKey, WindowInfo)> {
+    let mut native = native::top_level();
+
+    windows
+        .into_iter()
+        .filter_map(|window| {
+            let same_window = |(key, class, _): &&(WindowKey, Option<String>, Option<String>)| {
+                key.pid == window.pid && *class == window.class
+            };
+
+            // The title might have changed between both enumerations
+            let pos = native
+                .iter()
+                .position(|n| same_window(&n) && n.2 == window.title)
+                .or_else(|| native.iter().position(|n| same_window(&n)))?;
+
+            Some((native.remove(pos).0, window))
+        })
+        .collect()
+}
+
+#[cfg(not(windows))]
+pub fn with_handles(windows: Vec<WindowInfo>) -> Vec<(WindowKey, WindowInfo)> {
+    windows
+        .into_iter()
+        .enumerate()
+        .map(|(i, w)| (WindowKey::new(w.pid, i as isize), w))
+        .collect()
+}
+
+/// Returns the key of the window that currently has focus
+#[cfg(windows)]
+pub fn foreground_window() -> Option<WindowKey> {
+    native::foreground().map(native::key)
+}
+
+#[cfg(not(windows))]
+pub fn foreground_window() -> Option<WindowKey> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn window(pid: u32, class: &str, title: &str) -> WindowInfo {
+        serde_json::from_value(json!({
+            "full_exe": "C:/Games/game.exe",
+            "obs_id": format!("{}:{}:game.exe", title, class),
+            "pid": pid,
+            "title": title,
+            "class": class,
+            "is_game": false,
+        }))
+        .unwrap()
+    }
+
+    fn names(events: &[GameEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| match e {
+                GameEvent::Opened(_, w) => format!("opened {}", w.pid),
+                GameEvent::Closed(w) => format!("closed {}", w.pid),
+                GameEvent::Focused(w) => format!("focused {}", w.pid),
+                GameEvent::Unfocused(w) => format!("unfocused {}", w.pid),
+                GameEvent::TitleChanged(w) => format!("title {}", w.pid),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn open_close_and_focus() {
+        let mut tracker = WindowTracker::new();
+        let classify = |_: &WindowInfo| WindowType::Window;
+
+        let key = WindowKey::for_window;
+        let a = window(1, "Main", "A");
+        let events = tracker.update(vec![(key(&a), a.clone())], Some(key(&a)), classify);
+        assert_eq!(names(&events), vec!["opened 1", "focused 1"]);
+
+        // Second window of the same process with another class
+        let b = window(1, "Popup", "B");
+        let events = tracker.update(
+            vec![(key(&a), a.clone()), (key(&b), b.clone())],
+            Some(key(&b)),
+            classify,
+        );
+        assert_eq!(names(&events), vec!["unfocused 1", "opened 1", "focused 1"]);
+
+        let renamed = window(1, "Main", "A - renamed");
+        let events = tracker.update(vec![(key(&renamed), renamed)], None, classify);
+        assert_eq!(names(&events), vec!["unfocused 1", "closed 1", "title 1"]);
+
+        let events = tracker.update(vec![], None, classify);
+        assert_eq!(names(&events), vec!["closed 1"]);
+        assert!(tracker.update(vec![], None, classify).is_empty());
+    }
+
+    #[test]
+    fn remembers_focus_before_clipture() {
+        let own_pid = 99;
+        let mut tracker = WindowTracker::with_own_pid(own_pid);
+        let classify = |_: &WindowInfo| WindowType::Window;
+        let key = WindowKey::for_window;
+
+        let game = window(1, "Game", "Game");
+        let clipture = window(own_pid, "Tauri", "Clipture");
+        let windows = vec![
+            (key(&game), game.clone()),
+            (key(&clipture), clipture.clone()),
+        ];
+
+        tracker.update(windows.clone(), Some(key(&clipture)), classify);
+        assert!(tracker.last_external_focused().is_none());
+
+        tracker.update(windows.clone(), Some(key(&game)), classify);
+        // The user switches to Clipture to mark the game
+        tracker.update(windows.clone(), Some(key(&clipture)), classify);
+        assert_eq!(tracker.last_external_focused().map(|w| w.pid), Some(1));
+
+        tracker.update(windows, None, classify);
+        assert_eq!(tracker.last_external_focused().map(|w| w.pid), Some(1));
+    }
+}
@@ -1,3 +1,8 @@
+pub mod api;
 pub mod auth;
+pub mod clips;
+pub mod game_detection;
 pub mod obs;
-pub mod game_detection;
\ No newline at end of file
+pub mod session;
+pub mod settings;
+pub mod upload;
@@ -1,25 +1,320 @@
-use anyhow::bail;
-use libobs_sources::windows::WindowCaptureSourceUpdater;
-use libobs_window_helper::WindowInfo;
-use libobs_wrapper::{data::ObsObjectUpdater, utils::traits::ObsUpdatable};
+use anyhow::Context;
+use libobs_sources::windows::{
+    GameCaptureSourceBuilder, GameCaptureSourceUpdater, MonitorCaptureSourceBuilder,
+    ObsGameCaptureMode, WindowCaptureSourceBuilder, WindowCaptureSourceUpdater,
+};
+use libobs_window_helper::{WindowInfo, WindowSearchMode};
+use libobs_wrapper::{
+    data::{ObsObjectBuilder, ObsObjectUpdater},
+    scenes::ObsSceneRef,
+    sources::ObsSourceRef,
+    utils::traits::ObsUpdatable,
+};
+use serde::{Deserialize, Serialize};
+use specta::Type;
 
 use super::ObsManager;
 
+/// What should be captured by default, stored in the settings
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CaptureTarget {
+    /// Captures the monitor with the given id or name, the primary monitor if none match
+    Monitor {
+        id: Option<u32>,
+        name: Option<String>,
+    },
+    Window {
+        obs_id: String,
+    },
+    /// Captures the primary monitor until a game is detected and then switches to the game
+    FollowGame,
+}
+
+impl Default for CaptureTarget {
+    fn default() -> Self {
+        CaptureTarget::Monitor {
+            id: None,
+            name: None,
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MonitorSource {
+    pub id: u32,
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub is_primary: bool,
+}
+
+#[derive(Type, Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureSources {
+    pub monitors: Vec<MonitorSource>,
+    pub windows: Vec<WindowInfo>,
+}
+
+/// Lists all monitors and windows that can be captured
+pub fn list_sources() -> anyhow::Result<CaptureSources> {
+    let monitors = MonitorCaptureSourceBuilder::get_monitors()?
+        .iter()
+        .map(|m| MonitorSource {
+            id: m.0.id,
+            name: m.0.name.clone(),
+            width: m.0.width,
+            height: m.0.height,
+            is_primary: m.0.is_primary,
+        })
+        .collect();
+
+    let windows = WindowCaptureSourceBuilder::get_windows(WindowSearchMode::ExcludeMinimized)?;
+
+    Ok(CaptureSources { monitors, windows })
+}
+
+/// Returns the index of the monitor matching the id or name, falling back to the
+/// primary monitor and then to the first one
+pub fn find_monitor(
+    monitors: &[MonitorSource],
+    id: Option<u32>,
+    name: Option<&str>,
+) -> Option<usize> {
+    let by_id = id.and_then(|id| monitors.iter().position(|m| m.id == id));
+    let by_name = || name.and_then(|name| monitors.iter().position(|m| m.name == name));
+    let primary = || monitors.iter().position(|m| m.is_primary);
+    let first = || (!monitors.is_empty()).then_some(0);
+
+    by_id.or_else(by_name).or_else(primary).or_else(first)
+}
+
+/// The source that is currently captured by the capture scene item
+#[derive(Debug, Clone)]
+pub enum CaptureSource {
+    Monitor {
+        id: Option<u32>,
+        name: Option<String>,
+    },
+    Window(WindowInfo),
+    /// Game capture injects a hook into the game, only use it if the game supports it
+    Game(WindowInfo),
+}
+
+impl CaptureSource {
+    /// Resolves the target from the settings to a source that exists right now.
+    /// Falls back to the primary monitor if the target can't be found.
+    pub fn from_target(target: &CaptureTarget) -> anyhow::Result<Self> {
+        let source = match target {
+            CaptureTarget::Monitor { id, name } => CaptureSource::Monitor {
+                id: *id,
+                name: name.clone(),
+            },
+            CaptureTarget::Window { obs_id } => {
+                let windows =
+                    WindowCaptureSourceBuilder::get_windows(WindowSearchMode::IncludeMinimized)?;
+
+                match windows.into_iter().find(|w| &w.obs_id == obs_id) {
+                    Some(window) => CaptureSource::Window(window),
+                    None => {
+                        log::warn!(
+                            "Window {} not found, falling back to primary monitor",
+                            obs_id
+                        );
+                        CaptureSource::Monitor {
+                            id: None,
+                            name: None,
+                        }
+                    }
+                }
+            }
+            CaptureTarget::FollowGame => CaptureSource::Monitor {
+                id: None,
+                name: None,
+            },
+        };
+
+        Ok(source)
+    }
+
+    /// Game capture if the game supports hooking, window capture otherwise
+    pub fn for_game(window: WindowInfo, hook: bool) -> Self {
+        if hook {
+            CaptureSource::Game(window)
+        } else {
+            CaptureSource::Window(window)
+        }
+    }
+
+    fn source_name(&self) -> &'static str {
+        match self {
+            CaptureSource::Monitor { .. } => "monitor_capture",
+            CaptureSource::Window(_) => "window_capture",
+            CaptureSource::Game(_) => "game_capture",
+        }
+    }
+}
+
+/// Builds the capture source and adds it to the scene
+pub(super) fn add_capture_source(
+    scene: &mut ObsSceneRef,
+    source: &CaptureSource,
+) -> anyhow::Result<ObsSourceRef> {
+    let name = source.source_name();
+    let source = match source {
+        CaptureSource::Monitor {
+            id,
+            name: monitor_name,
+        } => {
+            let sources = list_sources()?.monitors;
+            let index =
+                find_monitor(&sources, *id, monitor_name.as_deref()).context("No monitor found")?;
+
+            let matched = sources
+                .iter()
+                .any(|m| Some(m.id) == *id || Some(&m.name) == monitor_name.as_ref());
+            if (id.is_some() || monitor_name.is_some()) && !matched {
+                log::warn!(
+                    "Monitor {:?} ({:?}) not found, using {}",
+                    id,
+                    monitor_name,
+                    sources[index].name
+                );
+            }
+
+            let monitors = MonitorCaptureSourceBuilder::get_monitors()?;
+            let monitor = monitors.get(index).context("Monitor disappeared")?;
+            MonitorCaptureSourceBuilder::new(name)
+                .set_monitor(monitor)
+                .build()
+        }
+        CaptureSource::Window(window) => WindowCaptureSourceBuilder::new(name)
+            .set_window(window)
+            .build(),
+        CaptureSource::Game(window) => GameCaptureSourceBuilder::new(name)
+            .set_capture_mode(ObsGameCaptureMode::CaptureSpecificWindow)
+            .set_window(window)
+            .build(),
+    };
+
+    Ok(scene.add_source(source)?)
+}
+
 pub trait CaptureTrait {
+    /// Switches the capture to the given source. Sources of the same kind are updated in
+    /// place, otherwise the scene item is swapped.
+    fn switch_source(&mut self, source: CaptureSource) -> anyhow::Result<()>;
     fn switch_window(&mut self, window: WindowInfo) -> anyhow::Result<()>;
+    fn switch_game(&mut self, window: WindowInfo, hook: bool) -> anyhow::Result<()>;
+    /// Switches back to the capture target configured in the settings
+    fn reset_capture(&mut self) -> anyhow::Result<()>;
+    /// Switches back to the configured target if the capture is following the given game window
+    fn release_game(&mut self, window: &WindowInfo) -> anyhow::Result<()>;
+    fn capture_source(&self) -> &CaptureSource;
 }
 
 impl CaptureTrait for ObsManager {
-    fn switch_window(&mut self, window: WindowInfo) -> anyhow::Result<()> {
-        if self.capture_source.id() != "window_capture" {
-            bail!("Capture source is not window_capture");
+    fn switch_source(&mut self, source: CaptureSource) -> anyhow::Result<()> {
+        match (&self.capture.source, &source) {
+            (CaptureSource::Window(_), CaptureSource::Window(window)) => {
+                let updater = self
+                    .capture
+                    .obs_source
+                    .create_updater::<WindowCaptureSourceUpdater>();
+
+                updater.set_window(window).update();
+            }
+            (CaptureSource::Game(_), CaptureSource::Game(window)) => {
+                let updater = self
+                    .capture
+                    .obs_source
+                    .create_updater::<GameCaptureSourceUpdater>();
+
+                updater.set_window(window).update();
+            }
+            _ => {
+                // Adding the new source first so there is no black frame in between
+                let new_source = add_capture_source(&mut self.scene, &source)?;
+                let old_source = std::mem::replace(&mut self.capture.obs_source, new_source);
+                if let Err(e) = self.scene.remove_source(&old_source) {
+                    log::warn!("Couldn't remove old capture source: {:?}", e);
+                }
+            }
         }
 
-        let updater = self
-            .capture_source
-            .create_updater::<WindowCaptureSourceUpdater>();
+        log::debug!("Switched capture to {}", source.source_name());
+        self.capture.source = source;
+        self.capture.from_game = false;
+        Ok(())
+    }
+
+    fn switch_window(&mut self, window: WindowInfo) -> anyhow::Result<()> {
+        self.switch_source(CaptureSource::Window(window))
+    }
 
-        updater.set_window(&window).update();
+    fn switch_game(&mut self, window: WindowInfo, hook: bool) -> anyhow::Result<()> {
+        self.switch_source(CaptureSource::for_game(window, hook))?;
+        self.capture.from_game = true;
         Ok(())
     }
+
+    fn reset_capture(&mut self) -> anyhow::Result<()> {
+        let source = CaptureSource::from_target(&self.settings.capture)?;
+        self.switch_source(source)
+    }
+
+    fn release_game(&mut self, window: &WindowInfo) -> anyhow::Result<()> {
+        let captured = match &self.capture.source {
+            CaptureSource::Window(w) | CaptureSource::Game(w) => w,
+            CaptureSource::Monitor { .. } => return Ok(()),
+        };
+
+        if !self.capture.from_game || captured.obs_id != window.obs_id {
+            return Ok(());
+        }
+
+        self.reset_capture()
+    }
+
+    fn capture_source(&self) -> &CaptureSource {
+        &self.capture.source
+    }
+}
+
+/// The capture source in the main scene and what it is capturing
+pub(super) struct CaptureState {
+    pub source: CaptureSource,
+    pub obs_source: ObsSourceRef,
+    /// Whether the source was chosen because a game is running
+    pub from_game: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(id: u32, name: &str, is_primary: bool) -> MonitorSource {
+        MonitorSource {
+            id,
+            name: name.to_string(),
+            width: 1920,
+            height: 1080,
+            is_primary,
+        }
+    }
+
+    #[test]
+    fn find_monitor_fallbacks() {
+        let monitors = vec![
+            monitor(10, "DISPLAY1", false),
+            monitor(20, "DISPLAY2", true),
+        ];
+
+        assert_eq!(find_monitor(&monitors, Some(10), None), Some(0));
+        assert_eq!(find_monitor(&monitors, None, Some("DISPLAY2")), Some(1));
+        // Id wins over name
+        assert_eq!(find_monitor(&monitors, Some(10), Some("DISPLAY2")), Some(0));
+        // Missing monitors fall back to the primary one
+        assert_eq!(find_monitor(&monitors, Some(30), Some("DISPLAY3")), Some(1));
+        assert_eq!(find_monitor(&monitors[..1], Some(30), None), Some(0));
+        assert_eq!(find_monitor(&[], None, None), None);
+    }
 }
@@ -1,18 +1,31 @@
 mod capture;
+mod recording;
+mod replay;
 pub mod runtime;
+mod signal;
 
 pub use capture::*;
-use libobs_wrapper::{context::ObsContext, sources::ObsSourceRef};
+use libobs_wrapper::{context::ObsContext, outputs::ObsOutputRef, scenes::ObsSceneRef};
+pub use recording::*;
+pub use replay::*;
+
+use crate::{
+    core::settings::{ObsSettings, Settings},
+    utils::dir::get_clips_dir,
+};
 
 pub struct ObsManager {
     #[allow(dead_code)]
     ctx: ObsContext,
-    capture_source: ObsSourceRef,
+    scene: ObsSceneRef,
+    capture: CaptureState,
+    replay: ReplayBuffer<ObsReplayBackend>,
+    recording: Recording<ObsRecordingBackend>,
+    settings: ObsSettings,
 }
 
-use libobs_sources::windows::{MonitorCaptureSourceBuilder, WindowCaptureSourceBuilder};
 use libobs_wrapper::{
-    data::{ObsData, ObsObjectBuilder},
+    data::ObsData,
     encoders::ObsContextEncoders,
     enums::ObsLogLevel,
     logger::ObsLogger,
@@ -33,22 +46,36 @@ impl ObsLogger for LogLogger {
 }
 
 impl ObsManager {
-    fn initialize_obs() -> anyhow::Result<ObsManager> {
-        // Start the OBS context
-        let startup_info = StartupInfo::default().set_logger(Box::new(LogLogger {}));
-
-        let mut context = ObsContext::new(startup_info)?;
+    /// Creates an output with the configured video and audio encoders attached
+    fn create_output(
+        context: &mut ObsContext,
+        settings: &ObsSettings,
+        id: &str,
+        name: &str,
+    ) -> anyhow::Result<ObsOutputRef> {
+        let output_info = OutputInfo::new(id, name, None, None);
+        let mut output = context.output(output_info)?;
 
-        let output_name = "output";
-        let output_info = OutputInfo::new("ffmpeg_muxer", output_name, None, None);
+        Self::attach_encoders(&mut output, settings, name)?;
+        Ok(output)
+    }
 
-        let mut output = context.output(output_info)?;
+    /// Attaches new encoders to the output, replacing the old ones. The output must not be active.
+    fn attach_encoders(
+        output: &mut ObsOutputRef,
+        settings: &ObsSettings,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let video_encoder = settings
+            .video_encoder
+            .clone()
+            .unwrap_or_else(|| ObsContext::get_best_video_encoder().to_string());
 
         // Register the video encoder
         let video_settings = ObsData::new();
         let video_info = VideoEncoderInfo::new(
-            ObsContext::get_best_video_encoder(),
-            "video_encoder",
+            video_encoder,
+            format!("{}_video_encoder", name),
             Some(video_settings),
             None,
         );
@@ -59,34 +86,110 @@ impl ObsManager {
         // Register the audio encoder
         let audio_settings = ObsData::new();
 
-        let audio_info =
-            AudioEncoderInfo::new("ffmpeg_aac", "audio_encoder", Some(audio_settings), None);
+        let audio_info = AudioEncoderInfo::new(
+            settings.audio_encoder.clone(),
+            format!("{}_audio_encoder", name),
+            Some(audio_settings),
+            None,
+        );
 
         let audio_handler = ObsContext::get_audio_ptr()?;
         output.audio_encoder(audio_info, 0, audio_handler)?;
 
-        let mut scene = context.scene("Main Scene");
-        let mut window_capture = MonitorCaptureSourceBuilder::new("window_capture")
-            .set_monitor(&MonitorCaptureSourceBuilder::get_monitors().unwrap()[1]);
+        Ok(())
+    }
+
+    fn initialize_obs(settings: Settings) -> anyhow::Result<ObsManager> {
+        // Start the OBS context
+        let startup_info = StartupInfo::default().set_logger(Box::new(LogLogger {}));
 
-        let t = WindowCaptureSourceBuilder::get_windows(
-            libobs_window_helper::WindowSearchMode::IncludeMinimized,
-        )
-        .unwrap();
+        let mut context = ObsContext::new(startup_info)?;
+
+        let clips_dir = get_clips_dir()?;
+
+        let recording_output =
+            Self::create_output(&mut context, &settings.obs, "ffmpeg_muxer", "recording")?;
+        let recording = Recording::new(
+            ObsRecordingBackend::new(recording_output),
+            clips_dir.clone(),
+        );
 
-        let window = t.iter().find(|w| w.obs_id.to_lowercase().contains("code.exe")).unwrap();
-        println!("Found window: {:?}", window);
-        //window_capture = window_capture.set_window(window);
+        let replay_output = Self::create_output(
+            &mut context,
+            &settings.obs,
+            "replay_buffer",
+            "replay_buffer",
+        )?;
+        let mut replay = ReplayBuffer::new(ObsReplayBackend::new(replay_output), clips_dir);
+        replay.set_settings(settings.replay.clone());
 
-        let window_capture = scene.add_source(window_capture.build())?;
+        let mut scene = context.scene("Main Scene");
+        let source = CaptureSource::from_target(&settings.obs.capture)?;
+        let obs_source = add_capture_source(&mut scene, &source)?;
 
         Ok(ObsManager {
             ctx: context,
-            capture_source: window_capture,
+            scene,
+            capture: CaptureState {
+                source,
+                obs_source,
+                from_game: false,
+            },
+            replay,
+            recording,
+            settings: settings.obs,
         })
     }
 
+    /// Applies changed settings without restarting OBS. Encoder changes are only applied
+    /// if no output is active at the moment.
+    pub fn apply_settings(&mut self, settings: &Settings) -> anyhow::Result<()> {
+        self.replay.set_settings(settings.replay.clone());
+
+        let encoders_changed = self.settings.video_encoder != settings.obs.video_encoder
+            || self.settings.audio_encoder != settings.obs.audio_encoder;
+        let outputs_active = self.replay.state() != &ReplayState::Idle
+            || self.recording.state() != RecordingState::Idle;
+
+        if encoders_changed && outputs_active {
+            log::warn!("Can't change encoders while recording or buffering, keeping old ones");
+        } else if encoders_changed {
+            log::info!("Encoders changed, attaching new encoders to outputs");
+            Self::attach_encoders(
+                self.recording.backend_mut().output_mut(),
+                &settings.obs,
+                "recording",
+            )?;
+            Self::attach_encoders(
+                self.replay.backend_mut().output_mut(),
+                &settings.obs,
+                "replay_buffer",
+            )?;
+
+            self.settings.video_encoder = settings.obs.video_encoder.clone();
+            self.settings.audio_encoder = settings.obs.audio_encoder.clone();
+        }
+
+        let capture_changed = self.settings.capture != settings.obs.capture;
+        self.settings.capture = settings.obs.capture.clone();
+
+        // A running game takes precedence, the new target is used once it closes
+        if capture_changed && !self.capture.from_game {
+            self.reset_capture()?;
+        }
+
+        Ok(())
+    }
+
     pub fn context(&mut self) -> &mut ObsContext {
         &mut self.ctx
     }
+
+    pub fn replay(&mut self) -> &mut ReplayBuffer<ObsReplayBackend> {
+        &mut self.replay
+    }
+
+    pub fn recording(&mut self) -> &mut Recording<ObsRecordingBackend> {
+        &mut self.recording
+    }
 }
@@ -0,0 +1,328 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use libobs_wrapper::{data::ObsData, outputs::ObsOutputRef};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::signal::OutputSignal;
+
+#[derive(Type, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RecordingState {
+    Idle,
+    Recording,
+    Paused,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputStats {
+    pub bytes_written: u64,
+    pub dropped_frames: u32,
+    pub total_frames: u32,
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordingStatus {
+    pub state: RecordingState,
+    pub path: Option<String>,
+    /// Recorded time in milliseconds, paused time is not included
+    pub duration_ms: u32,
+    #[specta(type = f64)]
+    pub bytes_written: u64,
+    pub dropped_frames: u32,
+    pub total_frames: u32,
+}
+
+/// The part of the recording that actually talks to OBS, so the state machine
+/// can be tested without an OBS context
+pub trait RecordingBackend {
+    fn start(&mut self, path: &Path) -> anyhow::Result<()>;
+    fn stop(&mut self) -> anyhow::Result<()>;
+    fn pause(&mut self, paused: bool) -> anyhow::Result<()>;
+    fn stats(&self) -> OutputStats;
+    /// Whether the output finished writing a stopped recording since the last call
+    fn take_stopped(&mut self) -> bool;
+}
+
+pub struct Recording<B: RecordingBackend> {
+    backend: B,
+    directory: PathBuf,
+    state: RecordingState,
+    path: Option<PathBuf>,
+    /// The stopped recording OBS is still writing the end of
+    finishing: Option<PathBuf>,
+    started: Option<Instant>,
+    paused_at: Option<Instant>,
+    paused_total: Duration,
+}
+
+impl<B: RecordingBackend> Recording<B> {
+    pub fn new(backend: B, directory: PathBuf) -> Self {
+        Self {
+            backend,
+            directory,
+            state: RecordingState::Idle,
+            path: None,
+            finishing: None,
+            started: None,
+            paused_at: None,
+            paused_total: Duration::ZERO,
+        }
+    }
+
+    pub fn state(&self) -> RecordingState {
+        self.state
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Starts recording to a new file in the clips directory and returns its path
+    pub fn start(&mut self) -> anyhow::Result<PathBuf> {
+        if self.state != RecordingState::Idle {
+            bail!("Already recording");
+        }
+
+        let file_name = chrono::Local::now()
+            .format("Recording %Y-%m-%d %H-%M-%S.mp4")
+            .to_string();
+        let path = self.directory.join(file_name);
+
+        // A previous recording that took too long to finish
+        self.finishing = None;
+        self.backend.take_stopped();
+
+        self.backend.start(&path)?;
+        self.state = RecordingState::Recording;
+        self.path = Some(path.clone());
+        self.started = Some(Instant::now());
+        self.paused_at = None;
+        self.paused_total = Duration::ZERO;
+
+        Ok(path)
+    }
+
+    /// Stops the recording and returns the path of the file. OBS writes the end of the
+    /// file asynchronously, use `poll_finished` to check if it is complete.
+    pub fn stop(&mut self) -> anyhow::Result<PathBuf> {
+        if self.state == RecordingState::Idle {
+            bail!("Not recording");
+        }
+
+        self.backend.stop()?;
+        self.state = RecordingState::Idle;
+        self.started = None;
+        self.paused_at = None;
+
+        let path = self.path.take().context("Recording should have a path")?;
+        self.finishing = Some(path.clone());
+        Ok(path)
+    }
+
+    /// Returns the path of the stopped recording as soon as OBS finished writing it
+    pub fn poll_finished(&mut self) -> Option<PathBuf> {
+        if self.finishing.is_some() && self.backend.take_stopped() {
+            return self.finishing.take();
+        }
+
+        None
+    }
+
+    pub fn pause(&mut self) -> anyhow::Result<()> {
+        if self.state != RecordingState::Recording {
+            bail!("Not recording");
+        }
+
+        self.backend.pause(true)?;
+        self.state = RecordingState::Paused;
+        self.paused_at = Some(Instant::now());
+
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        if self.state != RecordingState::Paused {
+            bail!("Recording is not paused");
+        }
+
+        self.backend.pause(false)?;
+        self.state = RecordingState::Recording;
+        if let Some(paused_at) = self.paused_at.take() {
+            self.paused_total += paused_at.elapsed();
+        }
+
+        Ok(())
+    }
+
+    pub fn duration(&self) -> Duration {
+        let Some(started) = self.started else {
+            return Duration::ZERO;
+        };
+
+        let current_pause = self
+            .paused_at
+            .map(|p| p.elapsed())
+            .unwrap_or(Duration::ZERO);
+
+        started
+            .elapsed()
+            .saturating_sub(self.paused_total + current_pause)
+    }
+
+    pub fn status(&self) -> RecordingStatus {
+        let stats = if self.state == RecordingState::Idle {
+            OutputStats::default()
+        } else {
+            self.backend.stats()
+        };
+
+        RecordingStatus {
+            state: self.state,
+            path: self.path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            duration_ms: u32::try_from(self.duration().as_millis()).unwrap_or(u32::MAX),
+            bytes_written: stats.bytes_written,
+            dropped_frames: stats.dropped_frames,
+            total_frames: stats.total_frames,
+        }
+    }
+}
+
+pub struct ObsRecordingBackend {
+    // Declared first so it's disconnected before the output is released
+    stopped: OutputSignal,
+    output: ObsOutputRef,
+}
+
+impl ObsRecordingBackend {
+    pub fn new(output: ObsOutputRef) -> Self {
+        Self {
+            stopped: OutputSignal::connect(&output, c"stop"),
+            output,
+        }
+    }
+
+    pub fn output_mut(&mut self) -> &mut ObsOutputRef {
+        &mut self.output
+    }
+}
+
+impl RecordingBackend for ObsRecordingBackend {
+    fn start(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut data = ObsData::new();
+        data.set_string("path", path.to_string_lossy().to_string());
+
+        self.output
+            .update_settings(data)
+            .context("Updating recording settings")?;
+        self.output.start().context("Starting recording")?;
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        self.output.stop().context("Stopping recording")?;
+        Ok(())
+    }
+
+    fn pause(&mut self, paused: bool) -> anyhow::Result<()> {
+        let success = unsafe { libobs::obs_output_pause(self.output.as_ptr(), paused) };
+        if !success {
+            bail!("OBS could not pause the recording, the encoder may not support it");
+        }
+
+        Ok(())
+    }
+
+    fn stats(&self) -> OutputStats {
+        let ptr = self.output.as_ptr();
+        unsafe {
+            OutputStats {
+                bytes_written: libobs::obs_output_get_total_bytes(ptr),
+                dropped_frames: libobs::obs_output_get_frames_dropped(ptr).max(0) as u32,
+                total_frames: libobs::obs_output_get_total_frames(ptr).max(0) as u32,
+            }
+        }
+    }
+
+    fn take_stopped(&mut self) -> bool {
+        self.stopped.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeBackend {
+        running: bool,
+        paused: bool,
+        stopped: bool,
+    }
+
+    impl RecordingBackend for FakeBackend {
+        fn start(&mut self, _path: &Path) -> anyhow::Result<()> {
+            self.running = true;
+            Ok(())
+        }
+
+        fn stop(&mut self) -> anyhow::Result<()> {
+            self.running = false;
+            Ok(())
+        }
+
+        fn pause(&mut self, paused: bool) -> anyhow::Result<()> {
+            self.paused = paused;
+            Ok(())
+        }
+
+        fn stats(&self) -> OutputStats {
+            OutputStats {
+                bytes_written: 1024,
+                dropped_frames: 2,
+                total_frames: 60,
+            }
+        }
+
+        fn take_stopped(&mut self) -> bool {
+            std::mem::take(&mut self.stopped)
+        }
+    }
+
+    #[test]
+    fn start_pause_resume_stop() {
+        let mut rec = Recording::new(FakeBackend::default(), PathBuf::from("clips"));
+        assert!(rec.pause().is_err());
+        assert!(rec.stop().is_err());
+
+        let path = rec.start().unwrap();
+        assert!(path.starts_with("clips"));
+        assert!(rec.start().is_err());
+        assert!(rec.resume().is_err());
+
+        rec.pause().unwrap();
+        assert!(rec.backend.paused);
+        assert_eq!(rec.status().state, RecordingState::Paused);
+
+        rec.resume().unwrap();
+        assert!(!rec.backend.paused);
+
+        let status = rec.status();
+        assert_eq!(status.bytes_written, 1024);
+        assert_eq!(status.dropped_frames, 2);
+
+        assert_eq!(rec.stop().unwrap(), path);
+        assert!(!rec.backend.running);
+        assert_eq!(rec.status().bytes_written, 0);
+
+        // The file is only complete once the output signalled that it stopped
+        assert_eq!(rec.poll_finished(), None);
+        rec.backend.stopped = true;
+        assert_eq!(rec.poll_finished(), Some(path));
+        assert_eq!(rec.poll_finished(), None);
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    ffi::{c_char, c_void, CStr},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use anyhow::{bail, Context};
+use libobs_wrapper::{data::ObsData, outputs::ObsOutputRef};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::signal::OutputSignal;
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplaySettings {
+    /// How many seconds the replay buffer should keep
+    pub max_time_sec: u32,
+    /// Maximum size of the replay buffer in megabytes
+    pub max_size_mb: u32,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self {
+            max_time_sec: 30,
+            max_size_mb: 512,
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReplayState {
+    Idle,
+    Buffering,
+    Saving,
+    Error(String),
+}
+
+/// The part of the replay buffer that actually talks to OBS, so the state machine
+/// can be tested without an OBS context
+pub trait ReplayBackend {
+    fn start(&mut self, settings: &ReplaySettings, directory: &Path) -> anyhow::Result<()>;
+    fn stop(&mut self) -> anyhow::Result<()>;
+    /// Tells the backend to write the buffer to disk. This happens asynchronously,
+    /// use `take_saved` to check if it is done
+    fn save(&mut self) -> anyhow::Result<()>;
+    /// The replay that was written since the last call, `None` while it is still being written
+    fn take_saved(&mut self) -> anyhow::Result<Option<PathBuf>>;
+}
+
+pub struct ReplayBuffer<B: ReplayBackend> {
+    backend: B,
+    state: ReplayState,
+    settings: ReplaySettings,
+    directory: PathBuf,
+}
+
+impl<B: ReplayBackend> ReplayBuffer<B> {
+    pub fn new(backend: B, directory: PathBuf) -> Self {
+        Self {
+            backend,
+            state: ReplayState::Idle,
+            settings: ReplaySettings::default(),
+            directory,
+        }
+    }
+
+    pub fn state(&self) -> &ReplayState {
+        &self.state
+    }
+
+    pub fn settings(&self) -> &ReplaySettings {
+        &self.settings
+    }
+
+    /// Settings are used the next time the replay buffer is started
+    pub fn set_settings(&mut self, settings: ReplaySettings) {
+        self.settings = settings;
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn start(&mut self, settings: Option<ReplaySettings>) -> anyhow::Result<()> {
+        match self.state {
+            ReplayState::Buffering | ReplayState::Saving => {
+                bail!("Replay buffer is already running")
+            }
+            ReplayState::Error(_) => {
+                // The backend might still be running, so make sure it's stopped before restarting
+                let _ = self.backend.stop();
+            }
+            ReplayState::Idle => {}
+        }
+
+        if let Some(settings) = settings {
+            self.settings = settings;
+        }
+
+        if let Err(e) = self.backend.start(&self.settings, &self.directory) {
+            self.state = ReplayState::Error(e.to_string());
+            return Err(e);
+        }
+
+        self.state = ReplayState::Buffering;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        match self.state {
+            ReplayState::Idle => return Ok(()),
+            ReplayState::Saving => bail!("Can't stop replay buffer while saving"),
+            _ => {}
+        }
+
+        let r = self.backend.stop();
+        self.state = match &r {
+            Ok(_) => ReplayState::Idle,
+            Err(e) => ReplayState::Error(e.to_string()),
+        };
+
+        r
+    }
+
+    pub fn request_save(&mut self) -> anyhow::Result<()> {
+        if self.state != ReplayState::Buffering {
+            bail!("Replay buffer is not running");
+        }
+
+        // A save that finished after its request timed out must not be reported for this one
+        if let Err(e) = self.backend.take_saved() {
+            log::warn!("Couldn't check for earlier replays: {:?}", e);
+        }
+
+        if let Err(e) = self.backend.save() {
+            self.state = ReplayState::Error(e.to_string());
+            return Err(e);
+        }
+
+        self.state = ReplayState::Saving;
+        Ok(())
+    }
+
+    /// Returns the path of the saved replay as soon as the backend is done writing it
+    pub fn poll_saved(&mut self) -> anyhow::Result<Option<PathBuf>> {
+        if self.state != ReplayState::Saving {
+            bail!("No replay is being saved");
+        }
+
+        let saved = match self.backend.take_saved() {
+            Ok(saved) => saved,
+            Err(e) => {
+                self.state = ReplayState::Error(e.to_string());
+                return Err(e);
+            }
+        };
+
+        if saved.is_some() {
+            self.state = ReplayState::Buffering;
+        }
+
+        Ok(saved)
+    }
+
+    pub fn fail(&mut self, msg: String) {
+        self.state = ReplayState::Error(msg);
+    }
+}
+
+pub struct ObsReplayBackend {
+    // Declared first so it's disconnected before the output is released
+    saved: OutputSignal,
+    output: ObsOutputRef,
+}
+
+impl ObsReplayBackend {
+    pub fn new(output: ObsOutputRef) -> Self {
+        Self {
+            saved: OutputSignal::connect(&output, c"saved"),
+            output,
+        }
+    }
+
+    pub fn output_mut(&mut self) -> &mut ObsOutputRef {
+        &mut self.output
+    }
+
+    /// Calls the given procedure of the replay buffer output and returns the
+    /// value of `out_param` if it is a string
+    fn call_proc(&self, name: &CStr, out_param: Option<&CStr>) -> anyhow::Result<Option<String>> {
+        unsafe {
+            let handler = libobs::obs_output_get_proc_handler(self.output.as_ptr());
+            let mut data: libobs::calldata_t = std::mem::zeroed();
+
+            let called = libobs::proc_handler_call(handler, name.as_ptr(), &mut data);
+            let mut res = None;
+            if let (true, Some(out_param)) = (called, out_param) {
+                let mut out: *const c_char = ptr::null();
+                let found = libobs::calldata_get_data(
+                    &data,
+                    out_param.as_ptr(),
+                    &mut out as *mut _ as *mut c_void,
+                    std::mem::size_of::<*const c_char>(),
+                );
+
+                if found && !out.is_null() {
+                    res = Some(CStr::from_ptr(out).to_string_lossy().to_string());
+                }
+            }
+
+            libobs::bfree(data.stack as *mut c_void);
+            if !called {
+                bail!(
+                    "Replay buffer has no {} procedure, is it running?",
+                    name.to_string_lossy()
+                );
+            }
+
+            Ok(res)
+        }
+    }
+}
+
+impl ReplayBackend for ObsReplayBackend {
+    fn start(&mut self, settings: &ReplaySettings, directory: &Path) -> anyhow::Result<()> {
+        let mut data = ObsData::new();
+        data.set_string("directory", directory.to_string_lossy().to_string())
+            .set_string("format", "Replay %CCYY-%MM-%DD %hh-%mm-%ss")
+            .set_string("extension", "mp4")
+            .set_int("max_time_sec", settings.max_time_sec as i64)
+            .set_int("max_size_mb", settings.max_size_mb as i64);
+
+        self.output
+            .update_settings(data)
+            .context("Updating replay buffer settings")?;
+        self.output.start().context("Starting replay buffer")?;
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        self.output.stop().context("Stopping replay buffer")?;
+        Ok(())
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        self.call_proc(c"save", None).context("Saving replay")?;
+        Ok(())
+    }
+
+    fn take_saved(&mut self) -> anyhow::Result<Option<PathBuf>> {
+        if !self.saved.take() {
+            return Ok(None);
+        }
+
+        let path = self
+            .call_proc(c"get_last_replay", Some(c"path"))?
+            .filter(|p| !p.is_empty())
+            .context("Replay buffer didn't report the saved file")?;
+
+        Ok(Some(PathBuf::from(path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeBackend {
+        running: bool,
+        fail_start: bool,
+        fail_save: bool,
+        pending: Option<PathBuf>,
+        written: usize,
+        saved: Option<PathBuf>,
+    }
+
+    impl ReplayBackend for FakeBackend {
+        fn start(&mut self, _settings: &ReplaySettings, _directory: &Path) -> anyhow::Result<()> {
+            if self.fail_start {
+                bail!("Encoder not available");
+            }
+
+            self.running = true;
+            Ok(())
+        }
+
+        fn stop(&mut self) -> anyhow::Result<()> {
+            self.running = false;
+            Ok(())
+        }
+
+        fn save(&mut self) -> anyhow::Result<()> {
+            if self.fail_save {
+                bail!("Replay buffer has no save procedure");
+            }
+
+            self.pending = Some(PathBuf::from(format!("replay-{}.mp4", self.written)));
+            Ok(())
+        }
+
+        fn take_saved(&mut self) -> anyhow::Result<Option<PathBuf>> {
+            Ok(self.saved.take())
+        }
+    }
+
+    impl FakeBackend {
+        fn finish_write(&mut self) {
+            let p = self.pending.take().expect("Should have pending save");
+            self.written += 1;
+            self.saved = Some(p);
+        }
+    }
+
+    fn buffer() -> ReplayBuffer<FakeBackend> {
+        ReplayBuffer::new(FakeBackend::default(), PathBuf::from("clips"))
+    }
+
+    #[test]
+    fn start_save_stop() {
+        let mut replay = buffer();
+        assert_eq!(replay.state(), &ReplayState::Idle);
+
+        replay.start(None).unwrap();
+        assert_eq!(replay.state(), &ReplayState::Buffering);
+        assert!(replay.start(None).is_err());
+
+        replay.request_save().unwrap();
+        assert_eq!(replay.state(), &ReplayState::Saving);
+        assert_eq!(replay.poll_saved().unwrap(), None);
+        assert!(replay.stop().is_err());
+
+        replay.backend.finish_write();
+        assert_eq!(
+            replay.poll_saved().unwrap(),
+            Some(PathBuf::from("replay-0.mp4"))
+        );
+        assert_eq!(replay.state(), &ReplayState::Buffering);
+
+        // A second save must not report the first replay again
+        replay.request_save().unwrap();
+        assert_eq!(replay.poll_saved().unwrap(), None);
+        replay.backend.finish_write();
+        assert_eq!(
+            replay.poll_saved().unwrap(),
+            Some(PathBuf::from("replay-1.mp4"))
+        );
+
+        replay.stop().unwrap();
+        assert_eq!(replay.state(), &ReplayState::Idle);
+        assert!(!replay.backend.running);
+    }
+
+    #[test]
+    fn save_requires_buffering() {
+        let mut replay = buffer();
+        assert!(replay.request_save().is_err());
+        assert!(replay.poll_saved().is_err());
+    }
+
+    #[test]
+    fn start_error_and_recovery() {
+        let mut replay = buffer();
+        replay.backend.fail_start = true;
+
+        assert!(replay.start(None).is_err());
+        assert!(matches!(replay.state(), ReplayState::Error(_)));
+
+        replay.backend.fail_start = false;
+        let settings = ReplaySettings {
+            max_time_sec: 120,
+            max_size_mb: 1024,
+        };
+        replay.start(Some(settings.clone())).unwrap();
+        assert_eq!(replay.state(), &ReplayState::Buffering);
+        assert_eq!(replay.settings(), &settings);
+    }
+
+    #[test]
+    fn failed_save_request_is_an_error() {
+        let mut replay = buffer();
+        replay.start(None).unwrap();
+        replay.backend.fail_save = true;
+
+        assert!(replay.request_save().is_err());
+        assert!(matches!(replay.state(), ReplayState::Error(_)));
+        replay.stop().unwrap();
+    }
+
+    #[test]
+    fn late_saves_are_not_reported_again() {
+        let mut replay = buffer();
+        replay.start(None).unwrap();
+        replay.request_save().unwrap();
+        replay.fail("Timed out".to_string());
+        replay.backend.finish_write();
+
+        replay.stop().unwrap();
+        replay.start(None).unwrap();
+        replay.request_save().unwrap();
+        assert_eq!(replay.poll_saved().unwrap(), None);
+        replay.backend.finish_write();
+        assert_eq!(
+            replay.poll_saved().unwrap(),
+            Some(PathBuf::from("replay-1.mp4"))
+        );
+    }
+
+    #[test]
+    fn failed_save_can_be_stopped() {
+        let mut replay = buffer();
+        replay.start(None).unwrap();
+        replay.request_save().unwrap();
+        replay.fail("Timed out".to_string());
+
+        replay.stop().unwrap();
+        assert_eq!(replay.state(), &ReplayState::Idle);
+    }
+}
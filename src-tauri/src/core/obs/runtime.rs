@@ -1,15 +1,21 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail};
 use lazy_static::lazy_static;
 use log::debug;
 use rspc::ErrorCode;
 use tauri::async_runtime::JoinHandle;
-use tokio::sync::{
-    mpsc::{self, UnboundedSender},
-    oneshot, Mutex, RwLock,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedSender},
+        oneshot, Mutex, RwLock,
+    },
+    time::{self, Instant},
 };
 
+use crate::core::settings::{get_settings, SETTINGS_MANAGER};
+
 use super::ObsManager;
 
 pub struct RunObsFunc(pub Box<dyn FnOnce(&mut ObsManager) + Send>);
@@ -32,9 +38,10 @@ impl ObsRuntime {
 
         let (tx, mut rx) = mpsc::unbounded_channel::<RunObsFunc>();
 
+        let settings = get_settings().await;
         let (init_tx, init_rx) = oneshot::channel();
         let h = tauri::async_runtime::spawn_blocking(move || {
-            let ctx = ObsManager::initialize_obs();
+            let ctx = ObsManager::initialize_obs(settings);
             if let Err(e) = ctx {
                 let _ = init_tx.send(Err(e));
                 return;
@@ -73,10 +80,43 @@ pub async fn startup_obs() -> anyhow::Result<()> {
     debug!("Writing sender...");
     __OBS_RUNTIME_SENDER.write().await.replace(sender);
 
+    spawn_settings_listener().await;
+
     debug!("Done.");
     Ok(())
 }
 
+/// Applies settings changes to the running OBS instance
+async fn spawn_settings_listener() {
+    let rx = SETTINGS_MANAGER
+        .read()
+        .await
+        .as_ref()
+        .map(|mgr| mgr.subscribe());
+
+    let Some(mut rx) = rx else {
+        log::warn!("Settings manager not initialized, OBS won't react to settings changes");
+        return;
+    };
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let settings = match rx.recv().await {
+                Ok(settings) => settings,
+                Err(broadcast::error::RecvError::Lagged(_)) => get_settings().await,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let r = run_with_obs(move |mgr| mgr.apply_settings(&settings)).await;
+            match r {
+                Ok(Err(e)) => log::warn!("Couldn't apply settings to OBS: {:?}", e),
+                Err(e) => log::error!("Error running with OBS: {:?}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    });
+}
+
 pub async fn run_with_obs<
     T: Send + 'static,
     E: Send + 'static + Sync,
@@ -101,6 +141,37 @@ pub async fn run_with_obs<
     Ok(rx.await?)
 }
 
+/// How long OBS may take to write the end of a stopped recording
+const FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+const FINISH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stops the recording and waits until OBS finished writing the file, so it can be probed
+/// right away. The path is returned even if OBS takes too long.
+pub async fn stop_recording() -> anyhow::Result<PathBuf> {
+    let path = run_with_obs(|mgr| mgr.recording().stop()).await??;
+
+    let timeout_at = Instant::now() + FINISH_TIMEOUT;
+    loop {
+        let finished =
+            run_with_obs(|mgr| Ok::<_, anyhow::Error>(mgr.recording().poll_finished())).await??;
+        if finished.is_some() {
+            break;
+        }
+
+        if Instant::now() >= timeout_at {
+            log::warn!(
+                "Timed out waiting for {} to be written, it's indexed again on the next start",
+                path.display()
+            );
+            break;
+        }
+
+        time::sleep(FINISH_POLL_INTERVAL).await;
+    }
+
+    Ok(path)
+}
+
 pub async fn run_with_obs_rspc<
     T: Send + 'static,
     F: FnOnce(&mut ObsManager) -> Result<T, rspc::Error> + Send + 'static,
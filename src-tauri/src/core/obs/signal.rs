@@ -0,0 +1,61 @@
+use std::{
+    ffi::{c_void, CStr},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use libobs_wrapper::outputs::ObsOutputRef;
+
+/// Remembers that an output emitted a signal, e.g. `saved` of the replay buffer. OBS raises
+/// signals on its own threads, so they are only checked by polling `take`.
+pub(super) struct OutputSignal {
+    handler: *mut libobs::signal_handler_t,
+    name: &'static CStr,
+    raised: Box<AtomicBool>,
+}
+
+// The signal handler belongs to the output and libobs guards it with its own lock
+unsafe impl Send for OutputSignal {}
+
+unsafe extern "C" fn on_signal(param: *mut c_void, _data: *mut libobs::calldata_t) {
+    let raised = &*(param as *const AtomicBool);
+    raised.store(true, Ordering::SeqCst);
+}
+
+impl OutputSignal {
+    pub fn connect(output: &ObsOutputRef, name: &'static CStr) -> Self {
+        let raised = Box::new(AtomicBool::new(false));
+        unsafe {
+            let handler = libobs::obs_output_get_signal_handler(output.as_ptr());
+            libobs::signal_handler_connect(
+                handler,
+                name.as_ptr(),
+                Some(on_signal),
+                raised.as_ref() as *const AtomicBool as *mut c_void,
+            );
+
+            Self {
+                handler,
+                name,
+                raised,
+            }
+        }
+    }
+
+    /// Whether the signal was emitted since the last call
+    pub fn take(&self) -> bool {
+        self.raised.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for OutputSignal {
+    fn drop(&mut self) {
+        unsafe {
+            libobs::signal_handler_disconnect(
+                self.handler,
+                self.name.as_ptr(),
+                Some(on_signal),
+                self.raised.as_ref() as *const AtomicBool as *mut c_void,
+            );
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use libobs_window_helper::WindowInfo;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc::UnboundedReceiver, Mutex},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    core::{
+        game_detection::{GameEvent, WindowType},
+        settings::{get_settings, SETTINGS_MANAGER},
+    },
+    utils::util::write_atomic,
+};
+
+mod obs;
+pub use obs::ObsSessionActions;
+
+pub const SESSION_LOG_FILE: &str = "sessions.json";
+/// How often pending session stops are checked
+const TICK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Type, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SessionMode {
+    Disabled,
+    ReplayBuffer,
+    Recording,
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub mode: SessionMode,
+    /// How long a game has to be closed before the session ends, so restarts and
+    /// short window flapping don't create new sessions
+    pub debounce_secs: u32,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            mode: SessionMode::ReplayBuffer,
+            debounce_secs: 5,
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub game_id: String,
+    pub window_title: Option<String>,
+    pub mode: SessionMode,
+    /// RFC 3339 timestamps
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub clips: Vec<String>,
+}
+
+/// What a session does when it starts and stops, so the controller can be tested without OBS
+#[async_trait]
+pub trait SessionActions: Send + Sync {
+    async fn start(&self, mode: SessionMode) -> anyhow::Result<()>;
+    /// Returns the path of the recording if one was made
+    async fn stop(&self, mode: SessionMode) -> anyhow::Result<Option<PathBuf>>;
+    /// Called with the recording `stop` returned, before the session is added to the history
+    async fn recorded(&self, _path: &Path, _record: &SessionRecord) {}
+}
+
+/// What the controller decided to do. It's carried out by `apply` after the controller lock
+/// is released, so starting and stopping OBS doesn't block settings updates or routes.
+pub enum SessionStep {
+    Start(SessionMode),
+    /// The session was already taken out of the controller and is added to the history
+    /// once OBS stopped
+    Stop(SessionRecord),
+}
+
+struct ActiveSession {
+    record: SessionRecord,
+    /// Open windows of the game, splash screens and popups included. Titles change while
+    /// a window is open, so they are told apart by process and class.
+    windows: Vec<WindowInfo>,
+    /// Set when the last game window closed, the session ends at this point unless the
+    /// game reopens
+    stop_at: Option<Instant>,
+}
+
+fn same_window(a: &WindowInfo, b: &WindowInfo) -> bool {
+    a.pid == b.pid && a.class == b.class
+}
+
+pub struct SessionController<A: SessionActions> {
+    actions: Arc<A>,
+    settings: SessionSettings,
+    active: Option<ActiveSession>,
+    history: Vec<SessionRecord>,
+    log_file: Option<PathBuf>,
+}
+
+fn now_rfc3339() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+impl<A: SessionActions> SessionController<A> {
+    /// Creates a new controller, loading previous sessions from `log_file` if given
+    pub fn new(actions: A, settings: SessionSettings, log_file: Option<PathBuf>) -> Self {
+        let history = log_file
+            .as_ref()
+            .filter(|f| f.exists())
+            .and_then(|f| match Self::read_log(f) {
+                Ok(h) => Some(h),
+                Err(e) => {
+                    log::warn!("Couldn't read session log: {:?}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Self {
+            actions: Arc::new(actions),
+            settings,
+            active: None,
+            history,
+            log_file,
+        }
+    }
+
+    fn read_log(file: &Path) -> anyhow::Result<Vec<SessionRecord>> {
+        let raw = fs::read_to_string(file)?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    async fn write_log(&self) -> anyhow::Result<()> {
+        if let Some(file) = &self.log_file {
+            write_atomic(file, serde_json::to_string(&self.history)?)
+                .await
+                .context("Writing session log")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn actions(&self) -> Arc<A> {
+        self.actions.clone()
+    }
+
+    pub fn set_settings(&mut self, settings: SessionSettings) {
+        self.settings = settings;
+    }
+
+    pub fn current(&self) -> Option<&SessionRecord> {
+        self.active.as_ref().map(|a| &a.record)
+    }
+
+    pub fn history(&self) -> &[SessionRecord] {
+        &self.history
+    }
+
+    /// Adds a clip to the running session
+    pub fn record_clip(&mut self, path: &Path) {
+        if let Some(active) = self.active.as_mut() {
+            active.record.clips.push(path.to_string_lossy().to_string());
+        }
+    }
+
+    pub fn handle_event(&mut self, event: GameEvent, now: Instant) -> Option<SessionStep> {
+        match event {
+            GameEvent::Opened(WindowType::Game(game), window) => {
+                if let Some(active) = self.active.as_mut() {
+                    if active.record.game_id == game.id {
+                        if active.stop_at.take().is_some() {
+                            log::debug!("Game {} reopened, keeping session", game.id);
+                        }
+
+                        active.windows.push(window);
+                    } else {
+                        log::debug!(
+                            "Ignoring {}, session for {} is still running",
+                            game.id,
+                            active.record.game_id
+                        );
+                    }
+
+                    return None;
+                }
+
+                let mode = self.settings.mode;
+                if mode == SessionMode::Disabled {
+                    return None;
+                }
+
+                log::info!("Starting {:?} session for {}", mode, game.name);
+                self.active = Some(ActiveSession {
+                    record: SessionRecord {
+                        game_id: game.id,
+                        window_title: window.title.clone(),
+                        mode,
+                        started_at: now_rfc3339(),
+                        ended_at: None,
+                        clips: vec![],
+                    },
+                    windows: vec![window],
+                    stop_at: None,
+                });
+
+                return Some(SessionStep::Start(mode));
+            }
+            GameEvent::Closed(window) => {
+                let Some(active) = self.active.as_mut() else {
+                    return None;
+                };
+
+                // Windows of other processes or games aren't part of the session
+                let Some(i) = active.windows.iter().position(|w| same_window(w, &window)) else {
+                    return None;
+                };
+
+                active.windows.remove(i);
+                if active.windows.is_empty() && active.stop_at.is_none() {
+                    let debounce = Duration::from_secs(self.settings.debounce_secs as u64);
+                    active.stop_at = Some(now + debounce);
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    /// Ends the session if its game has been closed for longer than the debounce time
+    pub fn tick(&mut self, now: Instant) -> Option<SessionStep> {
+        let should_stop = self
+            .active
+            .as_ref()
+            .and_then(|a| a.stop_at)
+            .is_some_and(|stop_at| stop_at <= now);
+
+        if should_stop {
+            return self.end_session();
+        }
+
+        None
+    }
+
+    pub fn end_session(&mut self) -> Option<SessionStep> {
+        let active = self.active.take()?;
+        log::info!("Ending session for {}", active.record.game_id);
+
+        Some(SessionStep::Stop(active.record))
+    }
+
+    /// Drops the session again if OBS couldn't be started for it
+    fn start_failed(&mut self) {
+        self.active = None;
+    }
+
+    async fn finish_session(&mut self, record: SessionRecord) -> anyhow::Result<()> {
+        self.history.push(record);
+        self.write_log().await
+    }
+}
+
+/// Lets `decide` pick the next step of the controller and carries it out. The lock is only
+/// held while deciding and while storing the result, never while OBS is busy.
+pub async fn apply<A: SessionActions>(
+    controller: &Mutex<Option<SessionController<A>>>,
+    decide: impl FnOnce(&mut SessionController<A>) -> Option<SessionStep>,
+) -> anyhow::Result<()> {
+    let (actions, step) = {
+        let mut guard = controller.lock().await;
+        let Some(ctrl) = guard.as_mut() else {
+            return Ok(());
+        };
+
+        match decide(ctrl) {
+            Some(step) => (ctrl.actions(), step),
+            None => return Ok(()),
+        }
+    };
+
+    match step {
+        SessionStep::Start(mode) => {
+            if let Err(e) = actions.start(mode).await {
+                if let Some(ctrl) = controller.lock().await.as_mut() {
+                    ctrl.start_failed();
+                }
+
+                return Err(e);
+            }
+        }
+        SessionStep::Stop(mut record) => {
+            let stopped = actions.stop(record.mode).await;
+            if let Ok(Some(path)) = &stopped {
+                actions.recorded(path, &record).await;
+                record.clips.push(path.to_string_lossy().to_string());
+            }
+
+            record.ended_at = Some(now_rfc3339());
+            if let Some(ctrl) = controller.lock().await.as_mut() {
+                ctrl.finish_session(record).await?;
+            }
+
+            stopped?;
+        }
+    }
+
+    Ok(())
+}
+
+lazy_static! {
+    pub static ref SESSION_CONTROLLER: Arc<Mutex<Option<SessionController<ObsSessionActions>>>> =
+        Arc::new(Mutex::new(None));
+}
+
+/// Feeds game events into the global controller and ends sessions once their debounce time passed.
+/// Stops when the sender side of `rx` is dropped.
+pub fn spawn_session_thread(mut rx: UnboundedReceiver<GameEvent>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            let event = select! {
+                event = rx.recv() => match event {
+                    Some(event) => Some(event),
+                    None => break,
+                },
+                _ = interval.tick() => None,
+            };
+
+            let r = match event {
+                Some(event) => {
+                    apply(&SESSION_CONTROLLER, |ctrl| {
+                        ctrl.handle_event(event, Instant::now())
+                    })
+                    .await
+                }
+                None => apply(&SESSION_CONTROLLER, |ctrl| ctrl.tick(Instant::now())).await,
+            };
+
+            if let Err(e) = r {
+                log::error!("Error in session controller: {:?}", e);
+            }
+        }
+    })
+}
+
+/// Keeps the settings of the global controller up to date
+pub async fn spawn_settings_listener() {
+    let rx = SETTINGS_MANAGER
+        .read()
+        .await
+        .as_ref()
+        .map(|mgr| mgr.subscribe());
+
+    let Some(mut rx) = rx else {
+        log::warn!("Settings manager not initialized, sessions won't react to settings changes");
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let settings = match rx.recv().await {
+                Ok(settings) => settings,
+                Err(broadcast::error::RecvError::Lagged(_)) => get_settings().await,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            if let Some(controller) = SESSION_CONTROLLER.lock().await.as_mut() {
+                controller.set_settings(settings.session);
+            }
+        }
+    });
+}
+
+/// Adds a clip to the running session, if there is one, and returns that session
+pub async fn record_clip(path: &Path) -> Option<SessionRecord> {
+    let mut controller = SESSION_CONTROLLER.lock().await;
+    let controller = controller.as_mut()?;
+
+    controller.record_clip(path);
+    controller.current().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use serde_json::json;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::core::game_detection::DetectedGame;
+
+    #[derive(Default, Clone)]
+    struct FakeActions {
+        calls: Arc<StdMutex<Vec<String>>>,
+        /// Makes `stop` wait until notified, like OBS finishing a recording
+        stop_gate: Option<Arc<Notify>>,
+    }
+
+    #[async_trait]
+    impl SessionActions for FakeActions {
+        async fn start(&self, mode: SessionMode) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(format!("start {:?}", mode));
+            Ok(())
+        }
+
+        async fn stop(&self, mode: SessionMode) -> anyhow::Result<Option<PathBuf>> {
+            if let Some(gate) = &self.stop_gate {
+                gate.notified().await;
+            }
+
+            self.calls.lock().unwrap().push(format!("stop {:?}", mode));
+            Ok((mode == SessionMode::Recording).then(|| PathBuf::from("recording.mp4")))
+        }
+    }
+
+    fn window(pid: u32, exe: &str) -> WindowInfo {
+        serde_json::from_value(json!({
+            "full_exe": exe,
+            "obs_id": format!("Game:Class:{}", exe),
+            "pid": pid,
+            "title": "Game",
+            "is_game": true,
+        }))
+        .unwrap()
+    }
+
+    fn opened(pid: u32, exe: &str) -> GameEvent {
+        let game = DetectedGame {
+            id: exe.to_string(),
+            name: exe.to_string(),
+            aliases: vec![],
+            hook: false,
+        };
+
+        GameEvent::Opened(WindowType::Game(game), window(pid, exe))
+    }
+
+    type Ctrl = Mutex<Option<SessionController<FakeActions>>>;
+
+    fn controller_with(mode: SessionMode, actions: FakeActions) -> (Ctrl, FakeActions) {
+        let settings = SessionSettings {
+            mode,
+            debounce_secs: 5,
+        };
+
+        (
+            Mutex::new(Some(SessionController::new(
+                actions.clone(),
+                settings,
+                None,
+            ))),
+            actions,
+        )
+    }
+
+    fn controller(mode: SessionMode) -> (Ctrl, FakeActions) {
+        controller_with(mode, FakeActions::default())
+    }
+
+    async fn event(ctrl: &Ctrl, event: GameEvent, now: Instant) {
+        apply(ctrl, |c| c.handle_event(event, now)).await.unwrap();
+    }
+
+    async fn tick(ctrl: &Ctrl, now: Instant) {
+        apply(ctrl, |c| c.tick(now)).await.unwrap();
+    }
+
+    async fn current(ctrl: &Ctrl) -> Option<SessionRecord> {
+        ctrl.lock().await.as_ref().unwrap().current().cloned()
+    }
+
+    async fn history(ctrl: &Ctrl) -> Vec<SessionRecord> {
+        ctrl.lock().await.as_ref().unwrap().history().to_vec()
+    }
+
+    #[tokio::test]
+    async fn session_lifecycle() {
+        let (ctrl, actions) = controller(SessionMode::ReplayBuffer);
+        let t0 = Instant::now();
+
+        event(&ctrl, opened(1, "game.exe"), t0).await;
+        assert_eq!(current(&ctrl).await.unwrap().game_id, "game.exe");
+
+        ctrl.lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .record_clip(Path::new("clip.mp4"));
+        event(&ctrl, GameEvent::Closed(window(1, "game.exe")), t0).await;
+
+        tick(&ctrl, t0 + Duration::from_secs(4)).await;
+        assert!(current(&ctrl).await.is_some());
+
+        tick(&ctrl, t0 + Duration::from_secs(5)).await;
+        assert!(current(&ctrl).await.is_none());
+
+        let history = history(&ctrl).await;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].clips, vec!["clip.mp4".to_string()]);
+        assert!(history[0].ended_at.is_some());
+        assert_eq!(
+            *actions.calls.lock().unwrap(),
+            vec!["start ReplayBuffer", "stop ReplayBuffer"]
+        );
+    }
+
+    #[tokio::test]
+    async fn debounces_flapping() {
+        let (ctrl, actions) = controller(SessionMode::ReplayBuffer);
+        let t0 = Instant::now();
+
+        event(&ctrl, opened(1, "game.exe"), t0).await;
+        event(&ctrl, GameEvent::Closed(window(1, "game.exe")), t0).await;
+        // Game restarted with a new process before the debounce time passed
+        event(&ctrl, opened(2, "game.exe"), t0 + Duration::from_secs(2)).await;
+        tick(&ctrl, t0 + Duration::from_secs(10)).await;
+
+        assert!(current(&ctrl).await.is_some());
+        assert_eq!(*actions.calls.lock().unwrap(), vec!["start ReplayBuffer"]);
+
+        // Closing the old process again must not end the session of the new one
+        event(
+            &ctrl,
+            GameEvent::Closed(window(1, "game.exe")),
+            t0 + Duration::from_secs(11),
+        )
+        .await;
+        tick(&ctrl, t0 + Duration::from_secs(20)).await;
+        assert!(current(&ctrl).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn ends_when_the_last_window_closes() {
+        let (ctrl, _) = controller(SessionMode::ReplayBuffer);
+        let t0 = Instant::now();
+
+        let splash = |mut w: WindowInfo| {
+            w.class = Some("Splash".to_string());
+            w
+        };
+        let GameEvent::Opened(game, main) = opened(1, "game.exe") else {
+            unreachable!()
+        };
+
+        event(&ctrl, GameEvent::Opened(game.clone(), main.clone()), t0).await;
+        event(&ctrl, GameEvent::Opened(game, splash(main.clone())), t0).await;
+
+        // The splash screen closing doesn't mean the game did
+        event(&ctrl, GameEvent::Closed(splash(main.clone())), t0).await;
+        tick(&ctrl, t0 + Duration::from_secs(10)).await;
+        assert!(current(&ctrl).await.is_some());
+
+        event(&ctrl, GameEvent::Closed(main), t0 + Duration::from_secs(10)).await;
+        tick(&ctrl, t0 + Duration::from_secs(15)).await;
+        assert!(current(&ctrl).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn one_session_at_a_time() {
+        let (ctrl, actions) = controller(SessionMode::Recording);
+        let t0 = Instant::now();
+
+        event(&ctrl, opened(1, "game.exe"), t0).await;
+        event(&ctrl, opened(2, "other.exe"), t0).await;
+        assert_eq!(current(&ctrl).await.unwrap().game_id, "game.exe");
+
+        apply(&ctrl, |c| c.end_session()).await.unwrap();
+        assert_eq!(
+            history(&ctrl).await[0].clips,
+            vec!["recording.mp4".to_string()]
+        );
+        assert_eq!(
+            *actions.calls.lock().unwrap(),
+            vec!["start Recording", "stop Recording"]
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_mode_does_nothing() {
+        let (ctrl, actions) = controller(SessionMode::Disabled);
+
+        event(&ctrl, opened(1, "game.exe"), Instant::now()).await;
+        event(
+            &ctrl,
+            GameEvent::Opened(WindowType::Window, window(3, "explorer.exe")),
+            Instant::now(),
+        )
+        .await;
+
+        assert!(current(&ctrl).await.is_none());
+        assert!(actions.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lock_is_free_while_obs_stops() {
+        let gate = Arc::new(Notify::new());
+        let (ctrl, actions) = controller_with(
+            SessionMode::Recording,
+            FakeActions {
+                stop_gate: Some(gate.clone()),
+                ..Default::default()
+            },
+        );
+        let ctrl = Arc::new(ctrl);
+
+        event(&ctrl, opened(1, "game.exe"), Instant::now()).await;
+        let stopping = tokio::spawn({
+            let ctrl = ctrl.clone();
+            async move { apply(&ctrl, |c| c.end_session()).await }
+        });
+        tokio::task::yield_now().await;
+
+        // Settings updates and routes go through while OBS is still stopping
+        let updated = tokio::time::timeout(Duration::from_secs(1), async {
+            let mut guard = ctrl.lock().await;
+            guard
+                .as_mut()
+                .unwrap()
+                .set_settings(SessionSettings::default());
+            guard.as_ref().unwrap().history().len()
+        })
+        .await
+        .expect("Controller should not be locked");
+        assert_eq!(updated, 0);
+
+        gate.notify_one();
+        stopping.await.unwrap().unwrap();
+        assert_eq!(history(&ctrl).await.len(), 1);
+        assert_eq!(
+            *actions.calls.lock().unwrap(),
+            vec!["start Recording", "stop Recording"]
+        );
+    }
+}
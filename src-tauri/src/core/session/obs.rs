@@ -0,0 +1,48 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use crate::core::{
+    clips::{index_clip, ClipKind, NewClip},
+    obs::runtime::{run_with_obs, stop_recording},
+};
+
+use super::{SessionActions, SessionMode, SessionRecord};
+
+/// Starts and stops the replay buffer or recording of the OBS runtime
+pub struct ObsSessionActions;
+
+#[async_trait]
+impl SessionActions for ObsSessionActions {
+    async fn start(&self, mode: SessionMode) -> anyhow::Result<()> {
+        match mode {
+            SessionMode::Disabled => Ok(()),
+            SessionMode::ReplayBuffer => run_with_obs(|mgr| mgr.replay().start(None)).await?,
+            SessionMode::Recording => {
+                run_with_obs(|mgr| mgr.recording().start().map(|_| ())).await?
+            }
+        }
+    }
+
+    async fn stop(&self, mode: SessionMode) -> anyhow::Result<Option<PathBuf>> {
+        match mode {
+            SessionMode::Disabled => Ok(None),
+            SessionMode::ReplayBuffer => {
+                run_with_obs(|mgr| mgr.replay().stop()).await??;
+                Ok(None)
+            }
+            SessionMode::Recording => stop_recording().await.map(Some),
+        }
+    }
+
+    async fn recorded(&self, path: &Path, record: &SessionRecord) {
+        index_clip(NewClip {
+            path: path.to_path_buf(),
+            kind: ClipKind::Recording,
+            game_id: Some(record.game_id.clone()),
+            window_title: record.window_title.clone(),
+            created_at: None,
+        })
+        .await;
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+
+use super::SETTINGS_VERSION;
+
+type Migration = fn(Value) -> anyhow::Result<Value>;
+
+/// Migrations indexed by the version they migrate from
+const MIGRATIONS: [Migration; SETTINGS_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Version 0 was just the plain settings object without a version wrapper
+fn v0_to_v1(value: Value) -> anyhow::Result<Value> {
+    Ok(json!({
+        "version": 1,
+        "settings": value,
+    }))
+}
+
+/// The monitor index was replaced by a capture target. Indices aren't stable between
+/// machines and restarts, so we just fall back to the primary monitor.
+fn v1_to_v2(mut value: Value) -> anyhow::Result<Value> {
+    if let Some(obs) = value["settings"]["obs"].as_object_mut() {
+        if let Some(index) = obs.remove("monitor_index") {
+            log::info!(
+                "Dropping monitor index {} in favor of capture target",
+                index
+            );
+        }
+    }
+
+    value["version"] = json!(2);
+    Ok(value)
+}
+
+/// Migrates the given settings file to the latest version and returns whether anything changed
+pub fn migrate(mut value: Value) -> anyhow::Result<(Value, bool)> {
+    let mut version = match value.get("version") {
+        Some(v) => v.as_u64().context("Settings version is not a number")? as u32,
+        None => 0,
+    };
+
+    if version > SETTINGS_VERSION {
+        bail!(
+            "Settings file is from a newer version of Clipture ({} > {})",
+            version,
+            SETTINGS_VERSION
+        );
+    }
+
+    let migrated = version < SETTINGS_VERSION;
+    while version < SETTINGS_VERSION {
+        log::debug!("Migrating settings from version {}", version);
+        value = MIGRATIONS[version as usize](value)?;
+        version += 1;
+    }
+
+    Ok((value, migrated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_unversioned() {
+        let (value, migrated) = migrate(json!({ "obs": { "monitor_index": 2 } })).unwrap();
+
+        assert!(migrated);
+        assert_eq!(value["version"], SETTINGS_VERSION);
+        assert!(value["settings"]["obs"].get("monitor_index").is_none());
+    }
+
+    #[test]
+    fn keeps_current() {
+        let current = json!({ "version": SETTINGS_VERSION, "settings": {} });
+        let (value, migrated) = migrate(current.clone()).unwrap();
+
+        assert!(!migrated);
+        assert_eq!(value, current);
+    }
+
+    #[test]
+    fn rejects_newer() {
+        assert!(migrate(json!({ "version": SETTINGS_VERSION + 1, "settings": {} })).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::sync::{broadcast, RwLock};
+
+use crate::{
+    core::{
+        obs::{CaptureTarget, ReplaySettings},
+        session::SessionSettings,
+    },
+    utils::{
+        consts::{set_clipture_base_url, CLIPTURE_BASE_URL},
+        util::write_atomic,
+    },
+};
+
+mod migrations;
+
+pub const SETTINGS_FILE: &str = "settings.json";
+/// Bump this and add a migration in `migrations.rs` whenever the settings change in a breaking way
+pub const SETTINGS_VERSION: u32 = 2;
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObsSettings {
+    /// Video encoder id, uses the best available encoder if not set
+    pub video_encoder: Option<String>,
+    pub audio_encoder: String,
+    /// What is captured when no game is running
+    pub capture: CaptureTarget,
+}
+
+impl Default for ObsSettings {
+    fn default() -> Self {
+        Self {
+            video_encoder: None,
+            audio_encoder: "ffmpeg_aac".to_string(),
+            capture: CaptureTarget::default(),
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameDetectionSettings {
+    /// How often the game detection data should be fetched again
+    pub refresh_interval_secs: u32,
+}
+
+impl Default for GameDetectionSettings {
+    fn default() -> Self {
+        Self {
+            refresh_interval_secs: 60 * 60 * 24,
+        }
+    }
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiSettings {
+    pub base_url: String,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        Self {
+            base_url: CLIPTURE_BASE_URL.to_string(),
+        }
+    }
+}
+
+#[derive(Type, Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub obs: ObsSettings,
+    pub replay: ReplaySettings,
+    pub session: SessionSettings,
+    pub game_detection: GameDetectionSettings,
+    pub api: ApiSettings,
+}
+
+impl Settings {
+    /// Rejects values the rest of the app can't work with, so they never get persisted
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.game_detection.refresh_interval_secs == 0 {
+            bail!("Game detection refresh interval must be at least one second");
+        }
+        if self.replay.max_time_sec == 0 {
+            bail!("Replay buffer length must be at least one second");
+        }
+        if self.replay.max_size_mb == 0 {
+            bail!("Replay buffer size must be at least one megabyte");
+        }
+        if self.obs.audio_encoder.trim().is_empty() {
+            bail!("Audio encoder must not be empty");
+        }
+
+        match tauri::Url::parse(&self.api.base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => bail!("API base url must be a http(s) url"),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SettingsFile {
+    version: u32,
+    settings: Settings,
+}
+
+pub struct SettingsManager {
+    path: PathBuf,
+    settings: RwLock<Settings>,
+    tx: broadcast::Sender<Settings>,
+}
+
+impl SettingsManager {
+    pub async fn new(data_dir: &Path) -> anyhow::Result<Self> {
+        let path = data_dir.join(SETTINGS_FILE);
+
+        let settings = if path.exists() {
+            let raw = fs::read_to_string(&path).context("Reading settings file")?;
+            let value =
+                serde_json::from_str::<serde_json::Value>(&raw).context("Parsing settings file")?;
+
+            let (value, migrated) = migrations::migrate(value)?;
+            let file =
+                serde_json::from_value::<SettingsFile>(value).context("Deserializing settings")?;
+
+            if migrated {
+                log::info!("Migrated settings to version {}", SETTINGS_VERSION);
+                Self::write(&path, &file.settings).await?;
+            }
+
+            file.settings
+        } else {
+            let settings = Settings::default();
+            fs::create_dir_all(data_dir)?;
+            Self::write(&path, &settings).await?;
+
+            settings
+        };
+
+        set_clipture_base_url(&settings.api.base_url);
+        let (tx, _) = broadcast::channel(16);
+
+        Ok(Self {
+            path,
+            settings: RwLock::new(settings),
+            tx,
+        })
+    }
+
+    async fn write(path: &Path, settings: &Settings) -> anyhow::Result<()> {
+        let file = SettingsFile {
+            version: SETTINGS_VERSION,
+            settings: settings.clone(),
+        };
+
+        write_atomic(path, serde_json::to_string_pretty(&file)?)
+            .await
+            .context("Writing settings file")?;
+        Ok(())
+    }
+
+    pub async fn get(&self) -> Settings {
+        self.settings.read().await.clone()
+    }
+
+    pub async fn set(&self, settings: Settings) -> anyhow::Result<()> {
+        settings.validate()?;
+
+        let mut guard = self.settings.write().await;
+        if *guard == settings {
+            return Ok(());
+        }
+
+        Self::write(&self.path, &settings).await?;
+        set_clipture_base_url(&settings.api.base_url);
+        *guard = settings.clone();
+
+        // No one listening is fine
+        let _ = self.tx.send(settings);
+        Ok(())
+    }
+
+    pub async fn reset(&self) -> anyhow::Result<Settings> {
+        let settings = Settings::default();
+        self.set(settings.clone()).await?;
+
+        Ok(settings)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Settings> {
+        self.tx.subscribe()
+    }
+}
+
+lazy_static! {
+    pub static ref SETTINGS_MANAGER: Arc<RwLock<Option<SettingsManager>>> =
+        Arc::new(RwLock::new(None));
+}
+
+/// Returns the current settings or the defaults if the settings manager is not initialized yet
+pub async fn get_settings() -> Settings {
+    match SETTINGS_MANAGER.read().await.as_ref() {
+        Some(mgr) => mgr.get().await,
+        None => Settings::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_settings() {
+        assert!(Settings::default().validate().is_ok());
+
+        let mut settings = Settings::default();
+        settings.game_detection.refresh_interval_secs = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = Settings::default();
+        settings.replay.max_time_sec = 0;
+        assert!(settings.validate().is_err());
+
+        let mut settings = Settings::default();
+        settings.api.base_url = "clipture.example".to_string();
+        assert!(settings.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_are_not_persisted() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("clipture-settings-{}", uuid::Uuid::new_v4()));
+        let mgr = SettingsManager::new(&dir).await?;
+
+        let mut settings = Settings::default();
+        settings.game_detection.refresh_interval_secs = 0;
+        assert!(mgr.set(settings).await.is_err());
+        assert_eq!(mgr.get().await, Settings::default());
+
+        let file: SettingsFile =
+            serde_json::from_str(&fs::read_to_string(dir.join(SETTINGS_FILE))?)?;
+        assert_eq!(file.settings, Settings::default());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
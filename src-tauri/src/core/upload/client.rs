@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use reqwest::{header, StatusCode};
+
+use crate::{
+    core::api::{ApiClient, AuthExpired},
+    json_typings::clipture_api::clip::upload,
+};
+
+/// Talks to the upload endpoints of the Clipture API:
+///
+/// - `POST /api/clip/upload` creates an upload and returns its id
+/// - `GET /api/clip/upload/<id>` returns how many bytes the server already has
+/// - `PUT /api/clip/upload/<id>` stores the chunk given by the `Content-Range` header
+/// - `POST /api/clip/upload/<id>/complete` turns the upload into a clip
+/// - `DELETE /api/clip/upload/<id>` discards the upload
+#[derive(Clone)]
+pub struct UploadClient {
+    api: ApiClient,
+    /// Delay before the first retry of a chunk, doubled for every further retry
+    pub retry_delay: Duration,
+}
+
+/// Whether a failed request is worth retrying
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+impl UploadClient {
+    pub fn new(api: ApiClient) -> Self {
+        Self {
+            api,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn path(path: &str) -> String {
+        format!("/api/clip/upload{}", path)
+    }
+
+    pub async fn create(
+        &self,
+        req: &upload::CreateRequest,
+    ) -> anyhow::Result<upload::CreateResponse> {
+        let res = self
+            .api
+            .send(self.api.post(&Self::path("")).json(req))
+            .await?
+            .error_for_status()?;
+
+        Ok(res
+            .json()
+            .await
+            .context("Parsing upload creation response")?)
+    }
+
+    pub async fn status(&self, upload_id: &str) -> anyhow::Result<upload::StatusResponse> {
+        let res = self
+            .api
+            .send(self.api.get(&Self::path(&format!("/{}", upload_id))))
+            .await?
+            .error_for_status()?;
+
+        Ok(res.json().await.context("Parsing upload status")?)
+    }
+
+    /// Sends a single chunk starting at `offset`. Errors are `ChunkError::Transient`
+    /// if sending it again might succeed.
+    pub async fn put_chunk(
+        &self,
+        upload_id: &str,
+        offset: u64,
+        total: u64,
+        chunk: Bytes,
+    ) -> Result<(), ChunkError> {
+        let end = offset + chunk.len() as u64 - 1;
+        let req = self
+            .api
+            .put(&Self::path(&format!("/{}", upload_id)))
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, end, total),
+            )
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(chunk);
+
+        let res = match self.api.send(req).await {
+            Ok(res) => res,
+            Err(e) if e.is::<AuthExpired>() => return Err(ChunkError::Fatal(e)),
+            Err(e) => return Err(ChunkError::Transient(e)),
+        };
+
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let err = anyhow::anyhow!("Uploading chunk failed with {}", status);
+        if is_transient(status) {
+            Err(ChunkError::Transient(err))
+        } else {
+            Err(ChunkError::Fatal(err))
+        }
+    }
+
+    pub async fn complete(&self, upload_id: &str) -> anyhow::Result<upload::CompleteResponse> {
+        let res = self
+            .api
+            .send(
+                self.api
+                    .post(&Self::path(&format!("/{}/complete", upload_id))),
+            )
+            .await?
+            .error_for_status()?;
+
+        Ok(res
+            .json()
+            .await
+            .context("Parsing upload completion response")?)
+    }
+
+    pub async fn abort(&self, upload_id: &str) -> anyhow::Result<()> {
+        self.api
+            .send(self.api.delete(&Self::path(&format!("/{}", upload_id))))
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ChunkError {
+    Transient(anyhow::Error),
+    Fatal(anyhow::Error),
+}
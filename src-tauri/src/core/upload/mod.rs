@@ -0,0 +1,743 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context};
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+    select,
+    sync::{broadcast, Mutex, Notify, RwLock},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    core::{api::ApiClient, clips::Clip},
+    json_typings::clipture_api::clip::upload,
+    utils::util::write_atomic,
+};
+
+mod client;
+use client::{ChunkError, UploadClient};
+
+pub const UPLOADS_FILE: &str = "uploads.json";
+/// Used when the server doesn't tell us which chunk size it wants
+const DEFAULT_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+/// Chunks are held in memory while they are sent, the server can't ask for more than this
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+/// How often a chunk is retried before the upload fails
+const MAX_RETRIES: u32 = 5;
+const PROGRESS_CAPACITY: usize = 64;
+
+lazy_static! {
+    pub static ref UPLOAD_MANAGER: Arc<RwLock<Option<Arc<UploadManager>>>> =
+        Arc::new(RwLock::new(None));
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UploadState {
+    Queued,
+    Uploading,
+    Paused,
+    Done { url: String },
+    Failed(String),
+}
+
+#[derive(Type, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadEntry {
+    pub id: String,
+    pub clip_id: u32,
+    pub path: String,
+    pub game_id: Option<String>,
+    pub duration_ms: u32,
+    #[specta(type = f64)]
+    pub size: u64,
+    #[specta(type = f64)]
+    pub uploaded: u64,
+    /// Id of the upload on the server, set once it has been created
+    pub remote_id: Option<String>,
+    #[specta(type = f64)]
+    pub chunk_size: u64,
+    pub state: UploadState,
+}
+
+/// Persisted queue of uploads, processed one at a time by the worker
+pub struct UploadManager {
+    file: PathBuf,
+    queue: Mutex<Vec<UploadEntry>>,
+    /// Upload the worker is currently sending, cancelled on pause and cancel
+    active: Mutex<Option<(String, CancellationToken)>>,
+    notify: Notify,
+    tx: broadcast::Sender<UploadEntry>,
+}
+
+/// Client for the signed in user against the configured API
+async fn api_client() -> anyhow::Result<UploadClient> {
+    Ok(UploadClient::new(ApiClient::authenticated().await?))
+}
+
+impl UploadManager {
+    /// Loads the queue from `file`. Uploads that were running when the app closed are queued again.
+    pub async fn load(file: PathBuf) -> Self {
+        let mut queue = match fs::read_to_string(&file).await {
+            Ok(raw) => match serde_json::from_str::<Vec<UploadEntry>>(&raw) {
+                Ok(queue) => queue,
+                Err(e) => {
+                    log::warn!("Couldn't parse upload queue: {:?}", e);
+                    Self::quarantine(&file).await;
+                    vec![]
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                log::warn!("Couldn't read upload queue: {:?}", e);
+                vec![]
+            }
+        };
+
+        for entry in queue.iter_mut() {
+            if entry.state == UploadState::Uploading {
+                entry.state = UploadState::Queued;
+            }
+        }
+
+        Self {
+            file,
+            queue: Mutex::new(queue),
+            active: Mutex::new(None),
+            notify: Notify::new(),
+            tx: broadcast::channel(PROGRESS_CAPACITY).0,
+        }
+    }
+
+    /// Moves an unreadable queue out of the way, so the next save doesn't drop the uploads
+    /// that could still be resumed
+    async fn quarantine(file: &Path) {
+        let target = file.with_file_name(format!(
+            "uploads.corrupt-{}.json",
+            chrono::Utc::now().timestamp()
+        ));
+
+        match fs::rename(file, &target).await {
+            Ok(()) => log::info!("Moved unreadable upload queue to {}", target.display()),
+            Err(e) => log::error!("Couldn't move unreadable upload queue: {:?}", e),
+        }
+    }
+
+    async fn save(&self, queue: &[UploadEntry]) {
+        let r = match serde_json::to_vec(queue) {
+            Ok(raw) => write_atomic(&self.file, raw).await,
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = r {
+            log::error!("Couldn't save upload queue: {:?}", e);
+        }
+    }
+
+    /// Changes the entry, persists the queue and notifies subscribers
+    async fn update<F>(&self, id: &str, f: F) -> Option<UploadEntry>
+    where
+        F: FnOnce(&mut UploadEntry),
+    {
+        let mut queue = self.queue.lock().await;
+        let entry = queue.iter_mut().find(|e| e.id == id)?;
+        f(entry);
+
+        let entry = entry.clone();
+        self.save(&queue).await;
+        let _ = self.tx.send(entry.clone());
+
+        Some(entry)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UploadEntry> {
+        self.tx.subscribe()
+    }
+
+    pub async fn list(&self) -> Vec<UploadEntry> {
+        self.queue.lock().await.clone()
+    }
+
+    pub async fn enqueue(&self, clip: &Clip) -> anyhow::Result<UploadEntry> {
+        let mut queue = self.queue.lock().await;
+        let pending = queue
+            .iter()
+            .any(|e| e.clip_id == clip.id && !matches!(e.state, UploadState::Done { .. }));
+        if pending {
+            bail!("Clip is already being uploaded");
+        }
+
+        let size = fs::metadata(&clip.path)
+            .await
+            .context("Reading clip size")?
+            .len();
+
+        let entry = UploadEntry {
+            id: Uuid::new_v4().to_string(),
+            clip_id: clip.id,
+            path: clip.path.clone(),
+            game_id: clip.game_id.clone(),
+            duration_ms: clip.duration_ms,
+            size,
+            uploaded: 0,
+            remote_id: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            state: UploadState::Queued,
+        };
+
+        queue.push(entry.clone());
+        self.save(&queue).await;
+        drop(queue);
+
+        let _ = self.tx.send(entry.clone());
+        self.notify.notify_one();
+
+        Ok(entry)
+    }
+
+    /// Stops the upload, `resume` continues it after the last chunk the server received
+    pub async fn pause(&self, id: &str) -> anyhow::Result<UploadEntry> {
+        let entry = self
+            .update(id, |e| {
+                if matches!(e.state, UploadState::Queued | UploadState::Uploading) {
+                    e.state = UploadState::Paused;
+                }
+            })
+            .await
+            .ok_or_else(|| anyhow!("No upload with id {}", id))?;
+
+        self.cancel_active(id).await;
+        Ok(entry)
+    }
+
+    /// Queues a paused or failed upload again, continuing where it stopped
+    pub async fn resume(&self, id: &str) -> anyhow::Result<UploadEntry> {
+        let entry = self
+            .update(id, |e| {
+                if matches!(e.state, UploadState::Paused | UploadState::Failed(_)) {
+                    e.state = UploadState::Queued;
+                }
+            })
+            .await
+            .ok_or_else(|| anyhow!("No upload with id {}", id))?;
+
+        self.notify.notify_one();
+        Ok(entry)
+    }
+
+    /// Removes the upload from the queue and discards what the server received so far
+    pub async fn cancel(&self, id: &str) -> anyhow::Result<()> {
+        let removed = {
+            let mut queue = self.queue.lock().await;
+            let pos = queue
+                .iter()
+                .position(|e| e.id == id)
+                .ok_or_else(|| anyhow!("No upload with id {}", id))?;
+
+            let removed = queue.remove(pos);
+            self.save(&queue).await;
+            removed
+        };
+
+        self.cancel_active(id).await;
+
+        let done = matches!(removed.state, UploadState::Done { .. });
+        if let (Some(remote_id), false) = (removed.remote_id, done) {
+            tokio::spawn(async move {
+                let r = match api_client().await {
+                    Ok(client) => client.abort(&remote_id).await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = r {
+                    log::warn!(
+                        "Couldn't discard upload {} on the server: {:?}",
+                        remote_id,
+                        e
+                    );
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn cancel_active(&self, id: &str) {
+        if let Some((active_id, token)) = self.active.lock().await.as_ref() {
+            if active_id == id {
+                token.cancel();
+            }
+        }
+    }
+
+    async fn next_queued(&self) -> Option<UploadEntry> {
+        self.queue
+            .lock()
+            .await
+            .iter()
+            .find(|e| e.state == UploadState::Queued)
+            .cloned()
+    }
+
+    /// Uploads queued clips one after another until the manager is dropped
+    pub fn spawn_worker(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                let Some(entry) = self.next_queued().await else {
+                    self.notify.notified().await;
+                    continue;
+                };
+
+                let client = match api_client().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        self.update(&entry.id, |en| {
+                            en.state = UploadState::Failed(e.to_string())
+                        })
+                        .await;
+                        continue;
+                    }
+                };
+
+                let token = CancellationToken::new();
+                *self.active.lock().await = Some((entry.id.clone(), token.clone()));
+
+                // It might have been paused or cancelled since we picked it
+                let started = self
+                    .update(&entry.id, |e| {
+                        if e.state == UploadState::Queued {
+                            e.state = UploadState::Uploading;
+                        }
+                    })
+                    .await;
+                if started.map(|e| e.state) != Some(UploadState::Uploading) {
+                    *self.active.lock().await = None;
+                    continue;
+                }
+
+                let id = entry.id.clone();
+                let result = select! {
+                    r = self.upload(&client, entry) => Some(r),
+                    _ = token.cancelled() => None,
+                };
+                *self.active.lock().await = None;
+
+                // Cancelled uploads already got their new state from pause or cancel
+                let Some(result) = result else {
+                    continue;
+                };
+
+                let state = match result {
+                    Ok(url) => UploadState::Done { url },
+                    Err(e) => {
+                        log::error!("Upload {} failed: {:?}", id, e);
+                        UploadState::Failed(e.to_string())
+                    }
+                };
+
+                self.update(&id, |e| e.state = state).await;
+            }
+        });
+    }
+
+    /// Sends the remaining chunks of the entry and returns the url of the uploaded clip
+    async fn upload(
+        &self,
+        client: &UploadClient,
+        mut entry: UploadEntry,
+    ) -> anyhow::Result<String> {
+        let mut file = fs::File::open(&entry.path).await.context("Opening clip")?;
+        if file.metadata().await?.len() != entry.size {
+            bail!("The clip changed since the upload was queued");
+        }
+
+        let resumed = match &entry.remote_id {
+            Some(remote_id) => match client.status(remote_id).await {
+                Ok(status) => Some(status.received.min(entry.size)),
+                Err(e) => {
+                    log::warn!(
+                        "Couldn't resume upload {}, starting over: {:?}",
+                        entry.id,
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        match resumed {
+            Some(received) => entry.uploaded = received,
+            None => {
+                let name = PathBuf::from(&entry.path)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+
+                let created = client
+                    .create(&upload::CreateRequest {
+                        name,
+                        size: entry.size,
+                        game_id: entry.game_id.clone(),
+                        duration_ms: entry.duration_ms,
+                    })
+                    .await?;
+
+                entry.remote_id = Some(created.upload_id);
+                entry.chunk_size = created.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+                entry.uploaded = 0;
+            }
+        }
+
+        let remote_id = entry.remote_id.clone().expect("Upload was just created");
+        entry.chunk_size = entry.chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        self.store_progress(&entry).await;
+
+        while entry.uploaded < entry.size {
+            let len = entry.chunk_size.min(entry.size - entry.uploaded);
+            let mut chunk = vec![0; len as usize];
+            file.seek(SeekFrom::Start(entry.uploaded)).await?;
+            file.read_exact(&mut chunk).await?;
+            // Retries share the buffer instead of copying it
+            let chunk = Bytes::from(chunk);
+
+            let mut attempt = 0;
+            loop {
+                match client
+                    .put_chunk(&remote_id, entry.uploaded, entry.size, chunk.clone())
+                    .await
+                {
+                    Ok(()) => break,
+                    Err(ChunkError::Transient(e)) if attempt < MAX_RETRIES => {
+                        let delay = client.retry_delay * 2u32.pow(attempt);
+                        log::warn!(
+                            "Chunk of upload {} failed, retrying in {:?}: {:?}",
+                            entry.id,
+                            delay,
+                            e
+                        );
+                        time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    Err(ChunkError::Transient(e)) | Err(ChunkError::Fatal(e)) => return Err(e),
+                }
+            }
+
+            entry.uploaded += len;
+            self.store_progress(&entry).await;
+        }
+
+        let completed = client.complete(&remote_id).await?;
+        Ok(completed.url)
+    }
+
+    async fn store_progress(&self, progress: &UploadEntry) {
+        self.update(&progress.id, |e| {
+            e.remote_id = progress.remote_id.clone();
+            e.chunk_size = progress.chunk_size;
+            e.uploaded = progress.uploaded;
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex as StdMutex,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Minimal upload API that keeps the received bytes in memory
+    #[derive(Clone, Default)]
+    struct MockApi {
+        received: Arc<StdMutex<Vec<u8>>>,
+        requests: Arc<StdMutex<Vec<String>>>,
+        /// How many chunk uploads fail with a 503 before they succeed again
+        failing_puts: Arc<AtomicUsize>,
+        /// Status every chunk upload fails with, if set
+        reject_puts: Option<u16>,
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+        let mut buf = vec![];
+        let mut tmp = [0u8; 4096];
+        let header_end = loop {
+            let n = stream.read(&mut tmp).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            buf.extend_from_slice(&tmp[..n]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..header_end]).to_lowercase();
+        let len = head
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .and_then(|l| l.trim().parse::<usize>().ok())
+            .unwrap_or_default();
+
+        let mut body = buf[header_end..].to_vec();
+        while body.len() < len {
+            let n = stream.read(&mut tmp).await.ok()?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&tmp[..n]);
+        }
+
+        Some((head, body))
+    }
+
+    impl MockApi {
+        async fn start(self) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let Some((head, body)) = read_request(&mut stream).await else {
+                        continue;
+                    };
+
+                    let line = head.lines().next().unwrap_or_default().to_string();
+                    self.requests.lock().unwrap().push(head.clone());
+
+                    let (status, body) = self.handle(&line, &head, body);
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+
+            url
+        }
+
+        fn handle(&self, line: &str, head: &str, body: Vec<u8>) -> (String, String) {
+            let ok = |body: &str| ("200 OK".to_string(), body.to_string());
+
+            if line.starts_with("post /api/clip/upload ") {
+                return ok(r#"{"uploadId":"r1","chunkSize":4}"#);
+            }
+            if line.starts_with("get /api/clip/upload/r1 ") {
+                let received = self.received.lock().unwrap().len();
+                return ok(&format!(r#"{{"received":{}}}"#, received));
+            }
+            if line.starts_with("post /api/clip/upload/r1/complete ") {
+                return ok(r#"{"id":"c1","url":"https://clipture.test/c1"}"#);
+            }
+            if line.starts_with("put /api/clip/upload/r1 ") {
+                if let Some(status) = self.reject_puts {
+                    return (format!("{} Rejected", status), String::new());
+                }
+
+                let failing = self.failing_puts.load(Ordering::SeqCst);
+                if failing > 0 {
+                    self.failing_puts.store(failing - 1, Ordering::SeqCst);
+                    return ("503 Service Unavailable".to_string(), String::new());
+                }
+
+                let start = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-range: bytes "))
+                    .and_then(|r| r.split('-').next())
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or_default();
+
+                let mut received = self.received.lock().unwrap();
+                received.truncate(start);
+                received.extend_from_slice(&body);
+                return ok("{}");
+            }
+
+            ("404 Not Found".to_string(), String::new())
+        }
+
+        fn chunk_requests(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.starts_with("put "))
+                .filter_map(|r| {
+                    r.lines()
+                        .find_map(|l| l.strip_prefix("content-range: "))
+                        .map(|l| l.trim().to_string())
+                })
+                .collect()
+        }
+    }
+
+    const CONTENT: &[u8] = b"0123456789";
+
+    async fn manager(name: &str) -> (UploadManager, UploadEntry) {
+        let dir =
+            std::env::temp_dir().join(format!("clipture-upload-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let clip_path = dir.join("clip.mp4");
+        std::fs::write(&clip_path, CONTENT).unwrap();
+
+        let manager = UploadManager::load(dir.join(UPLOADS_FILE)).await;
+        let clip: Clip = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "path": clip_path.to_string_lossy(),
+            "kind": "Replay",
+            "game_id": "cs2",
+            "window_title": null,
+            "created_at": "2024-10-01T00:00:00+00:00",
+            "duration_ms": 1000,
+            "width": 0,
+            "height": 0,
+            "size_bytes": 10,
+            "tags": [],
+            "favorite": false,
+            "has_thumbnails": false,
+        }))
+        .unwrap();
+
+        let entry = manager.enqueue(&clip).await.unwrap();
+        (manager, entry)
+    }
+
+    fn client(url: &str) -> UploadClient {
+        let cookies = HashMap::from([("session".to_string(), "abc".to_string())]);
+        let mut client = UploadClient::new(ApiClient::with(url, Some(&cookies)));
+        client.retry_delay = Duration::from_millis(10);
+        client
+    }
+
+    #[tokio::test]
+    async fn uploads_in_chunks_and_retries() -> anyhow::Result<()> {
+        let api = MockApi::default();
+        api.failing_puts.store(2, Ordering::SeqCst);
+        let url = api.clone().start().await;
+        let (manager, entry) = manager("chunks").await;
+
+        let clip_url = manager.upload(&client(&url), entry.clone()).await?;
+        assert_eq!(clip_url, "https://clipture.test/c1");
+        assert_eq!(*api.received.lock().unwrap(), CONTENT);
+        assert_eq!(
+            api.chunk_requests(),
+            vec![
+                "bytes 0-3/10",
+                "bytes 0-3/10",
+                "bytes 0-3/10",
+                "bytes 4-7/10",
+                "bytes 8-9/10"
+            ]
+        );
+        assert!(api.requests.lock().unwrap()[0].contains("cookie: session=abc"));
+
+        // Progress survives a restart
+        let reloaded = UploadManager::load(manager.file.clone()).await;
+        let stored = reloaded.list().await.remove(0);
+        assert_eq!(stored.uploaded, 10);
+        assert_eq!(stored.remote_id.as_deref(), Some("r1"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_from_what_the_server_has() -> anyhow::Result<()> {
+        let api = MockApi::default();
+        api.received
+            .lock()
+            .unwrap()
+            .extend_from_slice(&CONTENT[..4]);
+        let url = api.clone().start().await;
+        let (manager, mut entry) = manager("resume").await;
+
+        entry.remote_id = Some("r1".to_string());
+        entry.chunk_size = 4;
+        manager.upload(&client(&url), entry).await?;
+
+        assert_eq!(*api.received.lock().unwrap(), CONTENT);
+        assert_eq!(api.chunk_requests(), vec!["bytes 4-7/10", "bytes 8-9/10"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_chunks_are_not_retried() {
+        let api = MockApi {
+            reject_puts: Some(413),
+            ..Default::default()
+        };
+        let url = api.clone().start().await;
+        let (manager, entry) = manager("rejected").await;
+
+        assert!(manager.upload(&client(&url), entry).await.is_err());
+        assert_eq!(api.chunk_requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn queue_survives_restarts() -> anyhow::Result<()> {
+        let (manager, entry) = manager("queue").await;
+        manager
+            .update(&entry.id, |e| e.state = UploadState::Uploading)
+            .await;
+
+        let reloaded = UploadManager::load(manager.file.clone()).await;
+        assert_eq!(reloaded.list().await[0].state, UploadState::Queued);
+
+        reloaded.pause(&entry.id).await?;
+        assert_eq!(reloaded.list().await[0].state, UploadState::Paused);
+        reloaded.resume(&entry.id).await?;
+        assert_eq!(reloaded.list().await[0].state, UploadState::Queued);
+
+        reloaded.cancel(&entry.id).await?;
+        assert!(UploadManager::load(manager.file.clone())
+            .await
+            .list()
+            .await
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreadable_queue_is_kept() -> anyhow::Result<()> {
+        let (manager, _) = manager("corrupt").await;
+        std::fs::write(&manager.file, "[{\"id\": ")?;
+
+        let reloaded = UploadManager::load(manager.file.clone()).await;
+        assert!(reloaded.list().await.is_empty());
+        assert!(!manager.file.exists());
+
+        let dir = manager.file.parent().unwrap();
+        let kept = std::fs::read_dir(dir)?.filter_map(|e| e.ok()).any(|e| {
+            e.file_name()
+                .to_string_lossy()
+                .starts_with("uploads.corrupt-")
+        });
+        assert!(kept);
+        Ok(())
+    }
+}
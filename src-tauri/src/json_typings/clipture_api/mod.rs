@@ -1,2 +1,4 @@
+pub mod clip;
+pub mod game;
+pub mod user;
 pub mod validation;
-pub mod game;
\ No newline at end of file
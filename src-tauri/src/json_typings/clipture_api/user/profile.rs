@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Root {
+    pub id: String,
+    pub name: Option<String>,
+    pub image: Option<String>,
+    pub plan: Option<String>,
+    pub storage: Option<Storage>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Storage {
+    pub used: u64,
+    pub quota: u64,
+}
@@ -1 +1,2 @@
-pub mod report;
\ No newline at end of file
+pub mod report;
+pub mod session;
@@ -7,14 +7,25 @@
 use std::{
     env::{current_exe, set_current_dir},
     process,
+    sync::Arc,
 };
 
 use anyhow::Context;
+use core::{
+    auth::{
+        select_credential_store, spawn_session_validator, AuthManager, RedirectMode, AUTH_MANAGER,
+    },
+    clips::{
+        handle_thumbnail_request, spawn_reconcile, ClipLibrary, ThumbnailCache, ThumbnailWorker,
+        CLIPS_DB_FILE, CLIP_LIBRARY, THUMBNAILS_DIR, THUMBNAIL_PROTOCOL, THUMBNAIL_WORKER,
+    },
+    settings::{SettingsManager, SETTINGS_MANAGER},
+    upload::{UploadManager, UPLOADS_FILE, UPLOAD_MANAGER},
+};
 use tauri::Manager;
-use core::auth::{AuthManager, AUTH_MANAGER};
 use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
 use tauri_plugin_log as t_log;
-use utils::{consts::APP_HANDLE, crash_handler};
+use utils::{consts::APP_HANDLE, crash_handler, dir::get_clips_dir};
 
 mod core;
 mod json_typings;
@@ -57,6 +68,15 @@ fn main() -> anyhow::Result<()> {
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(rspc_tauri2::plugin(router, |_| ()))
+        .register_asynchronous_uri_scheme_protocol(
+            THUMBNAIL_PROTOCOL,
+            |_ctx, request, responder| {
+                let uri = request.uri().clone();
+                tauri::async_runtime::spawn(async move {
+                    responder.respond(handle_thumbnail_request(&uri).await);
+                });
+            },
+        )
         .plugin(
             t_log::Builder::new()
                 .target(t_log::Target::new(t_log::TargetKind::LogDir {
@@ -67,7 +87,83 @@ fn main() -> anyhow::Result<()> {
         .setup(move |app| {
             APP_HANDLE.blocking_write().replace(app.handle().clone());
 
-            let auth_manager = AuthManager::new(app);
+            let settings_manager = app
+                .path()
+                .app_data_dir()
+                .map_err(anyhow::Error::from)
+                .and_then(|dir| tauri::async_runtime::block_on(SettingsManager::new(&dir)));
+            match settings_manager {
+                Ok(mgr) => {
+                    SETTINGS_MANAGER.blocking_write().replace(mgr);
+                }
+                Err(err) => {
+                    app.dialog()
+                        .message(format!("Error loading settings: {}", err))
+                        .kind(MessageDialogKind::Error)
+                        .blocking_show();
+
+                    process::exit(1);
+                }
+            }
+
+            let clip_library = app
+                .path()
+                .app_data_dir()
+                .map_err(anyhow::Error::from)
+                .and_then(|dir| {
+                    let library = ClipLibrary::open(&dir.join(CLIPS_DB_FILE), get_clips_dir()?)?;
+                    Ok((library, dir))
+                });
+            match clip_library {
+                Ok((library, dir)) => {
+                    CLIP_LIBRARY.blocking_write().replace(library);
+                    tauri::async_runtime::spawn(async move {
+                        let cache = ThumbnailCache::new(dir.join(THUMBNAILS_DIR));
+                        THUMBNAIL_WORKER
+                            .write()
+                            .await
+                            .replace(ThumbnailWorker::spawn(cache));
+                        spawn_reconcile();
+
+                        let uploads = Arc::new(UploadManager::load(dir.join(UPLOADS_FILE)).await);
+                        uploads.clone().spawn_worker();
+                        UPLOAD_MANAGER.write().await.replace(uploads);
+                    });
+                }
+                // The app is usable without the library, so don't exit here
+                Err(err) => log::error!("Error opening clip library: {:?}", err),
+            }
+
+            // Installers register the scheme on other platforms
+            #[allow(unused_mut)]
+            let mut redirect_mode = RedirectMode::DeepLink;
+            #[cfg(any(target_os = "linux", all(debug_assertions, windows)))]
+            {
+                use tauri_plugin_deep_link::DeepLinkExt;
+                let registered = app
+                    .deep_link()
+                    .register_all()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| Ok(app.deep_link().is_registered("clipture")?));
+                if !matches!(registered, Ok(true)) {
+                    log::warn!(
+                        "Deep link isn't registered ({:?}), signing in through a loopback redirect",
+                        registered
+                    );
+                    redirect_mode = RedirectMode::Loopback;
+                }
+            }
+
+            let auth_manager = app
+                .path()
+                .app_data_dir()
+                .map_err(anyhow::Error::from)
+                .and_then(|dir| {
+                    tauri::async_runtime::block_on(async {
+                        let credentials = select_credential_store(&dir).await;
+                        AuthManager::new(app, credentials, redirect_mode).await
+                    })
+                });
             if let Err(err) = auth_manager {
                 app.dialog()
                     .message(format!("Error initializing auth manager: {}", err))
@@ -79,12 +175,7 @@ fn main() -> anyhow::Result<()> {
                 let mut guard = AUTH_MANAGER.blocking_write();
                 *guard = Some(auth_manager.unwrap());
             }
-
-            #[cfg(any(target_os = "linux", all(debug_assertions, windows)))]
-            {
-                use tauri_plugin_deep_link::DeepLinkExt;
-                app.deep_link().register_all()?;
-            }
+            spawn_session_validator();
 
             Ok(())
         })
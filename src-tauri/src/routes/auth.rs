@@ -1,6 +1,7 @@
+use async_stream::stream;
 use rspc::{ErrorCode, RouterBuilder};
 
-use crate::core::auth::AUTH_MANAGER;
+use crate::core::{api::AuthExpired, auth::AUTH_MANAGER};
 
 pub fn auth() -> RouterBuilder {
     <RouterBuilder>::new() //
@@ -24,6 +25,14 @@ pub fn auth() -> RouterBuilder {
                 Ok(())
             })
         })
+        .mutation("cancel_sign_in", |t| {
+            t(|_ctx, _input: ()| async {
+                let auth = AUTH_MANAGER.read().await;
+                let auth = auth.as_ref().expect("Should have auth manager");
+
+                Ok(auth.cancel_sign_in())
+            })
+        })
         .mutation("sign_out", |t| {
             t(|_ctx, _input: ()| async {
                 let auth = AUTH_MANAGER.read().await;
@@ -60,4 +69,84 @@ pub fn auth() -> RouterBuilder {
                 auth.open_sign_in_window();
             })
         })
+        .query("accounts", |t| {
+            t(|_ctx, _input: ()| async {
+                let auth = AUTH_MANAGER.read().await;
+                let auth = auth.as_ref().expect("Should have auth manager");
+
+                Ok(auth.accounts().await)
+            })
+        })
+        .mutation("switch_account", |t| {
+            t(|_ctx, id: String| async move {
+                let auth = AUTH_MANAGER.read().await;
+                let r = auth
+                    .as_ref()
+                    .expect("Should have auth manager")
+                    .switch_account(&id)
+                    .await;
+
+                if let Err(e) = r {
+                    log::error!("Error switching account: {:?}", e);
+                    return Err(rspc::Error::new(ErrorCode::NotFound, format!("{}", e)));
+                }
+
+                Ok(())
+            })
+        })
+        .mutation("remove_account", |t| {
+            t(|_ctx, id: String| async move {
+                let auth = AUTH_MANAGER.read().await;
+                let r = auth
+                    .as_ref()
+                    .expect("Should have auth manager")
+                    .remove_account(&id)
+                    .await;
+
+                match r {
+                    Ok(removed) => Ok(removed),
+                    Err(e) => {
+                        log::error!("Error removing account: {:?}", e);
+                        Err(rspc::Error::new(
+                            ErrorCode::InternalServerError,
+                            format!("{}", e),
+                        ))
+                    }
+                }
+            })
+        })
+        .query("profile", |t| {
+            t(|_ctx, _input: ()| async {
+                let auth = AUTH_MANAGER.read().await;
+                let auth = auth.as_ref().expect("Should have auth manager");
+
+                auth.profile().await.map_err(|e| {
+                    if e.is::<AuthExpired>() {
+                        return rspc::Error::new(ErrorCode::Unauthorized, format!("{}", e));
+                    }
+
+                    log::error!("Error fetching profile: {:?}", e);
+                    rspc::Error::new(ErrorCode::InternalServerError, format!("{}", e))
+                })
+            })
+        })
+        .subscription("status", |t| {
+            t(|_ctx, _input: ()| {
+                stream! {
+                    let mut rx = match AUTH_MANAGER.read().await.as_ref() {
+                        Some(auth) => auth.subscribe_status(),
+                        None => return,
+                    };
+
+                    loop {
+                        let status = rx.borrow_and_update().clone();
+                        yield status;
+
+                        if rx.changed().await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
 }
@@ -1,19 +1,42 @@
-use std::{env::temp_dir, path::PathBuf};
+use std::{
+    collections::VecDeque,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use async_stream::stream;
 use futures_core::Stream;
 use futures_util::StreamExt;
+use reqwest::{header, Client, StatusCode};
 use semver::Version;
 use sha2::{Digest, Sha256};
-use tokio::{fs::File, io::AsyncWriteExt};
-use uuid::Uuid;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
 
 use crate::{
+    core::api::http_client,
     json_typings::github,
-    utils::consts::{OBS_VERSION, RELEASES_URL},
+    utils::{
+        consts::{OBS_VERSION, RELEASES_URL},
+        dir::get_cache_dir,
+    },
 };
 
+/// Subdirectory of the cache dir the OBS archives are downloaded to
+const DOWNLOAD_DIR: &str = "obs-download";
+/// Retries of a download that doesn't make any progress before giving up
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Minimum time between two progress updates
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+/// Window the download speed is averaged over
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
 pub(super) enum DownloadStatus {
     Error(anyhow::Error),
     Progress(f32, String),
@@ -22,9 +45,7 @@ pub(super) enum DownloadStatus {
 
 pub(super) async fn download_obs() -> anyhow::Result<impl Stream<Item = DownloadStatus>> {
     // Fetch latest OBS release
-    let client = reqwest::ClientBuilder::new()
-        .user_agent("clipture-rs")
-        .build()?;
+    let client = http_client();
 
     let releases: github::releases::Root = client.get(RELEASES_URL).send().await?.json().await?;
 
@@ -43,12 +64,11 @@ pub(super) async fn download_obs() -> anyhow::Result<impl Stream<Item = Download
         .max_by_key(|r| &r.published_at)
         .context("Finding latest version")?;
 
-    let archive_url = latest_version
+    let archive = latest_version
         .assets
         .iter()
         .find(|a| a.name.ends_with(".7z"))
         .context("Finding 7z asset")?
-        .browser_download_url
         .clone();
 
     let hash_url = latest_version
@@ -59,71 +79,566 @@ pub(super) async fn download_obs() -> anyhow::Result<impl Stream<Item = Download
         .browser_download_url
         .clone();
 
-    let res = client.get(archive_url).send().await?;
-    let length = res.content_length().unwrap_or(0);
-
-    let mut bytes_stream = res.bytes_stream();
-
-    let path = PathBuf::new()
-        .join(temp_dir())
-        .join(format!("{}.7z", Uuid::new_v4()));
-    let mut tmp_file = File::create_new(&path)
+    let remote_hash = client
+        .get(hash_url)
+        .send()
+        .await
+        .context("Fetching hash")?
+        .error_for_status()?
+        .text()
         .await
-        .context("Creating temporary file")?;
-    let mut curr_len = 0;
+        .context("Reading hash")?;
+    let remote_hash = remote_hash
+        .split_whitespace()
+        .next()
+        .context("Empty hash file")?;
+    let remote_hash = hex::decode(remote_hash).context("Decoding hash")?;
+
+    let dir = get_cache_dir()?.join(DOWNLOAD_DIR);
+    fs::create_dir_all(&dir).await?;
+    let path = dir.join(&archive.name);
+    let part_path = dir.join(format!("{}.part", archive.name));
+    remove_stale_downloads(&dir, &[&path, &part_path]).await;
 
-    let mut hasher = Sha256::new();
     Ok(stream! {
+        if matches_hash(&path, &remote_hash).await {
+            log::info!("Reusing downloaded archive at {}", path.display());
+            yield DownloadStatus::Done(path);
+            return;
+        }
+
         yield DownloadStatus::Progress(0.0, "Downloading OBS".to_string());
-        while let Some(chunk) = bytes_stream.next().await {
-            let chunk = chunk.context("Retrieving data from stream");
-            if let Err(e) = chunk {
-                yield DownloadStatus::Error(e);
-                return;
-            }
 
-            let chunk = chunk.unwrap();
-            hasher.update(&chunk);
-            let r = tmp_file.write_all(&chunk).await.context("Writing to temporary file");
-            if let Err(e) = r {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let total = u64::try_from(archive.size).unwrap_or_default();
+        let url = archive.browser_download_url.clone();
+        let target = part_path.clone();
+        let task = tokio::spawn(async move {
+            let mut progress = ProgressReporter::new();
+            download_resumable(&client, &url, &target, total, RETRY_DELAY, |downloaded, total| {
+                if let Some(update) = progress.update(Instant::now(), downloaded, total) {
+                    let _ = tx.send(update);
+                }
+            })
+            .await
+        });
+
+        while let Some((progress, message)) = rx.recv().await {
+            yield DownloadStatus::Progress(progress, message);
+        }
+
+        match task.await.context("Joining download task") {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) | Err(e) => {
                 yield DownloadStatus::Error(e);
                 return;
             }
-
-            curr_len = std::cmp::min(curr_len + chunk.len() as u64, length);
-            yield DownloadStatus::Progress(curr_len as  f32 / length as f32, "Downloading OBS".to_string());
         }
 
-        // Getting remote hash
-        let remote_hash = client.get(hash_url).send().await.context("Fetching hash");
-        if let Err(e) = remote_hash {
-            yield DownloadStatus::Error(e);
+        yield DownloadStatus::Progress(1.0, "Verifying OBS download".to_string());
+        if !matches_hash(&part_path, &remote_hash).await {
+            // Nothing worth resuming in there
+            let _ = fs::remove_file(&part_path).await;
+            yield DownloadStatus::Error(anyhow::anyhow!("Hash mismatch"));
             return;
         }
 
-        let remote_hash = remote_hash.unwrap().text().await.context("Reading hash");
-        if let Err(e) = remote_hash {
-            yield DownloadStatus::Error(e);
+        log::info!("Hashes match");
+        if let Err(e) = fs::rename(&part_path, &path).await {
+            yield DownloadStatus::Error(anyhow::Error::from(e).context("Moving finished download"));
             return;
         }
 
-        let remote_hash = remote_hash.unwrap();
-        let remote_hash = hex::decode(remote_hash.trim()).context("Decoding hash");
-        if let Err(e) = remote_hash {
-            yield DownloadStatus::Error(e);
-            return;
+        yield DownloadStatus::Done(path);
+    })
+}
+
+/// Removes archives of older OBS versions, only `keep` stays
+async fn remove_stale_downloads(dir: &Path, keep: &[&Path]) {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if keep.contains(&path.as_path()) {
+            continue;
         }
 
-        let remote_hash = remote_hash.unwrap();
+        log::debug!("Removing stale download {}", path.display());
+        let _ = fs::remove_file(&path).await;
+    }
+}
 
-        // Calculating local hash
-        let local_hash = hasher.finalize();
-        if local_hash.as_slice() != remote_hash {
-            yield DownloadStatus::Error(anyhow::anyhow!("Hash mismatch"));
-            return;
+async fn matches_hash(path: &Path, expected: &[u8]) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+
+    match sha256_file(path).await {
+        Ok(hash) => hash == expected,
+        Err(e) => {
+            log::warn!("Couldn't hash {}: {:?}", path.display(), e);
+            false
         }
+    }
+}
 
-        log::info!("Hashes match");
-        yield DownloadStatus::Done(path);
+async fn sha256_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..n]);
+        }
+
+        Ok(hasher.finalize().to_vec())
     })
+    .await?
+}
+
+/// Downloads `url` to `path`, continuing a partial download that is already there.
+/// Dropped connections are resumed with a range request, `total` may be 0 if unknown.
+async fn download_resumable(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    mut total: u64,
+    retry_delay: Duration,
+    mut on_progress: impl FnMut(u64, u64),
+) -> anyhow::Result<()> {
+    let mut failures = 0;
+    loop {
+        let offset = fs::metadata(path).await.map(|m| m.len()).unwrap_or(0);
+        if total > 0 && offset == total {
+            return Ok(());
+        }
+        if total > 0 && offset > total {
+            fs::remove_file(path).await?;
+            continue;
+        }
+
+        let err =
+            match download_range(client, url, path, offset, &mut total, &mut on_progress).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+        let now = fs::metadata(path).await.map(|m| m.len()).unwrap_or(0);
+        if now > offset {
+            failures = 0;
+        }
+
+        failures += 1;
+        if failures > MAX_RETRIES {
+            return Err(err.context("Downloading OBS failed"));
+        }
+
+        log::warn!("Download interrupted at {} bytes, retrying: {:?}", now, err);
+        tokio::time::sleep(retry_delay * failures).await;
+    }
+}
+
+async fn download_range(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    offset: u64,
+    total: &mut u64,
+    on_progress: &mut impl FnMut(u64, u64),
+) -> anyhow::Result<()> {
+    let mut req = client.get(url);
+    if offset > 0 {
+        req = req.header(header::RANGE, format!("bytes={}-", offset));
+    }
+
+    let res = req.send().await?;
+    let (mut file, mut downloaded) = match res.status() {
+        StatusCode::PARTIAL_CONTENT if offset > 0 => {
+            let file = OpenOptions::new().append(true).open(path).await?;
+            (file, offset)
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            fs::remove_file(path).await?;
+            bail!("Server rejected the range of the partial download");
+        }
+        status if status.is_success() => {
+            if offset > 0 {
+                log::info!("Server doesn't support resuming, downloading from the start");
+            }
+
+            (fs::File::create(path).await?, 0)
+        }
+        status => bail!("Downloading failed with {}", status),
+    };
+
+    if *total == 0 {
+        *total = downloaded + res.content_length().unwrap_or(0);
+    }
+
+    let mut bytes_stream = res.bytes_stream();
+    while let Some(chunk) = bytes_stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                // The next attempt resumes from the file length, so it has to be on disk
+                file.flush().await?;
+                return Err(anyhow::Error::from(e).context("Retrieving data from stream"));
+            }
+        };
+        file.write_all(&chunk).await.context("Writing download")?;
+
+        downloaded += chunk.len() as u64;
+        on_progress(downloaded, *total);
+    }
+    file.flush().await?;
+
+    if *total > 0 && downloaded < *total {
+        bail!("Connection closed after {} of {} bytes", downloaded, total);
+    }
+
+    Ok(())
+}
+
+/// Turns byte counts into throttled progress updates with speed and ETA
+struct ProgressReporter {
+    samples: VecDeque<(Instant, u64)>,
+    last_sent: Option<Instant>,
+}
+
+impl ProgressReporter {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            last_sent: None,
+        }
+    }
+
+    fn update(&mut self, now: Instant, downloaded: u64, total: u64) -> Option<(f32, String)> {
+        // A restarted download goes backwards, the old samples mean nothing then
+        if self
+            .samples
+            .back()
+            .is_some_and(|(_, bytes)| *bytes > downloaded)
+        {
+            self.samples.clear();
+        }
+
+        self.samples.push_back((now, downloaded));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= SPEED_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let done = total > 0 && downloaded >= total;
+        if !done
+            && self
+                .last_sent
+                .is_some_and(|at| now.duration_since(at) < PROGRESS_INTERVAL)
+        {
+            return None;
+        }
+        self.last_sent = Some(now);
+
+        let progress = if total > 0 {
+            downloaded as f32 / total as f32
+        } else {
+            0.0
+        };
+
+        Some((progress, progress_message(self.speed(), downloaded, total)))
+    }
+
+    /// Bytes per second over the samples in the window
+    fn speed(&self) -> Option<f64> {
+        let (first_at, first) = self.samples.front()?;
+        let (last_at, last) = self.samples.back()?;
+
+        let secs = last_at.duration_since(*first_at).as_secs_f64();
+        if secs < 0.5 {
+            return None;
+        }
+
+        Some((last - first) as f64 / secs)
+    }
+}
+
+fn progress_message(speed: Option<f64>, downloaded: u64, total: u64) -> String {
+    let mut details = vec![];
+    if total > 0 {
+        details.push(format!(
+            "{} of {}",
+            format_bytes(downloaded as f64),
+            format_bytes(total as f64)
+        ));
+    }
+
+    if let Some(speed) = speed.filter(|s| *s > 0.0) {
+        details.push(format!("{}/s", format_bytes(speed)));
+
+        if total > downloaded {
+            let secs = ((total - downloaded) as f64 / speed).ceil() as u64;
+            details.push(format!("{} left", format_duration(secs)));
+        }
+    }
+
+    if details.is_empty() {
+        return "Downloading OBS".to_string();
+    }
+
+    format!("Downloading OBS ({})", details.join(", "))
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        (0..64 * 1024).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn temp_file() -> PathBuf {
+        std::env::temp_dir().join(format!("clipture-download-{}.part", uuid::Uuid::new_v4()))
+    }
+
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        /// Serves ranges, but the first `drops` responses are cut off halfway
+        DropConnections { drops: usize },
+        /// Always answers with the whole file
+        IgnoreRange,
+    }
+
+    async fn read_range(stream: &mut TcpStream) -> u64 {
+        let mut buf = vec![0; 4096];
+        let n = stream.read(&mut buf).await.unwrap_or_default();
+        let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+
+        request
+            .lines()
+            .find_map(|l| l.strip_prefix("range: bytes="))
+            .and_then(|r| r.trim_end_matches('-').parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Serves `data()` and returns the url and the offsets of all requests
+    async fn server(behavior: Behavior) -> (String, Arc<std::sync::Mutex<Vec<u64>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/obs.7z", listener.local_addr().unwrap());
+        let offsets = Arc::new(std::sync::Mutex::new(vec![]));
+        let served = Arc::new(AtomicUsize::new(0));
+
+        let requests = offsets.clone();
+        tokio::spawn(async move {
+            let data = data();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut offset = read_range(&mut stream).await;
+                requests.lock().unwrap().push(offset);
+
+                let mut cut = false;
+                let head = match behavior {
+                    Behavior::IgnoreRange => {
+                        offset = 0;
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", data.len())
+                    }
+                    Behavior::DropConnections { drops } => {
+                        cut = served.fetch_add(1, Ordering::SeqCst) < drops;
+                        if offset > 0 {
+                            format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n",
+                                offset,
+                                data.len() - 1,
+                                data.len(),
+                                data.len() as u64 - offset
+                            )
+                        } else {
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", data.len())
+                        }
+                    }
+                };
+
+                let body = &data[offset as usize..];
+                let body = if cut { &body[..body.len() / 2] } else { body };
+
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(b"Connection: close\r\n\r\n").await;
+                let _ = stream.write_all(body).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        (url, offsets)
+    }
+
+    #[tokio::test]
+    async fn resumes_dropped_connections() -> anyhow::Result<()> {
+        let (url, offsets) = server(Behavior::DropConnections { drops: 2 }).await;
+        let path = temp_file();
+
+        let mut last = 0;
+        download_resumable(
+            &http_client(),
+            &url,
+            &path,
+            0,
+            Duration::ZERO,
+            |downloaded, _| {
+                last = downloaded;
+            },
+        )
+        .await?;
+
+        assert_eq!(fs::read(&path).await?, data());
+        assert_eq!(last, data().len() as u64);
+
+        // Every retry continued where the previous connection stopped
+        let offsets = offsets.lock().unwrap().clone();
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[0], 0);
+        assert!(offsets[1] > 0 && offsets[2] > offsets[1]);
+
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_if_the_server_ignores_ranges() -> anyhow::Result<()> {
+        let (url, offsets) = server(Behavior::IgnoreRange).await;
+        let path = temp_file();
+        let stale = b"stale partial download";
+        fs::write(&path, stale).await?;
+
+        let total = data().len() as u64;
+        download_resumable(
+            &http_client(),
+            &url,
+            &path,
+            total,
+            Duration::ZERO,
+            |_, _| {},
+        )
+        .await?;
+
+        assert_eq!(fs::read(&path).await?, data());
+        assert_eq!(offsets.lock().unwrap().as_slice(), &[stale.len() as u64]);
+
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gives_up_without_progress() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/obs.7z", listener.local_addr()?);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_range(&mut stream).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let path = temp_file();
+        let r =
+            download_resumable(&http_client(), &url, &path, 10, Duration::ZERO, |_, _| {}).await;
+        assert!(r.is_err());
+
+        let _ = fs::remove_file(path).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuses_archives_with_matching_hash() -> anyhow::Result<()> {
+        let path = temp_file();
+        assert!(!matches_hash(&path, &[0; 32]).await);
+
+        fs::write(&path, data()).await?;
+        let hash = Sha256::digest(data()).to_vec();
+        assert!(matches_hash(&path, &hash).await);
+        assert!(!matches_hash(&path, &[0; 32]).await);
+
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn reports_speed_and_eta() {
+        let start = Instant::now();
+        let mut reporter = ProgressReporter::new();
+
+        let (progress, message) = reporter.update(start, 0, 10_000_000).unwrap();
+        assert_eq!(progress, 0.0);
+        assert_eq!(message, "Downloading OBS (0 B of 10.0 MB)");
+
+        // Throttled
+        assert!(reporter
+            .update(start + Duration::from_millis(100), 100_000, 10_000_000)
+            .is_none());
+
+        let (progress, message) = reporter
+            .update(start + Duration::from_secs(2), 2_000_000, 10_000_000)
+            .unwrap();
+        assert_eq!(progress, 0.2);
+        assert_eq!(
+            message,
+            "Downloading OBS (2.0 MB of 10.0 MB, 1.0 MB/s, 8s left)"
+        );
+
+        // The last update always goes through
+        assert!(reporter
+            .update(start + Duration::from_millis(2050), 10_000_000, 10_000_000)
+            .is_some());
+    }
+
+    #[test]
+    fn formats_sizes_and_durations() {
+        assert_eq!(format_bytes(512.0), "512 B");
+        assert_eq!(format_bytes(1_500_000.0), "1.5 MB");
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(125), "2m 5s");
+        assert_eq!(format_duration(7260), "2h 1m");
+        assert_eq!(progress_message(None, 0, 0), "Downloading OBS");
+    }
 }
@@ -1,12 +1,20 @@
 use async_stream::stream;
 use futures_core::Stream;
+use tauri::Manager;
+use tokio::sync::mpsc;
 
 use crate::{
     core::{
-        game_detection::{GameDetection, GameEvent, GameEventNotifier, WindowType},
-        obs::{self, runtime::run_with_obs, CaptureTrait},
+        game_detection::{
+            GameDetection, GameEvent, GameEventNotifier, SystemWindowSource, WindowType,
+        },
+        obs::{self, runtime::run_with_obs, CaptureTarget, CaptureTrait},
+        session::{
+            self, ObsSessionActions, SessionController, SESSION_CONTROLLER, SESSION_LOG_FILE,
+        },
+        settings::get_settings,
     },
-    utils::consts::GAME_DETECTION,
+    utils::consts::{app_handle, GAME_DETECTION},
 };
 
 use super::BootstrapStatus;
@@ -26,7 +34,7 @@ pub async fn bootstrap_obs() -> impl Stream<Item = BootstrapStatus> {
         yield BootstrapStatus::Progress(0.5, "Initializing Game Detector...".to_string());
 
         log::debug!("Initializing Game Detector...");
-        let detector = GameDetection::initialize().await;
+        let detector = GameDetection::initialize(SystemWindowSource).await;
         if let Err(e) = detector {
             log::error!("Error initializing Game Detector: {:?}", e);
             yield BootstrapStatus::Error(e.to_string());
@@ -35,23 +43,55 @@ pub async fn bootstrap_obs() -> impl Stream<Item = BootstrapStatus> {
 
         let detector = detector.unwrap();
 
-        detector.add_listener(|event| async move {
-            match event {
-                GameEvent::Closed(window_info) => log::trace!("Game Closed: {}", window_info.obs_id),
-                GameEvent::Opened(window_type, window_info) => {
-                    match window_type {
-                        WindowType::Game => {
-                            log::trace!("Game Opened: {}", window_info.obs_id);
-                            let e = run_with_obs(|mgr| {
-                                mgr.switch_window(window_info)
-                            }).await;
-
-                            if let Err(e) = e {
-                                log::error!("Error running with OBS: {:?}", e);
-                            }
-                        },
-                        _ => {}
+        let (session_tx, session_rx) = mpsc::unbounded_channel();
+        let log_file = app_handle()
+            .await
+            .path()
+            .app_data_dir()
+            .ok()
+            .map(|dir| dir.join(SESSION_LOG_FILE));
+
+        let controller = SessionController::new(ObsSessionActions, get_settings().await.session, log_file);
+        SESSION_CONTROLLER.lock().await.replace(controller);
+        session::spawn_session_thread(session_rx);
+        session::spawn_settings_listener().await;
+
+        detector.add_listener(move |event| {
+            let session_tx = session_tx.clone();
+            async move {
+                let _ = session_tx.send(event.clone());
+                match event {
+                    GameEvent::Closed(window_info) => {
+                        log::trace!("Window Closed: {}", window_info.obs_id);
+                        let e = run_with_obs(move |mgr| mgr.release_game(&window_info)).await;
+                        match e {
+                            Ok(Err(e)) => log::error!("Error switching capture back: {:?}", e),
+                            Err(e) => log::error!("Error running with OBS: {:?}", e),
+                            Ok(Ok(())) => {}
+                        }
+                    }
+                    GameEvent::Opened(window_type, window_info) => {
+                        match window_type {
+                            WindowType::Game(game) => {
+                                log::trace!("Game Opened: {} ({})", game.name, window_info.obs_id);
+                                if get_settings().await.obs.capture != CaptureTarget::FollowGame {
+                                    return;
+                                }
+
+                                let e = run_with_obs(move |mgr| {
+                                    mgr.switch_game(window_info, game.hook)
+                                }).await;
+
+                                match e {
+                                    Ok(Err(e)) => log::error!("Error switching capture to game: {:?}", e),
+                                    Err(e) => log::error!("Error running with OBS: {:?}", e),
+                                    Ok(Ok(())) => {}
+                                }
+                            },
+                            _ => {}
+                        }
                     }
+                    _ => {}
                 }
             }
         }).await;
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use async_stream::stream;
+use rspc::{Error as RError, ErrorCode, Router, RouterBuilder};
+use serde::Deserialize;
+use specta::Type;
+use tokio::sync::broadcast;
+
+use crate::{
+    core::clips::{
+        ffmpeg_available, start_merge, start_trim, subscribe_edits, with_library, Clip, ClipFilter,
+        ClipMetadataUpdate, ClipThumbnails, MergeRequest, TrimRequest, THUMBNAIL_WORKER,
+    },
+    core::upload::{UploadManager, UPLOAD_MANAGER},
+    utils::rspc::to_internal_res,
+};
+
+#[derive(Type, Deserialize)]
+struct UpdateMetadataInput {
+    id: u32,
+    update: ClipMetadataUpdate,
+}
+
+async fn upload_manager() -> Result<Arc<UploadManager>, RError> {
+    UPLOAD_MANAGER.read().await.clone().ok_or_else(|| {
+        RError::new(
+            ErrorCode::InternalServerError,
+            "Upload manager is not initialized".to_string(),
+        )
+    })
+}
+
+fn not_found(id: u32) -> RError {
+    RError::new(ErrorCode::NotFound, format!("No clip with id {}", id))
+}
+
+pub fn clips() -> RouterBuilder {
+    <Router>::new()
+        .query("list", |t| {
+            t(|_ctx, filter: ClipFilter| async move {
+                to_internal_res(with_library(move |library| library.list(&filter)).await)
+            })
+        })
+        .query("get", |t| {
+            t(|_ctx, id: u32| async move {
+                let clip: Option<Clip> =
+                    to_internal_res(with_library(move |library| library.get(id)).await)?;
+                clip.ok_or_else(|| not_found(id))
+            })
+        })
+        .query("thumbnails", |t| {
+            t(|_ctx, id: u32| async move {
+                // None until the background worker generated them
+                let key =
+                    to_internal_res(with_library(move |library| library.thumbnail_key(id)).await)?;
+                Ok(key.map(|_| ClipThumbnails::new(id)))
+            })
+        })
+        .mutation("delete", |t| {
+            t(|_ctx, id: u32| async move {
+                let deleted = with_library(move |library| {
+                    let thumbnail_key = library.thumbnail_key(id)?;
+                    if !library.delete(id)? {
+                        return Ok(None);
+                    }
+
+                    // Clips with the same content share their thumbnails
+                    let unused = match thumbnail_key {
+                        Some(key) if !library.thumbnail_key_used(&key)? => Some(key),
+                        _ => None,
+                    };
+
+                    Ok(Some(unused))
+                })
+                .await;
+
+                let Some(unused_key) = to_internal_res(deleted)? else {
+                    return Err(not_found(id));
+                };
+
+                let worker = THUMBNAIL_WORKER.read().await;
+                if let (Some(worker), Some(key)) = (worker.as_ref(), unused_key) {
+                    if let Err(e) = worker.cache().remove(&key).await {
+                        log::warn!("Couldn't remove thumbnails of clip {}: {:?}", id, e);
+                    }
+                }
+
+                Ok(())
+            })
+        })
+        .mutation("update_metadata", |t| {
+            t(|_ctx, input: UpdateMetadataInput| async move {
+                let UpdateMetadataInput { id, update } = input;
+                let clip = with_library(move |library| library.update_metadata(id, update)).await;
+                to_internal_res(clip)?.ok_or_else(|| not_found(id))
+            })
+        })
+        .query("ffmpeg_available", |t| {
+            t(|_ctx, _input: ()| async move { Ok(ffmpeg_available().await) })
+        })
+        .mutation("trim", |t| {
+            t(|_ctx, req: TrimRequest| async move { to_internal_res(start_trim(req).await) })
+        })
+        .mutation("merge", |t| {
+            t(|_ctx, req: MergeRequest| async move { to_internal_res(start_merge(req).await) })
+        })
+        .subscription("edit_progress", |t| {
+            t(|_ctx, _input: ()| {
+                let mut rx = subscribe_edits();
+                stream! {
+                    loop {
+                        match rx.recv().await {
+                            Ok(update) => yield update,
+                            Err(broadcast::error::RecvError::Lagged(n)) => {
+                                log::warn!("Edit progress subscriber missed {} updates", n);
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                }
+            })
+        })
+        .mutation("upload", |t| {
+            t(|_ctx, id: u32| async move {
+                let clip = to_internal_res(with_library(move |library| library.get(id)).await)?
+                    .ok_or_else(|| not_found(id))?;
+
+                to_internal_res(upload_manager().await?.enqueue(&clip).await)
+            })
+        })
+        .query("uploads", |t| {
+            t(|_ctx, _input: ()| async move { Ok(upload_manager().await?.list().await) })
+        })
+        .mutation("pause_upload", |t| {
+            t(|_ctx, id: String| async move {
+                to_internal_res(upload_manager().await?.pause(&id).await)
+            })
+        })
+        .mutation("resume_upload", |t| {
+            t(|_ctx, id: String| async move {
+                to_internal_res(upload_manager().await?.resume(&id).await)
+            })
+        })
+        .mutation("cancel_upload", |t| {
+            t(|_ctx, id: String| async move {
+                to_internal_res(upload_manager().await?.cancel(&id).await)
+            })
+        })
+        .subscription("upload_progress", |t| {
+            t(|_ctx, _input: ()| {
+                stream! {
+                    let Ok(manager) = upload_manager().await else {
+                        return;
+                    };
+
+                    let mut rx = manager.subscribe();
+                    drop(manager);
+                    loop {
+                        match rx.recv().await {
+                            Ok(entry) => yield entry,
+                            Err(broadcast::error::RecvError::Lagged(n)) => {
+                                log::warn!("Upload progress subscriber missed {} updates", n);
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                }
+            })
+        })
+}
@@ -1,50 +1,99 @@
-use crate::core::game_detection::GameEventNotifier;
+use crate::core::game_detection::{GameEventMessage, OverrideRule};
 use async_stream::stream;
-use rspc::{Router, RouterBuilder};
+use rspc::{Error as RError, ErrorCode, Router, RouterBuilder};
 
-use crate::utils::consts::GAME_DETECTION;
+use crate::utils::{consts::GAME_DETECTION, rspc::to_internal_res};
 
-pub fn game_detect() -> RouterBuilder {
-    <Router>::new().subscription("game_open", |t| {
-        t(|_ctx, _input: ()| {
-            stream! {
-                    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-                    let _guard = tokio::task::spawn(async move {
-                        let game = GAME_DETECTION.read().await;
-
-                        let mut listener = None;
-                        if let Some(game) = game.as_ref() {
-                            let s = game
-                                .add_listener(move |event| {
-                                    let tx = tx.clone();
-                                    async move {
-                                        let r = tx.try_send(event.clone());
-                                        if let Err(e) = r {
-                                            log::warn!("Error sending event: {:?}", e);
-                                        }
-                                    }
-                                })
-                                .await;
-
-                            listener = Some(s);
-                        }
+fn not_initialized() -> RError {
+    RError::new(
+        ErrorCode::InternalServerError,
+        "Game detection is not initialized".to_string(),
+    )
+}
 
-                    listener.map(|l| l.drop_guard())
-                    }).await;
+fn overrides() -> RouterBuilder {
+    <Router>::new()
+        .query("list", |t| {
+            t(|_ctx, _input: ()| async move {
+                let game = GAME_DETECTION.read().await;
+                let game = game.as_ref().ok_or_else(not_initialized)?;
 
-                if let Err(e) = _guard.as_ref() {
-                    log::error!("Error adding listener: {:?}", e);
+                Ok(game.overrides().await)
+            })
+        })
+        .mutation("add", |t| {
+            t(|_ctx, rule: OverrideRule| async move {
+                let game = GAME_DETECTION.read().await;
+                let game = game.as_ref().ok_or_else(not_initialized)?;
+
+                to_internal_res(game.add_override(rule).await)
+            })
+        })
+        .mutation("remove", |t| {
+            t(|_ctx, id: String| async move {
+                let game = GAME_DETECTION.read().await;
+                let game = game.as_ref().ok_or_else(not_initialized)?;
+
+                let removed = to_internal_res(game.remove_override(&id).await)?;
+                if !removed {
+                    return Err(RError::new(
+                        ErrorCode::NotFound,
+                        format!("No override with id {}", id),
+                    ));
                 }
 
-                if let Some(_guard) = _guard.ok().flatten() {
-                    log::debug!("Listener added: {:?}", _guard);
-                    while let Some(event) = rx.recv().await {
-                        yield event;
+                Ok(())
+            })
+        })
+}
+
+pub fn game_detect() -> RouterBuilder {
+    <Router>::new()
+        .merge("overrides.", overrides())
+        .query("current", |t| {
+            t(|_ctx, _input: ()| async move {
+                let game = GAME_DETECTION.read().await;
+                let game = game.as_ref().ok_or_else(not_initialized)?;
+
+                Ok(game.current().await)
+            })
+        })
+        .mutation("refresh_now", |t| {
+            t(|_ctx, _input: ()| async move {
+                let game = GAME_DETECTION.read().await;
+                let game = game.as_ref().ok_or_else(not_initialized)?;
+
+                Ok(game.refresh_now().await)
+            })
+        })
+        .mutation("mark_foreground_as_game", |t| {
+            t(|_ctx, hook: bool| async move {
+                let game = GAME_DETECTION.read().await;
+                let game = game.as_ref().ok_or_else(not_initialized)?;
+
+                to_internal_res(game.mark_foreground_as_game(hook).await)
+            })
+        })
+        .subscription("game_open", |t| {
+            t(|_ctx, _input: ()| {
+                stream! {
+                    let detection = GAME_DETECTION.read().await;
+                    let Some(game) = detection.as_ref() else {
+                        log::error!("Game detection not initialized");
+                        return;
+                    };
+
+                    let mut subscription = game.subscribe().await;
+                    drop(detection);
+
+                    while let Some(msg) = subscription.recv().await {
+                        if let GameEventMessage::Lagged(n) = &msg {
+                            log::warn!("game_open subscriber lagged behind, missed {} events", n);
+                        }
+
+                        yield msg;
                     }
-                } else {
-                    log::error!("Failed to add listener");
                 }
-            }
+            })
         })
-    })
 }
@@ -4,12 +4,18 @@ use rspc::{Config, Router};
 
 mod auth;
 mod bootstrap;
+mod clips;
 mod game_detect;
 mod obs;
+mod session;
+mod settings;
 
 use auth::auth;
 use bootstrap::bootstrap;
+use clips::clips;
 use game_detect::game_detect;
+use session::session;
+use settings::settings;
 
 pub fn router() -> Arc<Router<()>> {
     <Router>::new()
@@ -18,8 +24,11 @@ pub fn router() -> Arc<Router<()>> {
         ))
         .merge("auth.", auth())
         .merge("bootstrap.", bootstrap())
+        .merge("clips.", clips())
         .merge("game_detect.", game_detect())
         .merge("obs.", obs::obs())
+        .merge("session.", session())
+        .merge("settings.", settings())
         .build()
         .arced()
 }
@@ -0,0 +1,13 @@
+use rspc::{Router, RouterBuilder};
+
+use crate::{core::obs::list_sources, utils::rspc::to_internal_res};
+
+pub fn capture() -> RouterBuilder {
+    <Router>::new().query("list_sources", |t| {
+        t(|_ctx, _input: ()| async move {
+            let sources = tokio::task::spawn_blocking(list_sources).await;
+
+            to_internal_res(to_internal_res(sources)?)
+        })
+    })
+}
@@ -3,7 +3,10 @@ use rspc::{Router, RouterBuilder};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
+mod capture;
 mod preview;
+mod recording;
+mod replay;
 
 #[derive(Serialize, Deserialize, Type)]
 struct ObsPreviewCreation {
@@ -30,5 +33,9 @@ struct ObsSizePayload {
 }
 
 pub fn obs() -> RouterBuilder {
-    <Router>::new().merge("preview.", preview::preview())
+    <Router>::new()
+        .merge("capture.", capture::capture())
+        .merge("preview.", preview::preview())
+        .merge("recording.", recording::recording())
+        .merge("replay.", replay::replay())
 }
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use async_stream::stream;
+use rspc::{Error as RError, Router, RouterBuilder};
+
+use crate::{
+    core::{
+        clips::{index_clip, ClipKind, NewClip},
+        obs::{
+            runtime::{run_with_obs_rspc, stop_recording},
+            RecordingState, RecordingStatus,
+        },
+    },
+    utils::rspc::to_internal_res,
+};
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn recording() -> RouterBuilder {
+    <Router>::new() //
+        .mutation("start", |t| {
+            t(|_ctx, _input: ()| async move {
+                let path =
+                    run_with_obs_rspc(move |mgr| to_internal_res(mgr.recording().start())).await?;
+
+                log::info!("Recording to {}", path.display());
+                Ok(path.to_string_lossy().to_string())
+            })
+        })
+        .mutation("stop", |t| {
+            t(|_ctx, _input: ()| async move {
+                let path = to_internal_res(stop_recording().await)?;
+
+                log::info!("Recording saved to {}", path.display());
+                index_clip(NewClip {
+                    path: path.clone(),
+                    kind: ClipKind::Recording,
+                    game_id: None,
+                    window_title: None,
+                    created_at: None,
+                })
+                .await;
+
+                Ok(path.to_string_lossy().to_string())
+            })
+        })
+        .mutation("pause", |t| {
+            t(|_ctx, _input: ()| async move {
+                run_with_obs_rspc(move |mgr| to_internal_res(mgr.recording().pause())).await
+            })
+        })
+        .mutation("resume", |t| {
+            t(|_ctx, _input: ()| async move {
+                run_with_obs_rspc(move |mgr| to_internal_res(mgr.recording().resume())).await
+            })
+        })
+        .subscription("status", |t| {
+            t(|_ctx, _input: ()| {
+                stream! {
+                    let mut prev_state = None;
+                    loop {
+                        let status = run_with_obs_rspc(move |mgr| {
+                            Ok::<RecordingStatus, RError>(mgr.recording().status())
+                        })
+                        .await;
+
+                        let status = match status {
+                            Ok(status) => status,
+                            Err(e) => {
+                                log::error!("Error getting recording status: {:?}", e);
+                                break;
+                            }
+                        };
+
+                        // Only send idle updates when the state actually changed
+                        let changed = prev_state != Some(status.state);
+                        prev_state = Some(status.state);
+                        if changed || status.state != RecordingState::Idle {
+                            yield status;
+                        }
+
+                        tokio::time::sleep(STATUS_INTERVAL).await;
+                    }
+                }
+            })
+        })
+}
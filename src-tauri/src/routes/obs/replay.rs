@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use rspc::{Error as RError, ErrorCode, Router, RouterBuilder};
+use tokio::time::{self, Instant};
+
+use crate::{
+    core::{
+        clips::{index_clip, ClipKind, NewClip},
+        obs::{runtime::run_with_obs_rspc, ReplaySettings, ReplayState},
+        session,
+    },
+    utils::rspc::to_internal_res,
+};
+
+/// How long we wait for OBS to write the replay to disk
+const SAVE_TIMEOUT: Duration = Duration::from_secs(15);
+const SAVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn replay() -> RouterBuilder {
+    <Router>::new() //
+        .mutation("start", |t| {
+            t(|_ctx, settings: Option<ReplaySettings>| async move {
+                run_with_obs_rspc(move |mgr| to_internal_res(mgr.replay().start(settings))).await
+            })
+        })
+        .mutation("stop", |t| {
+            t(|_ctx, _input: ()| async move {
+                run_with_obs_rspc(move |mgr| to_internal_res(mgr.replay().stop())).await
+            })
+        })
+        .mutation("save", |t| {
+            t(|_ctx, _input: ()| async move {
+                run_with_obs_rspc(move |mgr| to_internal_res(mgr.replay().request_save())).await?;
+
+                let timeout_at = Instant::now() + SAVE_TIMEOUT;
+                loop {
+                    time::sleep(SAVE_POLL_INTERVAL).await;
+
+                    let saved =
+                        run_with_obs_rspc(move |mgr| to_internal_res(mgr.replay().poll_saved()))
+                            .await?;
+
+                    if let Some(path) = saved {
+                        log::info!("Replay saved to {}", path.display());
+                        let session = session::record_clip(&path).await;
+                        index_clip(NewClip {
+                            path: path.clone(),
+                            kind: ClipKind::Replay,
+                            game_id: session.as_ref().map(|s| s.game_id.clone()),
+                            window_title: session.and_then(|s| s.window_title),
+                            created_at: None,
+                        })
+                        .await;
+
+                        return Ok(path.to_string_lossy().to_string());
+                    }
+
+                    if Instant::now() >= timeout_at {
+                        run_with_obs_rspc(move |mgr| {
+                            mgr.replay()
+                                .fail("Timed out waiting for replay to be saved".to_string());
+                            Ok(())
+                        })
+                        .await?;
+
+                        return Err(RError::new(
+                            ErrorCode::Timeout,
+                            "Timed out waiting for replay to be saved".to_string(),
+                        ));
+                    }
+                }
+            })
+        })
+        .query("state", |t| {
+            t(|_ctx, _input: ()| async move {
+                run_with_obs_rspc(move |mgr| {
+                    Ok::<ReplayState, RError>(mgr.replay().state().clone())
+                })
+                .await
+            })
+        })
+}
@@ -0,0 +1,26 @@
+use rspc::{Router, RouterBuilder};
+
+use crate::core::session::{SessionRecord, SESSION_CONTROLLER};
+
+pub fn session() -> RouterBuilder {
+    <Router>::new()
+        .query("current", |t| {
+            t(|_ctx, _input: ()| async {
+                let controller = SESSION_CONTROLLER.lock().await;
+                let current = controller.as_ref().and_then(|c| c.current().cloned());
+
+                Ok(current)
+            })
+        })
+        .query("history", |t| {
+            t(|_ctx, _input: ()| async {
+                let controller = SESSION_CONTROLLER.lock().await;
+                let history: Vec<SessionRecord> = controller
+                    .as_ref()
+                    .map(|c| c.history().to_vec())
+                    .unwrap_or_default();
+
+                Ok(history)
+            })
+        })
+}
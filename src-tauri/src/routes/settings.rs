@@ -0,0 +1,61 @@
+use async_stream::stream;
+use rspc::{Error as RError, ErrorCode, Router, RouterBuilder};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    core::settings::{get_settings, Settings, SETTINGS_MANAGER},
+    utils::rspc::to_internal_res,
+};
+
+fn not_initialized() -> RError {
+    RError::new(
+        ErrorCode::InternalServerError,
+        "Settings manager not initialized".to_string(),
+    )
+}
+
+pub fn settings() -> RouterBuilder {
+    <Router>::new() //
+        .query("get", |t| {
+            t(|_ctx, _input: ()| async { Ok(get_settings().await) })
+        })
+        .mutation("set", |t| {
+            t(|_ctx, settings: Settings| async move {
+                settings
+                    .validate()
+                    .map_err(|e| RError::new(ErrorCode::BadRequest, e.to_string()))?;
+
+                let mgr = SETTINGS_MANAGER.read().await;
+                let mgr = mgr.as_ref().ok_or_else(not_initialized)?;
+
+                to_internal_res(mgr.set(settings).await)
+            })
+        })
+        .mutation("reset", |t| {
+            t(|_ctx, _input: ()| async {
+                let mgr = SETTINGS_MANAGER.read().await;
+                let mgr = mgr.as_ref().ok_or_else(not_initialized)?;
+
+                to_internal_res(mgr.reset().await)
+            })
+        })
+        .subscription("on_change", |t| {
+            t(|_ctx, _input: ()| {
+                stream! {
+                    let rx = SETTINGS_MANAGER.read().await.as_ref().map(|mgr| mgr.subscribe());
+                    let Some(mut rx) = rx else {
+                        log::error!("Settings manager not initialized");
+                        return;
+                    };
+
+                    loop {
+                        match rx.recv().await {
+                            Ok(settings) => yield settings,
+                            Err(RecvError::Lagged(_)) => yield get_settings().await,
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                }
+            })
+        })
+}
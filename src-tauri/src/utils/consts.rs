@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::sync::{self, Arc};
 
 use lazy_static::lazy_static;
 use semver::VersionReq;
@@ -11,6 +11,9 @@ lazy_static! {
     pub static ref APP_HANDLE: Arc<RwLock<Option<AppHandle>>> = Arc::new(RwLock::new(None));
     pub static ref GAME_DETECTION: Arc<RwLock<Option<GameDetection>>> = Arc::new(RwLock::new(None));
 
+    /// Base url of the Clipture API, can be changed in the settings
+    static ref BASE_URL: sync::RwLock<String> = sync::RwLock::new(CLIPTURE_BASE_URL.to_string());
+
     pub static ref OBS_VERSION: VersionReq =
         VersionReq::parse("^30.2.0").expect("Invalid OBS version requirement");
 }
@@ -20,8 +23,14 @@ lazy_static! {
 pub const INVALID_OBS_SIZE: usize = 1024 * 100;
 pub const CLIPTURE_BASE_URL: &'static str = "http://localhost:3000";
 
+pub fn set_clipture_base_url(url: &str) {
+    let mut base = BASE_URL.write().expect("Base url lock poisoned");
+    *base = url.trim_end_matches('/').to_string();
+}
+
 pub fn clipture_to_url<T: Into<String>>(url: T) -> String {
-    format!("{}{}", CLIPTURE_BASE_URL, url.into())
+    let base = BASE_URL.read().expect("Base url lock poisoned");
+    format!("{}{}", base, url.into())
 }
 
 #[allow(dead_code)]
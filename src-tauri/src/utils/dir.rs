@@ -1,6 +1,6 @@
 use std::{fs::create_dir_all, path::PathBuf};
 
-use directories::ProjectDirs;
+use directories::{ProjectDirs, UserDirs};
 
 pub fn get_project_dirs() -> anyhow::Result<ProjectDirs> {
     ProjectDirs::from("me", "sshcrack", "clipture")
@@ -17,3 +17,23 @@ pub fn get_log_dir() -> anyhow::Result<PathBuf> {
 
     Ok(logs)
 }
+
+/// Directory where replays and recordings are saved to
+pub fn get_clips_dir() -> anyhow::Result<PathBuf> {
+    let clips = UserDirs::new()
+        .and_then(|dirs| dirs.video_dir().map(|d| d.join("Clipture")))
+        .map(Ok)
+        .unwrap_or_else(|| get_project_dirs().map(|d| d.data_dir().join("clips")))?;
+
+    create_dir_all(&clips)?;
+
+    Ok(clips)
+}
+
+/// Directory for downloads that are resumed or reused across launches
+pub fn get_cache_dir() -> anyhow::Result<PathBuf> {
+    let cache = get_project_dirs()?.cache_dir().to_path_buf();
+    create_dir_all(&cache)?;
+
+    Ok(cache)
+}
@@ -1,8 +1,14 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
+use anyhow::Context;
+use tokio::{fs, io::AsyncWriteExt};
+
 pub struct AtomicDropGuard {
     b: Arc<AtomicBool>,
 }
@@ -19,3 +25,26 @@ impl Drop for AtomicDropGuard {
         self.b.store(false, Ordering::Release);
     }
 }
+
+/// Writes to a temporary file next to `path` and renames it, so readers either see the
+/// old or the new contents but never a partial write
+pub async fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    let mut tmp_name = path
+        .file_name()
+        .context("Path has no file name")?
+        .to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(contents.as_ref()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if let Err(e) = fs::rename(&tmp, path).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+
+    Ok(())
+}